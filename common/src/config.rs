@@ -0,0 +1,101 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Shared by every frontend, Pretty Notes is where the palette gets edited.
+const PALETTE_FILE_NAME: &str = "palette.toml";
+
+/// Where the per-user palette lives, the same file for every frontend.
+pub fn palette_path() -> PathBuf {
+    let mut path = user_config_dir();
+    path.push("pretty-notes");
+    path.push(PALETTE_FILE_NAME);
+    return path;
+}
+
+/**
+ * The per-user config directory the way glib's `g_get_user_config_dir`
+ * finds it, so frontends without glib read the same files: `$XDG_CONFIG_HOME`
+ * when set, otherwise the local app data folder on Windows and `~/.config`
+ * everywhere else, macOS included.
+ */
+pub fn user_config_dir() -> PathBuf {
+    return config_dir_from(|name| env::var_os(name), cfg!(windows));
+}
+
+fn config_dir_from(var: impl Fn(&str) -> Option<OsString>, is_windows: bool) -> PathBuf {
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+
+    if let Some(dir) = var("XDG_CONFIG_HOME") {
+        return PathBuf::from(dir);
+    }
+    if is_windows {
+        if let Some(dir) = var("LOCALAPPDATA") {
+            return PathBuf::from(dir);
+        }
+    }
+
+    let home = var("HOME").or_else(|| if is_windows { var("USERPROFILE") } else { None });
+    let mut dir = home.map_or_else(env::temp_dir, PathBuf::from);
+    dir.push(".config");
+    return dir;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_dir_follows_glib() {
+        let cases = vec![
+            (
+                vec![("XDG_CONFIG_HOME", "/xdg"), ("HOME", "/home/a")],
+                false,
+                "/xdg",
+            ),
+            (
+                vec![("XDG_CONFIG_HOME", ""), ("HOME", "/home/a")],
+                false,
+                "/home/a/.config",
+            ),
+            (vec![("HOME", "/Users/a")], false, "/Users/a/.config"),
+            (
+                vec![("LOCALAPPDATA", "/appdata"), ("HOME", "/home/a")],
+                false,
+                "/home/a/.config",
+            ),
+            (
+                vec![("LOCALAPPDATA", "/appdata"), ("HOME", "/home/a")],
+                true,
+                "/appdata",
+            ),
+            (
+                vec![("XDG_CONFIG_HOME", "/xdg"), ("LOCALAPPDATA", "/appdata")],
+                true,
+                "/xdg",
+            ),
+            (vec![("USERPROFILE", "/users/a")], true, "/users/a/.config"),
+            (vec![("USERPROFILE", "/users/a")], false, ""),
+        ];
+
+        for (vars, is_windows, expected) in cases {
+            let var = |name: &str| {
+                vars.iter()
+                    .find(|(var_name, _)| *var_name == name)
+                    .map(|(_, value)| OsString::from(value))
+            };
+            let expected = if expected.is_empty() {
+                env::temp_dir().join(".config")
+            } else {
+                PathBuf::from(expected)
+            };
+            assert_eq!(
+                config_dir_from(var, is_windows),
+                expected,
+                "with {:?}, on windows: {}",
+                vars,
+                is_windows
+            );
+        }
+    }
+}
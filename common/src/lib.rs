@@ -0,0 +1,10 @@
+#![allow(clippy::needless_return)]
+
+/// Where the frontends find the files they share, like the palette.
+pub mod config;
+/// The note colors and the TOML file they are kept in, the frontends only
+/// turn them into widgets.
+pub mod palette;
+/// The keyboard shortcuts of the GTK frontends, defined once so the relm and
+/// gtk-rs editors agree on them.
+pub mod shortcuts;
@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_COLORS: [&str; 3] = ["#F5E050", "#F38E94", "#CC8CF3"];
+
+/**
+ * A color of the palette. The id names its `color_tag_<id>` tag and never
+ * changes, so notes keep pointing at the same color when the palette is
+ * reordered.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PaletteColor {
+    pub id: u32,
+    pub color: String,
+}
+
+impl PaletteColor {
+    pub fn tag_name(&self) -> String {
+        return format!("color_tag_{}", self.id);
+    }
+
+    /// Reads `#RRGGBB` into its red, green and blue channels.
+    pub fn rgb(&self) -> Option<[u8; 3]> {
+        let hex = self.color.strip_prefix('#')?;
+        if hex.len() != 6 {
+            return None;
+        }
+
+        let channel = |idx: usize| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok();
+        return Some([channel(0)?, channel(2)?, channel(4)?]);
+    }
+}
+
+/**
+ * The colors offered for typing, in button order. Removed colors are kept in
+ * `retired` so that notes which still use them render in the right color.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub colors: Vec<PaletteColor>,
+    #[serde(default)]
+    pub retired: Vec<PaletteColor>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        let colors = DEFAULT_COLORS
+            .iter()
+            .enumerate()
+            .map(|(idx, color)| PaletteColor {
+                id: idx as u32 + 1,
+                color: color.to_string(),
+            })
+            .collect();
+
+        return Palette {
+            colors,
+            retired: vec![],
+        };
+    }
+}
+
+impl Palette {
+    /// Where the palette is stored, in the per-user config directory.
+    pub fn default_path() -> PathBuf {
+        return crate::config::palette_path();
+    }
+
+    /// Reads the palette at `path`, a missing file gives the default palette.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Palette::default()),
+            Err(err) => return Err(err),
+        };
+        return Palette::from_toml(content.as_str());
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        return fs::write(path, self.to_toml()?);
+    }
+
+    pub fn from_toml(content: &str) -> io::Result<Self> {
+        let palette: Palette = toml::from_str(content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        if palette.colors.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The palette needs at least one color",
+            ));
+        }
+        return Ok(palette);
+    }
+
+    pub fn to_toml(&self) -> io::Result<String> {
+        return toml::to_string(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()));
+    }
+
+    /// Every color a note may refer to, the retired ones last.
+    pub fn all_colors(&self) -> impl Iterator<Item = &PaletteColor> {
+        return self.colors.iter().chain(self.retired.iter());
+    }
+
+    /// The tag typing starts with, a palette always has at least one color.
+    pub fn first_tag_name(&self) -> String {
+        return self.colors[0].tag_name();
+    }
+
+    /// Adds `color` at the end of the palette under a fresh id.
+    pub fn add(&mut self, color: &str) -> &PaletteColor {
+        let id = self.all_colors().map(|color| color.id).max().unwrap_or(0) + 1;
+        self.colors.push(PaletteColor {
+            id,
+            color: color.to_string(),
+        });
+        return &self.colors[self.colors.len() - 1];
+    }
+
+    /// Retires the color at `idx`, the last color can't be removed.
+    pub fn remove(&mut self, idx: usize) {
+        if self.colors.len() > 1 && idx < self.colors.len() {
+            let color = self.colors.remove(idx);
+            self.retired.push(color);
+        }
+    }
+
+    /// Changes the color at `idx`. A color already used in notes keeps its
+    /// old value under a new id, so only text typed from now on gets the new
+    /// color.
+    pub fn set_color(&mut self, idx: usize, color: &str) {
+        if idx >= self.colors.len() || self.colors[idx].color == color {
+            return;
+        }
+        let id = self.all_colors().map(|color| color.id).max().unwrap_or(0) + 1;
+        let old_color = std::mem::replace(
+            &mut self.colors[idx],
+            PaletteColor {
+                id,
+                color: color.to_string(),
+            },
+        );
+        self.retired.push(old_color);
+    }
+
+    /// Swaps the color at `idx` with its neighbour `offset` places away.
+    pub fn shift(&mut self, idx: usize, offset: isize) {
+        let target = idx as isize + offset;
+        if idx < self.colors.len() && target >= 0 && (target as usize) < self.colors.len() {
+            self.colors.swap(idx, target as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_palette_uses_the_original_tag_names() {
+        let palette = Palette::default();
+        let tag_names = palette
+            .colors
+            .iter()
+            .map(|color| color.tag_name())
+            .collect::<Vec<String>>();
+
+        assert_eq!(tag_names, vec!["color_tag_1", "color_tag_2", "color_tag_3"]);
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let mut palette = Palette::default();
+        palette.add("#3584E4");
+        palette.remove(0);
+
+        let parsed = Palette::from_toml(palette.to_toml().unwrap().as_str()).unwrap();
+        assert_eq!(parsed, palette);
+    }
+
+    #[test]
+    fn removed_and_changed_colors_stay_resolvable() {
+        let mut palette = Palette::default();
+        palette.remove(1);
+        palette.set_color(0, "#000000");
+        let added_id = palette.add("#FFFFFF").id;
+
+        let tag_colors = palette
+            .all_colors()
+            .map(|color| (color.tag_name(), color.color.to_string()))
+            .collect::<Vec<(String, String)>>();
+
+        assert!(tag_colors.contains(&("color_tag_1".to_string(), "#F5E050".to_string())));
+        assert!(tag_colors.contains(&("color_tag_2".to_string(), "#F38E94".to_string())));
+        assert!(tag_colors.contains(&("color_tag_4".to_string(), "#000000".to_string())));
+        assert_eq!(added_id, 5);
+        assert_eq!(palette.colors.len(), 3);
+    }
+
+    #[test]
+    fn reorder_keeps_ids() {
+        let mut palette = Palette::default();
+        palette.shift(0, 1);
+        palette.shift(0, -1);
+        palette.shift(2, 1);
+
+        let ids = palette
+            .colors
+            .iter()
+            .map(|color| color.id)
+            .collect::<Vec<u32>>();
+        assert_eq!(ids, vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn colors_read_as_rgb() {
+        let color = |color: &str| PaletteColor {
+            id: 1,
+            color: color.to_string(),
+        };
+
+        assert_eq!(color("#CC8CF3").rgb(), Some([204, 140, 243]));
+        assert_eq!(color("#cc8cf3").rgb(), Some([204, 140, 243]));
+        assert_eq!(color("CC8CF3").rgb(), None);
+        assert_eq!(color("#CC8CF").rgb(), None);
+        assert_eq!(color("#CC8CFG").rgb(), None);
+    }
+
+    #[test]
+    fn empty_palette_is_rejected() {
+        assert!(Palette::from_toml("colors = []").is_err());
+    }
+}
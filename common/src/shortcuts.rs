@@ -0,0 +1,178 @@
+/// Ctrl+1 to Ctrl+9 pick the palette colors in button order.
+pub const MAX_COLOR_SHORTCUTS: usize = 9;
+
+/// A keyboard shortcut for one of the actions in the window's "win" group.
+pub struct Shortcut {
+    /// in the format `gtk::accelerator_parse` reads, like `<Primary>s`
+    pub accel: String,
+    pub action: &'static str,
+    /// the parameter the action is activated with, if it takes one
+    pub target: Option<i32>,
+    pub group: &'static str,
+    pub title: String,
+}
+
+impl Shortcut {
+    fn new(accel: &str, action: &'static str, group: &'static str, title: &str) -> Self {
+        return Shortcut {
+            accel: accel.to_string(),
+            action,
+            target: None,
+            group,
+            title: title.to_string(),
+        };
+    }
+
+    /// The action with its target, like `win.select-color(0)`, the way
+    /// `gtk::Application::set_accels_for_action` takes it.
+    pub fn detailed_action(&self) -> String {
+        return match self.target {
+            Some(target) => format!("win.{}({})", self.action, target),
+            None => format!("win.{}", self.action),
+        };
+    }
+}
+
+/// Every shortcut of the editor, with one per palette color up to Ctrl+9.
+pub fn shortcuts(color_count: usize) -> Vec<Shortcut> {
+    let mut shortcuts = vec![
+        Shortcut::new("<Primary>n", "new-note", "Notes", "New note"),
+        Shortcut::new("<Primary>s", "save", "Notes", "Save the note"),
+        Shortcut::new("<Primary>f", "find", "Editing", "Find"),
+        Shortcut::new("<Primary>z", "undo", "Editing", "Undo"),
+        Shortcut::new("<Primary><Shift>z", "redo", "Editing", "Redo"),
+        Shortcut::new("<Primary>b", "toggle-bold", "Formatting", "Bold"),
+        Shortcut::new("<Primary>i", "toggle-italic", "Formatting", "Italic"),
+    ];
+
+    for idx in 0..color_count.min(MAX_COLOR_SHORTCUTS) {
+        shortcuts.push(Shortcut {
+            accel: format!("<Primary>{}", idx + 1),
+            action: "select-color",
+            target: Some(idx as i32),
+            group: "Formatting",
+            title: format!("Color {}", idx + 1),
+        });
+    }
+
+    shortcuts.push(Shortcut::new(
+        "F1",
+        "show-shortcuts",
+        "Help",
+        "Keyboard shortcuts",
+    ));
+    return shortcuts;
+}
+
+/**
+ * The `gtk::Builder` definition of the help window listing `shortcuts` by
+ * group, the window's id is "shortcuts".
+ */
+pub fn shortcuts_ui(shortcuts: &[Shortcut]) -> String {
+    let mut groups: Vec<&str> = vec![];
+    for shortcut in shortcuts {
+        if !groups.contains(&shortcut.group) {
+            groups.push(shortcut.group);
+        }
+    }
+
+    let mut ui = String::from(
+        "<interface>\
+         <object class=\"GtkShortcutsWindow\" id=\"shortcuts\">\
+         <property name=\"modal\">1</property>\
+         <child><object class=\"GtkShortcutsSection\">\
+         <property name=\"visible\">1</property>\
+         <property name=\"section-name\">editor</property>",
+    );
+    for group in groups {
+        ui.push_str(
+            format!(
+                "<child><object class=\"GtkShortcutsGroup\">\
+                 <property name=\"visible\">1</property>\
+                 <property name=\"title\">{}</property>",
+                escape_xml(group)
+            )
+            .as_str(),
+        );
+        for shortcut in shortcuts.iter().filter(|shortcut| shortcut.group == group) {
+            ui.push_str(
+                format!(
+                    "<child><object class=\"GtkShortcutsShortcut\">\
+                     <property name=\"visible\">1</property>\
+                     <property name=\"accelerator\">{}</property>\
+                     <property name=\"title\">{}</property>\
+                     </object></child>",
+                    escape_xml(shortcut.accel.as_str()),
+                    escape_xml(shortcut.title.as_str())
+                )
+                .as_str(),
+            );
+        }
+        ui.push_str("</object></child>");
+    }
+    ui.push_str("</object></child></object></interface>");
+
+    return ui;
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_get_shortcuts_up_to_ctrl_9() {
+        let color_shortcuts = |color_count| {
+            shortcuts(color_count)
+                .into_iter()
+                .filter(|shortcut| shortcut.action == "select-color")
+                .map(|shortcut| (shortcut.accel.to_string(), shortcut.detailed_action()))
+                .collect::<Vec<(String, String)>>()
+        };
+
+        assert_eq!(color_shortcuts(0), vec![]);
+        assert_eq!(
+            color_shortcuts(2),
+            vec![
+                ("<Primary>1".to_string(), "win.select-color(0)".to_string()),
+                ("<Primary>2".to_string(), "win.select-color(1)".to_string()),
+            ]
+        );
+        assert_eq!(color_shortcuts(12).len(), MAX_COLOR_SHORTCUTS);
+    }
+
+    #[test]
+    fn every_shortcut_is_listed_once() {
+        let shortcuts = shortcuts(3);
+        let ui = shortcuts_ui(&shortcuts);
+
+        for shortcut in &shortcuts {
+            let accelerator = format!(
+                "<property name=\"accelerator\">{}</property>",
+                escape_xml(shortcut.accel.as_str())
+            );
+            assert_eq!(
+                ui.matches(accelerator.as_str()).count(),
+                1,
+                "{}",
+                shortcut.accel
+            );
+        }
+        assert_eq!(ui.matches("GtkShortcutsGroup").count(), 4);
+        assert!(ui.contains("&lt;Primary&gt;&lt;Shift&gt;z"));
+        assert!(!ui.contains("<Primary>"));
+    }
+}
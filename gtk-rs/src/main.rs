@@ -6,9 +6,11 @@ use gtk::{
     TextView,
 };
 
+use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 
-const COLORS: [&str; 3] = ["#F38E94", "#F5E050", "#CC8CF3"];
+use common::palette::{Palette, PaletteColor};
+use common::shortcuts::{shortcuts, shortcuts_ui, Shortcut};
 
 const STYLE: &str = "
     
@@ -24,22 +26,28 @@ const STYLE: &str = "
         font-size: 18px;
     }
 
-    #button-1 {
-        background-color: #F5E050;
-        border-color: #F5E050;
-    }
-
-    #button-2 {
-        background-color: #F38E94;
-        border-color: #F38E94;
-    }
+";
 
-    #button-3 {
-        background-color: #CC8CF3;
-        border-color: #CC8CF3;
-    }
+/// The widget name of the button that picks `color`.
+fn button_name(color: &PaletteColor) -> String {
+    return format!("button-{}", color.id);
+}
 
-";
+/// Paints every color button in its palette color.
+fn palette_css(palette: &Palette) -> String {
+    return palette
+        .colors
+        .iter()
+        .map(|color| {
+            format!(
+                "#{} {{ background-color: {}; border-color: {}; }}\n",
+                button_name(color),
+                color.color,
+                color.color
+            )
+        })
+        .collect();
+}
 
 fn get_button(name: &str) -> Button {
     let button = Button::new();
@@ -53,15 +61,94 @@ fn get_button(name: &str) -> Button {
     return button;
 }
 
+/// Gives the selected text `tag_name`, taking the other colors off of it.
+fn recolor_selection(tb: &TextBuffer, palette: &Palette, tag_name: &str) {
+    if let Some((start, end)) = tb.get_selection_bounds() {
+        for color in palette.all_colors() {
+            let color_tag_name = color.tag_name();
+            if color_tag_name != tag_name {
+                tb.remove_tag_by_name(color_tag_name.as_str(), &start, &end);
+            }
+        }
+        tb.apply_tag_by_name(tag_name, &start, &end);
+    }
+}
+
+/// Takes `tag_name` off the selected text if all of it has the tag, gives
+/// it to all of it otherwise.
+fn toggle_selection_tag(tb: &TextBuffer, tag_name: &str) {
+    let tag = match tb
+        .get_tag_table()
+        .and_then(|tag_table| tag_table.lookup(tag_name))
+    {
+        Some(tag) => tag,
+        None => return,
+    };
+
+    if let Some((start, end)) = tb.get_selection_bounds() {
+        let mut iter = start.clone();
+        let mut is_tagged = true;
+        while iter.get_offset() < end.get_offset() {
+            if !iter.has_tag(&tag) {
+                is_tagged = false;
+                break;
+            }
+            iter.forward_char();
+        }
+
+        if is_tagged {
+            tb.remove_tag(&tag, &start, &end);
+        } else {
+            tb.apply_tag(&tag, &start, &end);
+        }
+    }
+}
+
+/// The shared shortcuts of the actions `window` has, with the palette colors
+/// in button order.
+fn window_shortcuts(window: &ApplicationWindow, palette: &Palette) -> Vec<Shortcut> {
+    return shortcuts(palette.colors.len())
+        .into_iter()
+        .filter(|shortcut| window.lookup_action(shortcut.action).is_some())
+        .collect();
+}
+
+/// Builds the help window listing `shortcuts`.
+fn shortcuts_window(shortcuts: &[Shortcut]) -> Option<gtk::ShortcutsWindow> {
+    return gtk::Builder::from_string(shortcuts_ui(shortcuts).as_str()).get_object("shortcuts");
+}
+
+/// Makes `tag_name` the color of whatever gets typed next and recolors the
+/// selection, if there is one.
+fn select_color(
+    selected_tag_lock: &RwLock<String>,
+    tb: &TextBuffer,
+    palette: &Palette,
+    tag_name: &str,
+) {
+    let mut stag = selected_tag_lock
+        .write()
+        .expect("Error in color button handler");
+    *stag = tag_name.to_string();
+    recolor_selection(tb, palette, stag.as_str());
+}
+
 fn main() {
-    let selected_tag_lock = Arc::new(RwLock::new(String::from("color_tag_1")));
+    let palette = Rc::new(
+        Palette::load(&Palette::default_path()).unwrap_or_else(|err| {
+            eprintln!("Couldn't read the palette, using the default one: {}", err);
+            Palette::default()
+        }),
+    );
+    let selected_tag_lock = Arc::new(RwLock::new(palette.colors[0].tag_name()));
     let application =
         Application::new(Some("com.github.gtk-rs.examples.basic"), Default::default())
             .expect("Failed to initialize GTK application");
     application.connect_activate(move |app| {
         let provider = gtk::CssProvider::new();
+        let style = format!("{}{}", STYLE, palette_css(&palette));
         provider
-            .load_from_data(STYLE.as_bytes())
+            .load_from_data(style.as_bytes())
             .expect("Failed to load CSS");
         // We give the CssProvided to the default screen so the CSS rules we added
         // can be applied to our window.
@@ -78,31 +165,19 @@ fn main() {
         let hbox = Box::new(gtk::Orientation::Horizontal, 0);
 
         let button_container = Box::new(gtk::Orientation::Vertical, 5);
-        let btn1 = get_button("button-1");
-        let btn2 = get_button("button-2");
-        let btn3 = get_button("button-3");
-
-        let st1 = selected_tag_lock.clone();
-        btn1.connect_clicked(move |_| {
-            let mut stag = st1.write().expect("Error in btn-1 handler");
-            *stag = String::from("color_tag_0");
-        });
-
-        let st2 = selected_tag_lock.clone();
-        btn2.connect_clicked(move |_| {
-            let mut stag = st2.write().expect("Error in btn 2 handler");
-            *stag = String::from("color_tag_1");
-        });
-
-        let st3 = selected_tag_lock.clone();
-        btn3.connect_clicked(move |_| {
-            let mut stag = st3.write().expect("Error in btn 3 handler");
-            *stag = String::from("color_tag_2");
-        });
+        let color_buttons = palette
+            .colors
+            .iter()
+            .map(|color| get_button(button_name(color).as_str()))
+            .collect::<Vec<Button>>();
+        let bold_button = get_button("bold");
+        let italic_button = get_button("italic");
 
-        button_container.pack_start(&btn1, false, false, 0);
-        button_container.pack_start(&btn2, false, false, 0);
-        button_container.pack_start(&btn3, false, false, 0);
+        for button in &color_buttons {
+            button_container.pack_start(button, false, false, 0);
+        }
+        button_container.pack_start(&bold_button, false, false, 0);
+        button_container.pack_start(&italic_button, false, false, 0);
 
         button_container.set_halign(Align::Center);
         button_container.set_valign(Align::Center);
@@ -118,14 +193,13 @@ fn main() {
             .get_buffer()
             .expect("Error: Couldn't get access to the text buffer inside the view");
 
-        let color_tags = COLORS
-            .to_vec()
-            .iter()
-            .enumerate()
-            .map(|(idx, color)| {
+        // retired colors get a tag too, text may still be using them
+        let color_tags = palette
+            .all_colors()
+            .map(|color| {
                 gtk::TextTagBuilder::new()
-                    .name(format!("color_tag_{}", idx).as_str())
-                    .foreground(*color)
+                    .name(color.tag_name().as_str())
+                    .foreground(color.color.as_str())
                     .font("Gaegu")
                     .build()
             })
@@ -136,6 +210,71 @@ fn main() {
         for tag in &color_tags {
             tag_table.add(tag);
         }
+        tag_table.add(&gtk::TextTagBuilder::new().name("bold").weight(600).build());
+        tag_table.add(
+            &gtk::TextTagBuilder::new()
+                .name("italic")
+                .style(pango::Style::Italic)
+                .build(),
+        );
+
+        for (button, color) in color_buttons.iter().zip(palette.colors.iter()) {
+            let st = selected_tag_lock.clone();
+            let tb = tv_buffer.clone();
+            let palette = palette.clone();
+            let tag_name = color.tag_name();
+            button.connect_clicked(move |_| select_color(&st, &tb, &palette, tag_name.as_str()));
+        }
+
+        // the buttons' actions again, for the keyboard shortcuts
+        bold_button.set_action_name(Some("win.toggle-bold"));
+        italic_button.set_action_name(Some("win.toggle-italic"));
+
+        let toggle_bold = gio::SimpleAction::new("toggle-bold", None);
+        let tb_bold = tv_buffer.clone();
+        toggle_bold.connect_activate(move |_, _| toggle_selection_tag(&tb_bold, "bold"));
+        window.add_action(&toggle_bold);
+
+        let toggle_italic = gio::SimpleAction::new("toggle-italic", None);
+        let tb_italic = tv_buffer.clone();
+        toggle_italic.connect_activate(move |_, _| toggle_selection_tag(&tb_italic, "italic"));
+        window.add_action(&toggle_italic);
+
+        let select_color_action =
+            gio::SimpleAction::new("select-color", glib::VariantTy::new("i").ok());
+        let st = selected_tag_lock.clone();
+        let tb = tv_buffer.clone();
+        let color_palette = palette.clone();
+        select_color_action.connect_activate(move |_, parameter| {
+            let color = parameter
+                .and_then(|parameter| parameter.get::<i32>())
+                .and_then(|idx| color_palette.colors.get(idx as usize));
+            if let Some(color) = color {
+                select_color(&st, &tb, &color_palette, color.tag_name().as_str());
+            }
+        });
+        window.add_action(&select_color_action);
+
+        let show_shortcuts = gio::SimpleAction::new("show-shortcuts", None);
+        let parent = window.clone();
+        let shortcuts_palette = palette.clone();
+        show_shortcuts.connect_activate(move |_, _| {
+            let shortcut_list = window_shortcuts(&parent, &shortcuts_palette);
+            if let Some(shortcuts_window) = shortcuts_window(&shortcut_list) {
+                shortcuts_window.set_transient_for(Some(&parent));
+                shortcuts_window.show_all();
+            }
+        });
+        window.add_action(&show_shortcuts);
+
+        // the shared table also has the relm editor's note and history
+        // shortcuts, this window has no actions for those
+        for shortcut in window_shortcuts(&window, &palette) {
+            app.set_accels_for_action(
+                shortcut.detailed_action().as_str(),
+                &[shortcut.accel.as_str()],
+            );
+        }
 
         let tt = selected_tag_lock.clone();
         tv_buffer
@@ -143,17 +282,17 @@ fn main() {
                 let tb = args[0].get::<TextBuffer>().unwrap().unwrap();
                 let iter = args[1].get::<TextIter>().unwrap().unwrap();
 
-                let tag = tt.read().expect("Cannot get read lock");
+                let tag_name = tt.read().expect("Cannot get read lock");
                 let tag = tb
                     .get_tag_table()
-                    .unwrap()
-                    .lookup(tag.as_str())
-                    .expect("Couldn't find tag in the tablet list in buffer");
-                tb.apply_tag(
-                    &tag,
-                    &tb.get_iter_at_offset(&iter.get_offset() - 1),
-                    &tb.get_iter_at_offset(iter.get_offset()),
-                );
+                    .and_then(|tag_table| tag_table.lookup(tag_name.as_str()));
+                if let Some(tag) = tag {
+                    tb.apply_tag(
+                        &tag,
+                        &tb.get_iter_at_offset(&iter.get_offset() - 1),
+                        &tb.get_iter_at_offset(iter.get_offset()),
+                    );
+                }
 
                 None
             })
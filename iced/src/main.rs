@@ -1,3 +1,4 @@
+mod palette;
 mod style;
 
 use iced::{
@@ -10,8 +11,6 @@ const DEFAULT_FONT: Font = Font::External {
     bytes: include_bytes!("resources/Cascadia.ttf"),
 };
 
-const COLORS: [[u8; 3]; 3] = [[245, 224, 80], [243, 142, 148], [203, 140, 243]];
-
 const MAX_WIDTH: u16 = 800;
 
 pub fn main() {
@@ -29,33 +28,26 @@ struct Counter {
 
 impl std::default::Default for Counter {
     fn default() -> Self {
-        let palette = vec![
-            PaletteColor::new(
-                "primary".to_string(),
-                Color::from_rgb8(COLORS[0][0], COLORS[0][1], COLORS[0][2]),
-            ),
-            PaletteColor::new(
-                "secondary".to_string(),
-                Color::from_rgb8(COLORS[1][0], COLORS[1][1], COLORS[1][2]),
-            ),
-            PaletteColor::new(
-                "tertiary".to_string(),
-                Color::from_rgb8(COLORS[2][0], COLORS[2][1], COLORS[2][2]),
-            ),
-        ];
+        let colors = palette::load().unwrap_or_else(|err| {
+            eprintln!("Couldn't read the palette, using the default one: {}", err);
+            palette::default_colors()
+        });
+        let palette = colors
+            .iter()
+            .map(|(id, rgb)| {
+                PaletteColor::new(
+                    format!("color_{}", id),
+                    Color::from_rgb8(rgb[0], rgb[1], rgb[2]),
+                )
+            })
+            .collect::<Vec<PaletteColor>>();
 
         return Counter {
             input_val: String::default(),
             input_state: text_input::State::default(),
             task_list: vec![],
             latest_task_id: i32::default(),
-            color: palette
-                .iter()
-                .filter(|&p| p.id == "primary".to_string())
-                .collect::<Vec<&PaletteColor>>()
-                .first()
-                .unwrap()
-                .color,
+            color: palette.first().unwrap().color,
             color_palette: palette,
         };
     }
@@ -0,0 +1,27 @@
+use common::palette::Palette;
+use std::io;
+
+/// The `(id, rgb)` pairs of the colors offered for picking, in button order.
+fn rgb_colors(palette: &Palette) -> Vec<(u32, [u8; 3])> {
+    return palette
+        .colors
+        .iter()
+        .filter_map(|color| color.rgb().map(|rgb| (color.id, rgb)))
+        .collect();
+}
+
+pub fn default_colors() -> Vec<(u32, [u8; 3])> {
+    return rgb_colors(&Palette::default());
+}
+
+/**
+ * Reads the per-user palette as `(id, rgb)` pairs in button order. A missing
+ * file gives the default palette, colors that aren't `#RRGGBB` are skipped.
+ */
+pub fn load() -> io::Result<Vec<(u32, [u8; 3])>> {
+    let colors = rgb_colors(&Palette::load(&Palette::default_path())?);
+    if colors.is_empty() {
+        return Ok(default_colors());
+    }
+    return Ok(colors);
+}
@@ -0,0 +1,554 @@
+use crate::export::TagStyle;
+use crate::note_file::Snapshot;
+use crate::tags::is_view_tag;
+use crate::text_ops::{DeleteTextEventData, InsertOpsData, InsertTextEventData, Ops, TagRun};
+use gtk::prelude::*;
+use gtk::{TextBuffer, TextIter, TextTag, TextTagTable};
+
+/**
+ * Applies the buffer side of `op`. Every position comes from the op itself,
+ * so the result does not depend on where the cursor is.
+ */
+pub fn apply(tb: &TextBuffer, op: &Ops) {
+    let tag_table = tb.tag_table().expect("Couldn't get hold of a tag table!");
+
+    match op {
+        Ops::Insert(insert_ops_data) => {
+            tb.insert(
+                &mut tb.iter_at_offset(insert_ops_data.offset),
+                insert_ops_data.content.as_str(),
+            );
+
+            // inserts restored by undo carry their tags as separate ops
+            if !insert_ops_data.tag.is_empty() {
+                let tag = lookup_or_create(&tag_table, insert_ops_data.tag.as_str());
+                let inserted_chars = insert_ops_data.content.chars().count() as i32;
+
+                tb.apply_tag(
+                    &tag,
+                    &tb.iter_at_offset(insert_ops_data.offset),
+                    &tb.iter_at_offset(insert_ops_data.offset + inserted_chars),
+                );
+            }
+        }
+        Ops::Delete((start_offset, end_offset)) => {
+            tb.delete(
+                &mut tb.iter_at_offset(*start_offset),
+                &mut tb.iter_at_offset(*end_offset),
+            );
+        }
+        Ops::MoveCursor(position) => tb.place_cursor(&tb.iter_at_offset(*position)),
+        Ops::SelectColorTag(_) => {}
+        Ops::ApplyTag((tag_name, start_offset, end_offset)) => {
+            let tag = lookup_or_create(&tag_table, tag_name.as_str());
+
+            tb.apply_tag(
+                &tag,
+                &tb.iter_at_offset(*start_offset),
+                &tb.iter_at_offset(*end_offset),
+            );
+        }
+        Ops::RemoveTag((tag_name, start_offset, end_offset)) => {
+            tb.remove_tag_by_name(
+                tag_name.as_str(),
+                &tb.iter_at_offset(*start_offset),
+                &tb.iter_at_offset(*end_offset),
+            );
+        }
+    }
+}
+
+/**
+ * Finds the tag named `tag_name`. A tag nobody knows the look of is added
+ * without any style, so the text still keeps it when the note is saved again.
+ */
+pub fn lookup_or_create(tag_table: &TextTagTable, tag_name: &str) -> TextTag {
+    if let Some(tag) = tag_table.lookup(tag_name) {
+        return tag;
+    }
+
+    let tag = TextTag::new(Some(tag_name));
+    tag_table.add(&tag);
+    return tag;
+}
+
+/**
+ * What the buffer's `insert-text` signal tells about an edit. The text goes
+ * where `iter` is, which needn't be where the cursor is.
+ */
+pub fn insert_event_data(iter: &TextIter, content: &str) -> InsertTextEventData {
+    return InsertTextEventData::new(iter.offset(), content);
+}
+
+/**
+ * What the buffer's `delete-range` signal tells about an edit, read before
+ * gtk removes the text so the deletion can be undone.
+ */
+pub fn delete_event_data(tb: &TextBuffer, start: &TextIter, end: &TextIter) -> DeleteTextEventData {
+    let content = start.text(end).map(|s| s.to_string()).unwrap_or_default();
+
+    return DeleteTextEventData::new(
+        start.offset(),
+        end.offset(),
+        content,
+        tag_runs(tb, start.offset(), end.offset()),
+    );
+}
+
+/**
+ * Colors the text the user just typed or pasted with `tag_name` and returns
+ * the op that logs it. Runs once gtk has inserted the text.
+ */
+pub fn record_insert(tb: &TextBuffer, inserted: &InsertTextEventData, tag_name: &str) -> Ops {
+    let tag_table = tb.tag_table().expect("Couldn't get hold of a tag table!");
+    let tag = lookup_or_create(&tag_table, tag_name);
+
+    let inserted_chars = inserted.content.chars().count() as i32;
+    tb.apply_tag(
+        &tag,
+        &tb.iter_at_offset(inserted.offset),
+        &tb.iter_at_offset(inserted.offset + inserted_chars),
+    );
+
+    return Ops::Insert(InsertOpsData::new(
+        inserted.offset,
+        inserted.content.to_string(),
+        tag_name.to_string(),
+    ));
+}
+
+/// The op that logs text the user deleted.
+pub fn record_delete(deleted: &DeleteTextEventData) -> Ops {
+    return Ops::Delete((deleted.start_offset, deleted.end_offset));
+}
+
+/**
+ * Adds the tags of a note's tag table that the buffer doesn't have yet, the
+ * tags the app defines itself are left as they are.
+ */
+pub fn register_tags(tb: &TextBuffer, tags: &[(String, TagStyle)]) {
+    let tag_table = tb.tag_table().expect("Couldn't get hold of a tag table!");
+
+    for (tag_name, style) in tags {
+        if tag_table.lookup(tag_name.as_str()).is_some() {
+            continue;
+        }
+
+        let mut builder = gtk::builders::TextTagBuilder::new().name(tag_name.as_str());
+        if let Some(color) = &style.foreground {
+            builder = builder.foreground(color.as_str());
+        }
+        if let Some(color) = &style.background {
+            builder = builder.background(color.as_str());
+        }
+        if let Some(family) = &style.font_family {
+            builder = builder.family(family.as_str());
+        }
+        if let Some(size) = style.size_points {
+            builder = builder.size_points(size);
+        }
+        if style.italic {
+            builder = builder.style(gtk::pango::Style::Italic);
+        }
+        if let Some(weight) = style.weight {
+            builder = builder.weight(weight);
+        }
+        if style.strikethrough {
+            builder = builder.strikethrough(true);
+        }
+        if style.underline {
+            builder = builder.underline(gtk::pango::Underline::Single);
+        }
+        if style.invisible {
+            builder = builder.invisible(true);
+        }
+        if let Some(scale) = style.scale {
+            builder = builder.scale(scale);
+        }
+        if let Some(margin) = style.left_margin {
+            builder = builder.left_margin(margin);
+        }
+        tag_table.add(&builder.build());
+    }
+}
+
+/**
+ * Collects the tags covering `start_offset..end_offset` as runs, one run per
+ * contiguous stretch of the same tag.
+ */
+pub fn tag_runs(tb: &TextBuffer, start_offset: i32, end_offset: i32) -> Vec<TagRun> {
+    let mut runs: Vec<TagRun> = vec![];
+
+    for offset in start_offset..end_offset {
+        for tag in tb.iter_at_offset(offset).tags() {
+            let tag_name = match tag.name() {
+                Some(name) if !is_view_tag(name.as_str()) => name.to_string(),
+                _ => continue,
+            };
+
+            match runs
+                .iter_mut()
+                .rev()
+                .find(|(name, _, end)| *name == tag_name && *end == offset)
+            {
+                Some(run) => run.2 = offset + 1,
+                None => runs.push((tag_name, offset, offset + 1)),
+            }
+        }
+    }
+
+    return runs;
+}
+
+/// Fills an empty buffer with the text and tags of `snapshot`.
+pub fn restore_snapshot(tb: &TextBuffer, snapshot: &Snapshot) {
+    let tag_table = tb.tag_table().expect("Couldn't get hold of a tag table!");
+
+    tb.insert(&mut tb.end_iter(), snapshot.text.as_str());
+    for (tag_name, start_offset, end_offset) in &snapshot.tags {
+        tb.apply_tag(
+            &lookup_or_create(&tag_table, tag_name.as_str()),
+            &tb.iter_at_offset(*start_offset),
+            &tb.iter_at_offset(*end_offset),
+        );
+    }
+}
+
+/**
+ * The look of every named tag in the buffer's tag table, lowest priority
+ * first.
+ */
+pub fn tag_styles(tb: &TextBuffer) -> Vec<(String, TagStyle)> {
+    let tag_table = tb.tag_table().expect("Couldn't get hold of a tag table!");
+
+    let mut tags = vec![];
+    tag_table.foreach(|tag| tags.push(tag.clone()));
+    tags.sort_by_key(|tag| tag.priority());
+
+    return tags
+        .iter()
+        .filter_map(|tag| {
+            let tag_name = tag.name()?.to_string();
+            if is_view_tag(tag_name.as_str()) {
+                return None;
+            }
+            let style = TagStyle {
+                foreground: tag
+                    .foreground_rgba()
+                    .filter(|_| tag.is_foreground_set())
+                    .map(|color| color.to_string()),
+                background: tag
+                    .background_rgba()
+                    .filter(|_| tag.is_background_set())
+                    .map(|color| color.to_string()),
+                font_family: tag
+                    .family()
+                    .filter(|_| tag.is_family_set())
+                    .map(|family| family.to_string()),
+                size_points: Some(tag.size_points()).filter(|_| tag.is_size_set()),
+                italic: tag.is_style_set() && tag.style() != gtk::pango::Style::Normal,
+                weight: Some(tag.weight()).filter(|_| tag.is_weight_set()),
+                strikethrough: tag.is_strikethrough_set() && tag.is_strikethrough(),
+                underline: tag.is_underline_set() && tag.underline() != gtk::pango::Underline::None,
+                invisible: tag.is_invisible_set() && tag.is_invisible(),
+                scale: Some(tag.scale()).filter(|_| tag.is_scale_set()),
+                left_margin: Some(tag.left_margin()).filter(|_| tag.is_left_margin_set()),
+            };
+            Some((tag_name, style))
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{any_op, insert};
+    use crate::document::Document;
+    use crate::note_file::{self, Note};
+    use gtk::TextTag;
+    use proptest::prelude::*;
+    use proptest::test_runner::TestRunner;
+    use std::cell::RefCell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Mutex;
+    use std::thread;
+
+    type GtkTest = (Box<dyn FnOnce() + Send>, Sender<thread::Result<()>>);
+
+    /// The thread gtk was initialized on, started by the first test needing it.
+    static GTK_THREAD: Mutex<Option<Sender<GtkTest>>> = Mutex::new(None);
+
+    /**
+     * Runs `test` on the gtk thread, gtk may only be used from the thread
+     * that initialized it. Fails when there is no display to initialize gtk
+     * with, the tests using this are ignored by default and run under xvfb:
+     * `xvfb-run cargo test -- --ignored`.
+     */
+    fn on_gtk_thread(test: impl FnOnce() + Send + 'static) {
+        let (done, result) = mpsc::channel();
+        {
+            let mut gtk_thread = GTK_THREAD.lock().unwrap_or_else(|err| err.into_inner());
+            let sender = gtk_thread.get_or_insert_with(|| {
+                let (sender, tests) = mpsc::channel::<GtkTest>();
+                thread::spawn(move || {
+                    gtk::init().expect("gtk could not be initialized, is there a display?");
+                    for (test, done) in tests {
+                        let _ = done.send(panic::catch_unwind(AssertUnwindSafe(test)));
+                    }
+                });
+                sender
+            });
+            let _ = sender.send((Box::new(test), done));
+        }
+
+        match result.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(panic)) => panic::resume_unwind(panic),
+            Err(_) => panic!("gtk could not be initialized, is there a display?"),
+        }
+    }
+
+    fn new_buffer() -> TextBuffer {
+        let buffer = TextBuffer::new(None::<&gtk::TextTagTable>);
+        let tag_table = buffer.tag_table().unwrap();
+        for name in &["color_tag_1", "color_tag_2", "bold", "italic"] {
+            tag_table.add(&TextTag::new(Some(*name)));
+        }
+        return buffer;
+    }
+
+    /// Performs `op` on `tb` the way a user edit would and logs it.
+    fn edit(tb: &TextBuffer, log: &mut Vec<Ops>, op: Ops) {
+        apply(tb, &op);
+        log.push(op);
+    }
+
+    fn buffer_text(tb: &TextBuffer) -> String {
+        let (start, end) = tb.bounds();
+        return tb
+            .text(&start, &end, true)
+            .expect("Error while trying to read gtk buffer")
+            .to_string();
+    }
+
+    fn snapshot(tb: &TextBuffer, current_tag: &str) -> Snapshot {
+        return Snapshot {
+            text: buffer_text(tb),
+            tags: tag_runs(tb, 0, tb.char_count()),
+            current_tag: current_tag.to_string(),
+        };
+    }
+
+    fn hydrate(bytes: &[u8]) -> TextBuffer {
+        let note = note_file::decode(bytes)
+            .ok()
+            .expect("saved note should decode");
+        let tb = new_buffer();
+        register_tags(&tb, &note.tags);
+        if let Some(snapshot) = &note.snapshot {
+            restore_snapshot(&tb, snapshot);
+        }
+        for op in &note.ops {
+            apply(&tb, op);
+        }
+        return tb;
+    }
+
+    fn assert_same_buffer(expected: &TextBuffer, actual: &TextBuffer) {
+        assert_eq!(buffer_text(expected), buffer_text(actual));
+        assert_eq!(
+            tag_runs(expected, 0, expected.char_count()),
+            tag_runs(actual, 0, actual.char_count())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a display, run with xvfb-run cargo test -- --ignored"]
+    fn edit_save_hydrate_round_trip() {
+        on_gtk_thread(edit_save_hydrate_round_trip_on_gtk_thread);
+    }
+
+    fn edit_save_hydrate_round_trip_on_gtk_thread() {
+        // edits in the middle of the text, with the cursor somewhere else
+        let tb = new_buffer();
+        let mut log = vec![];
+        edit(&tb, &mut log, insert(0, "world", "color_tag_1"));
+        edit(&tb, &mut log, Ops::MoveCursor(5));
+        edit(&tb, &mut log, insert(0, "hello ", "color_tag_2"));
+        edit(&tb, &mut log, insert(11, "!", "color_tag_1"));
+        edit(&tb, &mut log, insert(6, "big ", "color_tag_1"));
+        edit(&tb, &mut log, Ops::Delete((7, 9)));
+        edit(&tb, &mut log, Ops::ApplyTag(("bold".to_string(), 0, 5)));
+        edit(&tb, &mut log, Ops::ApplyTag(("italic".to_string(), 3, 8)));
+        edit(&tb, &mut log, Ops::RemoveTag(("italic".to_string(), 4, 5)));
+        assert_eq!(buffer_text(&tb), "hello b world!");
+
+        let bytes = note_file::encode(&Note::new(None, log, vec![]))
+            .ok()
+            .unwrap();
+        assert_same_buffer(&tb, &hydrate(&bytes));
+
+        // a compacted note followed by more edits
+        let mut log = vec![];
+        let compacted = snapshot(&tb, "color_tag_1");
+        edit(&tb, &mut log, insert(0, "> ", "color_tag_2"));
+        edit(&tb, &mut log, Ops::Delete((8, 10)));
+
+        let bytes = note_file::encode(&Note::new(Some(compacted), log, vec![]))
+            .ok()
+            .unwrap();
+        assert_same_buffer(&tb, &hydrate(&bytes));
+
+        // tags the app doesn't define come from the note's tag table, or
+        // stay plain when the note doesn't know their look either
+        let tb = new_buffer();
+        let mut log = vec![];
+        edit(&tb, &mut log, insert(0, "retired", "color_tag_9"));
+        edit(&tb, &mut log, Ops::ApplyTag(("mystery".to_string(), 0, 3)));
+        let retired_style = TagStyle {
+            foreground: Some("rgb(1,2,3)".to_string()),
+            background: None,
+            font_family: Some("JetBrains Mono".to_string()),
+            size_points: None,
+            italic: true,
+            weight: Some(600),
+            strikethrough: false,
+            underline: false,
+            invisible: false,
+            scale: None,
+            left_margin: None,
+        };
+        let tags = vec![("color_tag_9".to_string(), retired_style.clone())];
+
+        let bytes = note_file::encode(&Note::new(None, log, tags)).ok().unwrap();
+        let hydrated = hydrate(&bytes);
+        assert_same_buffer(&tb, &hydrated);
+
+        let hydrated_styles = tag_styles(&hydrated);
+        let (_, style) = hydrated_styles
+            .iter()
+            .find(|(name, _)| name == "color_tag_9")
+            .expect("the tag table should have been registered");
+        assert_eq!(style.foreground, retired_style.foreground);
+        assert_eq!(style.weight, Some(600));
+        assert!(style.italic);
+    }
+
+    /// Any ops leave the buffer showing what they leave in the document.
+    #[test]
+    #[ignore = "needs a display, run with xvfb-run cargo test -- --ignored"]
+    fn buffer_shows_what_the_document_holds() {
+        on_gtk_thread(|| {
+            let mut runner = TestRunner::default();
+            let ops = prop::collection::vec(any_op(12), 0..24);
+            let result = runner.run(&ops, |ops| {
+                let tb = new_buffer();
+                let mut document = Document::new();
+                for op in &ops {
+                    document.apply(op);
+                    apply(&tb, op);
+                }
+
+                prop_assert_eq!(buffer_text(&tb), document.text());
+                let mut runs = tag_runs(&tb, 0, tb.char_count());
+                runs.sort();
+                prop_assert_eq!(runs, document.tag_runs(0, document.char_count()));
+                Ok(())
+            });
+            if let Err(err) = result {
+                panic!("{}", err);
+            }
+        });
+    }
+
+    proptest! {
+        /// The document side of the round trip, which needs no display.
+        #[test]
+        fn document_survives_save_and_hydrate(
+            ops in prop::collection::vec(any_op(12), 0..24),
+            compacted_ops in 0..24usize,
+        ) {
+            let compacted_ops = compacted_ops.min(ops.len());
+            let mut document = Document::new();
+            for op in &ops[..compacted_ops] {
+                document.apply(op);
+            }
+            let snapshot = document.snapshot("color_tag_1");
+            for op in &ops[compacted_ops..] {
+                document.apply(op);
+            }
+
+            let note = Note::new(Some(snapshot), ops[compacted_ops..].to_vec(), vec![]);
+            let bytes = note_file::encode(&note).ok().unwrap();
+            let hydrated_note = note_file::decode(&bytes).ok().unwrap();
+
+            let mut hydrated = Document::new();
+            if let Some(snapshot) = &hydrated_note.snapshot {
+                hydrated.restore_snapshot(snapshot);
+            }
+            for op in &hydrated_note.ops {
+                hydrated.apply(op);
+            }
+            prop_assert_eq!(hydrated, document);
+        }
+    }
+
+    /**
+     * Edits made on the buffer are recorded through the signal handlers the
+     * editor uses, with the cursor away from where the text changes. Inserts
+     * are recorded once gtk is done with them, like the queued messages of
+     * the editor are.
+     */
+    #[test]
+    #[ignore = "needs a display, run with xvfb-run cargo test -- --ignored"]
+    fn recorded_edits_survive_save_and_hydrate() {
+        on_gtk_thread(|| {
+            let tb = new_buffer();
+            let log = Rc::new(RefCell::new(vec![]));
+            let inserted = Rc::new(RefCell::new(vec![]));
+            {
+                let inserted = inserted.clone();
+                tb.connect_insert_text(move |_, iter, content| {
+                    inserted.borrow_mut().push(insert_event_data(iter, content));
+                });
+            }
+            {
+                let log = log.clone();
+                tb.connect_delete_range(move |tb, start, end| {
+                    log.borrow_mut()
+                        .push(record_delete(&delete_event_data(tb, start, end)));
+                });
+            }
+            let type_at = |offset: i32, content: &str, tag_name: &str| {
+                tb.insert(&mut tb.iter_at_offset(offset), content);
+                for event in inserted.borrow_mut().drain(..) {
+                    log.borrow_mut().push(record_insert(&tb, &event, tag_name));
+                }
+            };
+
+            type_at(0, "world", "color_tag_1");
+            tb.place_cursor(&tb.start_iter());
+            type_at(5, "!", "color_tag_2");
+            type_at(0, "hello ", "color_tag_2");
+            tb.place_cursor(&tb.end_iter());
+            tb.delete(&mut tb.iter_at_offset(6), &mut tb.iter_at_offset(7));
+            type_at(6, "W", "color_tag_1");
+            assert_eq!(buffer_text(&tb), "hello World!");
+            assert_eq!(
+                log.borrow()[1..4],
+                [
+                    insert(5, "!", "color_tag_2"),
+                    insert(0, "hello ", "color_tag_2"),
+                    Ops::Delete((6, 7)),
+                ]
+            );
+
+            let bytes = note_file::encode(&Note::new(None, log.borrow().clone(), vec![]))
+                .ok()
+                .unwrap();
+            assert_same_buffer(&tb, &hydrate(&bytes));
+        });
+    }
+}
@@ -0,0 +1,336 @@
+use crate::crypto;
+use crate::document::Document;
+use crate::export::{self, MarkdownOptions};
+use crate::note_file::{self, Note};
+use crate::notebook::{Notebook, NOTE_EXTENSION};
+use crate::text_ops::{InsertOpsData, Ops};
+use crate::timeline;
+use crate::{color_tag_colors, highlight_tag_colors};
+use common::palette::Palette;
+use std::fmt;
+use std::path::Path;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Where the passphrase of encrypted notes comes from, it is never asked for.
+const PASSPHRASE_VAR: &str = "PRETTY_NOTES_PASSPHRASE";
+
+const USAGE: &str = "\
+Usage: relm <command> <note> [options]
+
+<note> is the name of a note in the notebook or the path of a .note file.
+Encrypted notes are opened with the passphrase in $PRETTY_NOTES_PASSPHRASE.
+
+Commands:
+  cat <note>            print the text of the note, hidden parts included
+  export <note> --format md|html|txt [--keep-colors] [--output <file>]
+                        print the note in another format, or write it to <file>
+  append <note> <text>  add <text> to the end of the note
+  stats <note>          count the characters, words, lines and ops
+  compact <note>        fold the op log into the snapshot
+";
+
+enum CliError {
+    /// the arguments don't make sense, the usage gets printed along
+    Usage(String),
+    Failed(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A note read from the notebook it was found in.
+struct OpenNote {
+    notebook: Notebook,
+    name: String,
+    note: Note,
+    /// set for encrypted notes, which are saved encrypted again
+    passphrase: Option<String>,
+}
+
+impl OpenNote {
+    /**
+     * Finds `note` either as the path of a note file or by name in the
+     * per-user notebook, and decodes it.
+     */
+    fn open(note: &str) -> Result<Self, CliError> {
+        let path = Path::new(note);
+        let is_note_file = path.extension().map_or(false, |ext| ext == NOTE_EXTENSION);
+
+        let (notebook, name) = if is_note_file && path.is_file() {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => Path::new(".").to_path_buf(),
+            };
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            (Notebook::open(dir), name)
+        } else {
+            (Notebook::open_default(), note.to_string())
+        };
+        let notebook = notebook.map_err(|err| CliError::Failed(err.to_string()))?;
+        if !notebook.exists(name.as_str()) {
+            return Err(CliError::Failed(format!(
+                "There is no note named \"{}\"",
+                note
+            )));
+        }
+
+        let mut bytes = notebook
+            .read(name.as_str())
+            .map_err(|err| CliError::Failed(format!("Couldn't read the note: {}", err)))?;
+        let mut passphrase = None;
+        if crypto::is_encrypted(&bytes) {
+            let given = std::env::var(PASSPHRASE_VAR).map_err(|_| {
+                CliError::Failed(format!(
+                    "\"{}\" is encrypted, put its passphrase in ${}",
+                    note, PASSPHRASE_VAR
+                ))
+            })?;
+            bytes = crypto::decrypt(&bytes, given.as_str())
+                .map_err(|err| CliError::Failed(err.to_string()))?;
+            passphrase = Some(given);
+        }
+        let note = note_file::decode(&bytes).map_err(|err| CliError::Failed(err.to_string()))?;
+
+        return Ok(OpenNote {
+            notebook,
+            name,
+            note,
+            passphrase,
+        });
+    }
+
+    fn save(&self) -> Result<(), CliError> {
+        let mut bytes =
+            note_file::encode(&self.note).map_err(|err| CliError::Failed(err.to_string()))?;
+        if let Some(passphrase) = &self.passphrase {
+            bytes = crypto::encrypt(&bytes, passphrase.as_str())
+                .map_err(|err| CliError::Failed(err.to_string()))?;
+        }
+        return self
+            .notebook
+            .write(self.name.as_str(), &bytes)
+            .map_err(|err| CliError::Failed(format!("Couldn't save the note: {}", err)));
+    }
+}
+
+/**
+ * Replays the note the way the editor does when it opens it. Comes with the
+ * color typed text would get, if the note ever picked one.
+ */
+fn replay(note: &Note) -> (Document, Option<String>) {
+    return timeline::version_at(note.snapshot.as_ref(), &note.ops, note.ops.len());
+}
+
+fn load_palette() -> Result<Palette, CliError> {
+    return Palette::load(&Palette::default_path())
+        .map_err(|err| CliError::Failed(format!("Couldn't read the palette: {}", err)));
+}
+
+fn export(name: &str, note: &Note, args: &[String]) -> Result<String, CliError> {
+    let mut format = None;
+    let mut keep_colors = false;
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next(),
+            "--keep-colors" => keep_colors = true,
+            "--output" | "-o" => output = args.next(),
+            _ => return Err(CliError::Usage(format!("Unknown option \"{}\"", arg))),
+        }
+    }
+
+    let (document, _) = replay(note);
+    let text = document.text();
+    let runs = document.tag_runs(0, document.char_count());
+    let exported = match format.map(|format| format.as_str()) {
+        Some("md") => export::to_markdown(
+            text.as_str(),
+            &runs,
+            &color_tag_colors(&load_palette()?),
+            &highlight_tag_colors(),
+            &MarkdownOptions { keep_colors },
+        ),
+        Some("html") => export::to_html(name, text.as_str(), &runs, &note.tags),
+        Some("txt") => export::to_plain_text(text.as_str(), &runs),
+        _ => {
+            return Err(CliError::Usage(
+                "--format takes md, html or txt".to_string(),
+            ))
+        }
+    };
+
+    return match output {
+        Some(path) => std::fs::write(path, exported)
+            .map(|_| String::new())
+            .map_err(|err| CliError::Failed(format!("Couldn't export the note: {}", err))),
+        None => Ok(exported),
+    };
+}
+
+/// Adds `text` at the end of the note in the color it would be typed in.
+fn append(note: &mut Note, text: &str, default_tag: String) {
+    let (document, current_tag) = replay(note);
+    note.ops.push(Ops::Insert(InsertOpsData::new(
+        document.char_count(),
+        text.to_string(),
+        current_tag.unwrap_or(default_tag),
+    )));
+    note.op_times.push(timeline::now());
+}
+
+fn stats(note: &Note) -> String {
+    let (document, _) = replay(note);
+    let text = document.text();
+
+    return format!(
+        "characters  {}\nwords       {}\nlines       {}\nops         {}\nsnapshot    {}\n",
+        document.char_count(),
+        text.unicode_words().count(),
+        text.lines().count(),
+        note.ops.len(),
+        if note.snapshot.is_some() { "yes" } else { "no" }
+    );
+}
+
+/// Folds the op log into the snapshot and returns how many ops it held.
+fn compact(note: &mut Note, default_tag: String) -> usize {
+    let (document, current_tag) = replay(note);
+    let current_tag = current_tag.unwrap_or(default_tag);
+
+    note.snapshot = Some(document.snapshot(current_tag.as_str()));
+    note.op_times.clear();
+    return note.ops.drain(..).count();
+}
+
+fn run_command(args: &[String]) -> Result<String, CliError> {
+    let (command, note) = match args {
+        [command, note, ..] => (command.as_str(), note.as_str()),
+        [command] if command == "--help" || command == "help" => return Ok(USAGE.to_string()),
+        _ => return Err(CliError::Usage("Which note?".to_string())),
+    };
+    let rest = &args[2..];
+
+    match command {
+        "cat" => {
+            let (document, _) = replay(&OpenNote::open(note)?.note);
+            Ok(document.text())
+        }
+        "export" => {
+            let open_note = OpenNote::open(note)?;
+            export(open_note.name.as_str(), &open_note.note, rest)
+        }
+        "append" => {
+            let text = match rest {
+                [text] => text,
+                _ => return Err(CliError::Usage("append takes the text to add".to_string())),
+            };
+            let mut open_note = OpenNote::open(note)?;
+            append(&mut open_note.note, text, load_palette()?.first_tag_name());
+            open_note.save()?;
+            Ok(String::new())
+        }
+        "stats" => Ok(stats(&OpenNote::open(note)?.note)),
+        "compact" => {
+            let mut open_note = OpenNote::open(note)?;
+            let compacted = compact(&mut open_note.note, load_palette()?.first_tag_name());
+            open_note.save()?;
+            Ok(format!("Folded {} ops into the snapshot\n", compacted))
+        }
+        _ => Err(CliError::Usage(format!("Unknown command \"{}\"", command))),
+    }
+}
+
+/// Runs the command in `args` and returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    match run_command(args) {
+        Ok(output) => {
+            print!("{}", output);
+            return 0;
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            return match err {
+                CliError::Usage(_) => 2,
+                CliError::Failed(_) => 1,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::insert;
+
+    fn note() -> Note {
+        let mut document = Document::new();
+        document.apply(&insert(0, "first line\n", "color_tag_1"));
+        let snapshot = document.snapshot("color_tag_1");
+
+        let ops = vec![
+            insert(11, "second", "color_tag_1"),
+            Ops::SelectColorTag("color_tag_2".to_string()),
+            Ops::ApplyTag(("bold".to_string(), 0, 5)),
+        ];
+        return Note::new(Some(snapshot), ops, vec![]);
+    }
+
+    #[test]
+    fn replays_the_snapshot_and_the_ops() {
+        let (document, current_tag) = replay(&note());
+        assert_eq!(document.text(), "first line\nsecond");
+        assert_eq!(current_tag, Some("color_tag_2".to_string()));
+        assert_eq!(stats(&note()).lines().nth(1), Some("words       3"));
+    }
+
+    #[test]
+    fn append_uses_the_color_picked_last() {
+        let mut note = note();
+        append(&mut note, "!", "color_tag_1".to_string());
+        assert_eq!(note.ops.last(), Some(&insert(17, "!", "color_tag_2")),);
+        assert_eq!(note.op_times.len(), note.ops.len());
+
+        let mut empty = Note::new(None, vec![], vec![]);
+        append(&mut empty, "hi", "color_tag_1".to_string());
+        assert_eq!(empty.ops, vec![insert(0, "hi", "color_tag_1")]);
+    }
+
+    #[test]
+    fn compacting_keeps_the_document() {
+        let mut note = note();
+        let (before, _) = replay(&note);
+
+        assert_eq!(compact(&mut note, "color_tag_1".to_string()), 3);
+        assert!(note.ops.is_empty());
+        assert!(note.op_times.is_empty());
+        let (after, current_tag) = replay(&note);
+        assert_eq!(after, before);
+        assert_eq!(current_tag, Some("color_tag_2".to_string()));
+    }
+
+    #[test]
+    fn export_needs_a_known_format() {
+        let args = vec!["--format".to_string(), "pdf".to_string()];
+        assert!(matches!(
+            export("note", &note(), &args),
+            Err(CliError::Usage(_))
+        ));
+
+        let args = vec!["--format".to_string(), "txt".to_string()];
+        assert_eq!(
+            export("note", &note(), &args).ok(),
+            Some("first line\nsecond".to_string())
+        );
+    }
+}
@@ -0,0 +1,1053 @@
+use crate::document::Document;
+use crate::tags::is_view_tag;
+use crate::text_ops::{InsertOpsData, Ops};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The site of the instance that shares a note, the one that joins is the other.
+pub const HOST_SITE: u32 = 1;
+pub const GUEST_SITE: u32 = 2;
+/// Messages longer than this are not something a peer sent.
+const MAX_MESSAGE_LEN: u32 = 256 * 1024 * 1024;
+/// How often a host waiting for its peer checks whether it should stop.
+const ACCEPT_POLL_MS: u64 = 100;
+
+/**
+ * Names a char for good: a Lamport time and the site that inserted it.
+ * Later times sort higher, the site breaks ties. Tag changes are stamped
+ * the same way.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CharId {
+    counter: u64,
+    site: u32,
+}
+
+/// The last change of a tag on one char, the one with the higher stamp wins.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct TagState {
+    stamp: CharId,
+    is_set: bool,
+}
+
+/// A char of the shared note, deleted ones stay as tombstones.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Element {
+    id: CharId,
+    value: char,
+    is_deleted: bool,
+    tags: BTreeMap<String, TagState>,
+}
+
+impl Element {
+    fn has_tag(&self, tag_name: &str) -> bool {
+        return self.tags.get(tag_name).map_or(false, |state| state.is_set);
+    }
+}
+
+/// An edit as it travels between replicas, pointing at chars by their ids.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Edit {
+    /**
+     * `text` goes right after the char `after`, or at the start. Its chars
+     * get consecutive ids starting at `id` and carry `tags`.
+     */
+    Insert {
+        id: CharId,
+        after: Option<CharId>,
+        text: String,
+        tags: Vec<String>,
+    },
+    Delete(Vec<CharId>),
+    Tag {
+        tag_name: String,
+        stamp: CharId,
+        ids: Vec<CharId>,
+        is_set: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Message {
+    /// the host's first message: the name of the note and all of its chars
+    Hello {
+        name: String,
+        state: Vec<Element>,
+    },
+    Edit(Edit),
+    /// the char the sender's cursor is right after, `None` at the start
+    Cursor(Option<CharId>),
+}
+
+/**
+ * One copy of a shared note, a sequence CRDT in the manner of RGA. Chars are
+ * never taken out, so an edit can always find the chars it refers to, and
+ * concurrent inserts at the same spot are ordered by their ids. Replicas
+ * that have integrated the same edits hold the same note, whatever order
+ * they came in.
+ *
+ * Offsets are those of the visible chars, the same the `Document` of the
+ * note uses.
+ */
+pub struct Replica {
+    site: u32,
+    clock: u64,
+    elements: Vec<Element>,
+}
+
+impl Replica {
+    /// Starts sharing `document`, its chars get ids of `site`.
+    pub fn new(site: u32, document: &Document) -> Self {
+        let mut replica = Replica {
+            site,
+            clock: 0,
+            elements: vec![],
+        };
+        for value in document.text().chars() {
+            let id = replica.next_id();
+            replica.elements.push(Element {
+                id,
+                value,
+                is_deleted: false,
+                tags: BTreeMap::new(),
+            });
+        }
+        for (tag_name, start_offset, end_offset) in document.tag_runs(0, document.char_count()) {
+            for element in &mut replica.elements[start_offset as usize..end_offset as usize] {
+                let state = TagState {
+                    stamp: element.id,
+                    is_set: true,
+                };
+                element.tags.insert(tag_name.to_string(), state);
+            }
+        }
+        return replica;
+    }
+
+    /// Takes over the chars another replica sent along in its `Hello`.
+    pub fn join(site: u32, state: Vec<Element>) -> Self {
+        let clock = state
+            .iter()
+            .flat_map(|element| {
+                let stamps = element.tags.values().map(|state| state.stamp.counter);
+                std::iter::once(element.id.counter).chain(stamps)
+            })
+            .max()
+            .unwrap_or(0);
+
+        return Replica {
+            site,
+            clock,
+            elements: state,
+        };
+    }
+
+    pub fn state(&self) -> Vec<Element> {
+        return self.elements.clone();
+    }
+
+    /// The note this replica holds.
+    pub fn document(&self) -> Document {
+        let mut document = Document::new();
+        let visible = self.visible();
+        let text = visible
+            .iter()
+            .map(|&idx| self.elements[idx].value)
+            .collect::<String>();
+        document.apply(&Ops::Insert(InsertOpsData::new(0, text, String::new())));
+
+        for (offset, &idx) in visible.iter().enumerate() {
+            for (tag_name, state) in &self.elements[idx].tags {
+                if state.is_set {
+                    let offset = offset as i32;
+                    document.apply(&Ops::ApplyTag((tag_name.to_string(), offset, offset + 1)));
+                }
+            }
+        }
+        return document;
+    }
+
+    fn next_id(&mut self) -> CharId {
+        self.clock += 1;
+        return CharId {
+            counter: self.clock,
+            site: self.site,
+        };
+    }
+
+    /// Keeps the clock ahead of every id and stamp this replica has seen.
+    fn observe(&mut self, id: CharId, len: usize) {
+        self.clock = self.clock.max(id.counter + len.max(1) as u64 - 1);
+    }
+
+    /// Indices of the chars that aren't deleted, in order.
+    fn visible(&self) -> Vec<usize> {
+        return self
+            .elements
+            .iter()
+            .enumerate()
+            .filter(|(_, element)| !element.is_deleted)
+            .map(|(idx, _)| idx)
+            .collect();
+    }
+
+    fn index_of(&self, id: CharId) -> Option<usize> {
+        return self.elements.iter().position(|element| element.id == id);
+    }
+
+    /// The number of visible chars before the element at `idx`.
+    fn offset_of(&self, idx: usize) -> i32 {
+        return self.elements[..idx]
+            .iter()
+            .filter(|element| !element.is_deleted)
+            .count() as i32;
+    }
+
+    /// Like `Document`, offsets below zero or past the end mean the end.
+    fn range(&self, visible: &[usize], start_offset: i32, end_offset: i32) -> (usize, usize) {
+        let len = visible.len() as i32;
+        let clamp = |offset: i32| {
+            if offset < 0 || offset > len {
+                len
+            } else {
+                offset
+            }
+        };
+        let (start, end) = (clamp(start_offset), clamp(end_offset));
+        return (start.min(end) as usize, start.max(end) as usize);
+    }
+
+    /**
+     * The char a cursor at `offset` comes right after, `None` at the start.
+     * Sending this rather than the offset keeps the cursor in place while
+     * the peer's edits move the text around it.
+     */
+    pub fn anchor(&self, offset: i32) -> Option<CharId> {
+        let visible = self.visible();
+        let (_, end) = self.range(&visible, 0, offset);
+        if end == 0 {
+            return None;
+        }
+        return Some(self.elements[visible[end - 1]].id);
+    }
+
+    /// Where a cursor right after `anchor` is now.
+    pub fn anchor_offset(&self, anchor: Option<CharId>) -> i32 {
+        return match anchor.and_then(|id| self.index_of(id)) {
+            Some(idx) => self.offset_of(idx + 1),
+            None => 0,
+        };
+    }
+
+    /**
+     * Turns an op applied to the note into an edit for the other replicas
+     * and integrates it here. Ops that don't change the text or the tags
+     * give `None`.
+     */
+    pub fn local(&mut self, op: &Ops) -> Option<Edit> {
+        let visible = self.visible();
+        let ids_in = |replica: &Replica, start: usize, end: usize| {
+            visible[start..end]
+                .iter()
+                .map(|&idx| replica.elements[idx].id)
+                .collect::<Vec<CharId>>()
+        };
+
+        let edit = match op {
+            Ops::Insert(insert_ops_data) => {
+                if insert_ops_data.content.is_empty() {
+                    return None;
+                }
+                let (_, offset) = self.range(&visible, 0, insert_ops_data.offset);
+                let after = match offset {
+                    0 => None,
+                    _ => Some(&self.elements[visible[offset - 1]]),
+                };
+
+                // like the document, the text takes on the tags before it
+                let mut tags = after.map_or(vec![], |element| {
+                    element
+                        .tags
+                        .iter()
+                        .filter(|(_, state)| state.is_set)
+                        .map(|(tag_name, _)| tag_name.to_string())
+                        .collect()
+                });
+                let tag_name = insert_ops_data.tag.as_str();
+                if !tag_name.is_empty()
+                    && !is_view_tag(tag_name)
+                    && !tags.contains(&tag_name.to_string())
+                {
+                    tags.push(tag_name.to_string());
+                }
+
+                let after = after.map(|element| element.id);
+                let id = self.next_id();
+                self.clock += insert_ops_data.content.chars().count() as u64 - 1;
+                Edit::Insert {
+                    id,
+                    after,
+                    text: insert_ops_data.content.to_string(),
+                    tags,
+                }
+            }
+            Ops::Delete((start_offset, end_offset)) => {
+                let (start, end) = self.range(&visible, *start_offset, *end_offset);
+                if start == end {
+                    return None;
+                }
+                Edit::Delete(ids_in(self, start, end))
+            }
+            Ops::ApplyTag((tag_name, start_offset, end_offset))
+            | Ops::RemoveTag((tag_name, start_offset, end_offset)) => {
+                let (start, end) = self.range(&visible, *start_offset, *end_offset);
+                if start == end || is_view_tag(tag_name) {
+                    return None;
+                }
+                let ids = ids_in(self, start, end);
+                Edit::Tag {
+                    tag_name: tag_name.to_string(),
+                    stamp: self.next_id(),
+                    ids,
+                    is_set: matches!(op, Ops::ApplyTag(_)),
+                }
+            }
+            Ops::MoveCursor(_) | Ops::SelectColorTag(_) => return None,
+        };
+
+        self.integrate(&edit);
+        return Some(edit);
+    }
+
+    /**
+     * Integrates an edit of another replica and returns the ops that bring
+     * the note up to date with it. `None` when the edit refers to chars this
+     * replica never had, it can't follow the other one anymore then.
+     */
+    pub fn remote(&mut self, edit: &Edit) -> Option<Vec<Ops>> {
+        return self.integrate(edit);
+    }
+
+    fn integrate(&mut self, edit: &Edit) -> Option<Vec<Ops>> {
+        let mut ops = vec![];
+
+        match edit {
+            Edit::Insert {
+                id,
+                after,
+                text,
+                tags,
+            } => {
+                let mut idx = match after {
+                    Some(after) => self.index_of(*after)? + 1,
+                    None => 0,
+                };
+                // concurrent inserts at the same spot, the newest goes first
+                while idx < self.elements.len() && self.elements[idx].id > *id {
+                    idx += 1;
+                }
+
+                // every later char comes right after the one before it,
+                // nothing newer can sit there yet
+                let offset = self.offset_of(idx);
+                let tags_before = match offset {
+                    0 => vec![],
+                    _ => {
+                        let before = self.visible()[offset as usize - 1];
+                        self.elements[before]
+                            .tags
+                            .iter()
+                            .filter(|(_, state)| state.is_set)
+                            .map(|(tag_name, _)| tag_name.to_string())
+                            .collect()
+                    }
+                };
+                let new_elements = text.chars().enumerate().map(|(n, value)| {
+                    let char_id = CharId {
+                        counter: id.counter + n as u64,
+                        site: id.site,
+                    };
+                    let tag_states = tags.iter().map(|tag_name| {
+                        let state = TagState {
+                            stamp: *id,
+                            is_set: true,
+                        };
+                        (tag_name.to_string(), state)
+                    });
+                    Element {
+                        id: char_id,
+                        value,
+                        is_deleted: false,
+                        tags: tag_states.collect(),
+                    }
+                });
+                self.elements.splice(idx..idx, new_elements);
+                self.observe(*id, text.chars().count());
+
+                let end_offset = offset + text.chars().count() as i32;
+                ops.push(Ops::Insert(InsertOpsData::new(
+                    offset,
+                    text.to_string(),
+                    String::new(),
+                )));
+                for tag_name in tags_before
+                    .iter()
+                    .filter(|tag_name| !tags.contains(tag_name))
+                {
+                    ops.push(Ops::RemoveTag((tag_name.to_string(), offset, end_offset)));
+                }
+                for tag_name in tags {
+                    ops.push(Ops::ApplyTag((tag_name.to_string(), offset, end_offset)));
+                }
+            }
+            Edit::Delete(ids) => {
+                for id in ids {
+                    let idx = match self.index_of(*id) {
+                        Some(idx) if !self.elements[idx].is_deleted => idx,
+                        _ => continue,
+                    };
+                    let offset = self.offset_of(idx);
+                    self.elements[idx].is_deleted = true;
+
+                    match ops.last_mut() {
+                        Some(Ops::Delete((start_offset, end_offset)))
+                            if *start_offset == offset =>
+                        {
+                            *end_offset += 1
+                        }
+                        _ => ops.push(Ops::Delete((offset, offset + 1))),
+                    }
+                }
+            }
+            Edit::Tag {
+                tag_name,
+                stamp,
+                ids,
+                is_set,
+            } => {
+                self.observe(*stamp, 1);
+                for id in ids {
+                    let idx = match self.index_of(*id) {
+                        Some(idx) => idx,
+                        None => continue,
+                    };
+                    let element = &mut self.elements[idx];
+                    let was_set = element.has_tag(tag_name);
+                    match element.tags.get(tag_name) {
+                        Some(state) if state.stamp >= *stamp => continue,
+                        _ => {}
+                    }
+                    element.tags.insert(
+                        tag_name.to_string(),
+                        TagState {
+                            stamp: *stamp,
+                            is_set: *is_set,
+                        },
+                    );
+                    if element.is_deleted || was_set == *is_set {
+                        continue;
+                    }
+
+                    let offset = self.offset_of(idx);
+                    let extended = match ops.last_mut() {
+                        Some(Ops::ApplyTag((_, _, end_offset)))
+                        | Some(Ops::RemoveTag((_, _, end_offset)))
+                            if *end_offset == offset =>
+                        {
+                            *end_offset += 1;
+                            true
+                        }
+                        _ => false,
+                    };
+                    if !extended {
+                        let tag = (tag_name.to_string(), offset, offset + 1);
+                        ops.push(if *is_set {
+                            Ops::ApplyTag(tag)
+                        } else {
+                            Ops::RemoveTag(tag)
+                        });
+                    }
+                }
+            }
+        }
+
+        return Some(ops);
+    }
+}
+
+/**
+ * Merges two lines of edits made to `base`: returns the ops that bring a
+ * note that has had `mine` applied up to date with `theirs`, as if the two
+ * had been shared live all along.
+ */
+pub fn merge(base: &Document, theirs: &[Ops], mine: &[Ops]) -> Vec<Ops> {
+    let mut their_replica = Replica::new(HOST_SITE, base);
+    let mut my_replica = Replica::join(GUEST_SITE, their_replica.state());
+
+    let their_edits = theirs
+        .iter()
+        .filter_map(|op| their_replica.local(op))
+        .collect::<Vec<Edit>>();
+    for op in mine {
+        my_replica.local(op);
+    }
+
+    return their_edits
+        .iter()
+        .flat_map(|edit| {
+            my_replica
+                .remote(edit)
+                .expect("both replicas start out with the chars of `base`")
+        })
+        .collect();
+}
+
+/// Where to share a note: a path means a Unix domain socket, anything else
+/// is a TCP address like `127.0.0.1:7878`.
+pub enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn parse(address: &str) -> Self {
+        #[cfg(unix)]
+        {
+            if address.contains('/') {
+                return Endpoint::Unix(PathBuf::from(address));
+            }
+        }
+        return Endpoint::Tcp(address.to_string());
+    }
+}
+
+/// A connection to the peer, cloned to read and write from different threads.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+        return match endpoint {
+            Endpoint::Tcp(address) => Ok(Stream::Tcp(TcpStream::connect(address.as_str())?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+        };
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        return match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        };
+    }
+
+    /// Ends the connection, a thread reading from it gets to see that too.
+    pub fn shutdown(&self) {
+        // the peer may have hung up already
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+
+    /// Sends `message` with its length in front, as a little endian u32.
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        let bytes = bincode::serialize(message)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut framed = (bytes.len() as u32).to_le_bytes().to_vec();
+        framed.extend(bytes);
+        return self.write_all(&framed);
+    }
+
+    pub fn receive(&mut self) -> io::Result<Message> {
+        let mut len = [0; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the peer sent a message that is too long",
+            ));
+        }
+
+        let mut bytes = vec![0; len as usize];
+        self.read_exact(&mut bytes)?;
+        return bincode::deserialize(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Waits for the peer to join a shared note.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        let listener = match endpoint {
+            Endpoint::Tcp(address) => Listener::Tcp(TcpListener::bind(address.as_str())?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Listener::Unix(UnixListener::bind(path)?, path.to_path_buf()),
+        };
+        // polled, so the host can stop waiting
+        match &listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(true)?,
+        }
+        return Ok(listener);
+    }
+
+    /// The address the peer connects to, with the port picked for `:0`.
+    pub fn address(&self) -> String {
+        return match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|address| address.to_string())
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => path.display().to_string(),
+        };
+    }
+
+    /// Waits for a peer until `stop` is set, which gives `None`.
+    pub fn accept(&self, stop: &AtomicBool) -> io::Result<Option<Stream>> {
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            let accepted = match self {
+                Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_nonblocking(false)?;
+                    Ok(Stream::Tcp(stream))
+                }),
+                #[cfg(unix)]
+                Listener::Unix(listener, _) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_nonblocking(false)?;
+                    Ok(Stream::Unix(stream))
+                }),
+            };
+            match accepted {
+                Ok(stream) => return Ok(Some(stream)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // the socket file would keep the path taken
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, path) = self {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// What the thread of a shared note reports back to the editor.
+pub enum Event {
+    /// the peer joined, messages go out through this stream
+    Connected(Stream),
+    Received(Message),
+    /// the connection ended, with the reason unless it was closed here
+    Closed(Option<String>),
+}
+
+/// Reads messages from `stream` until it ends, passing them to `on_event`.
+fn read_messages(mut stream: Stream, stop: &AtomicBool, on_event: &dyn Fn(Event)) {
+    loop {
+        match stream.receive() {
+            Ok(message) => on_event(Event::Received(message)),
+            Err(err) => {
+                let reason = match err.kind() {
+                    _ if stop.load(Ordering::Relaxed) => None,
+                    io::ErrorKind::UnexpectedEof => Some("The peer stopped sharing".to_string()),
+                    _ => Some(err.to_string()),
+                };
+                on_event(Event::Closed(reason));
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Waits for a peer on `listener` in a thread of its own and reads what it
+ * sends. Setting `stop` ends the waiting.
+ */
+pub fn host(listener: Listener, stop: Arc<AtomicBool>, on_event: impl Fn(Event) + Send + 'static) {
+    std::thread::spawn(move || {
+        let stream = match listener.accept(&stop) {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return,
+            Err(err) => {
+                on_event(Event::Closed(Some(err.to_string())));
+                return;
+            }
+        };
+        drop(listener);
+
+        match stream.try_clone() {
+            Ok(writer) => on_event(Event::Connected(writer)),
+            Err(err) => {
+                on_event(Event::Closed(Some(err.to_string())));
+                return;
+            }
+        }
+        read_messages(stream, &stop, &on_event);
+    });
+}
+
+/**
+ * Connects to the host of a shared note and reads what it sends in a thread
+ * of its own. Returns the stream to send to.
+ */
+pub fn join(
+    endpoint: &Endpoint,
+    stop: Arc<AtomicBool>,
+    on_event: impl Fn(Event) + Send + 'static,
+) -> io::Result<Stream> {
+    let stream = Stream::connect(endpoint)?;
+    let reader = stream.try_clone()?;
+    std::thread::spawn(move || read_messages(reader, &stop, &on_event));
+    return Ok(stream);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{any_op, insert};
+    use proptest::prelude::*;
+    use std::sync::mpsc;
+
+    /// A headless instance: the note it shows and its replica of it.
+    struct Peer {
+        document: Document,
+        replica: Replica,
+    }
+
+    impl Peer {
+        fn host(document: Document) -> Self {
+            let replica = Replica::new(HOST_SITE, &document);
+            return Peer { document, replica };
+        }
+
+        fn guest(host: &Peer) -> Self {
+            let replica = Replica::join(GUEST_SITE, host.replica.state());
+            return Peer {
+                document: replica.document(),
+                replica,
+            };
+        }
+
+        /// Makes a local edit, like typing does, and returns what to send.
+        fn edit(&mut self, op: &Ops) -> Option<Edit> {
+            let edit = self.replica.local(op);
+            self.document.apply(op);
+            return edit;
+        }
+
+        fn receive(&mut self, edit: &Edit) {
+            for op in self
+                .replica
+                .remote(edit)
+                .expect("edits only refer to chars the peer has")
+            {
+                self.document.apply(&op);
+            }
+        }
+
+        fn assert_in_sync(&self) {
+            assert_eq!(self.document, self.replica.document());
+        }
+    }
+
+    fn tagged(document: &Document, tag_name: &str) -> String {
+        let text = document.text().chars().collect::<Vec<char>>();
+        return document
+            .tag_runs(0, document.char_count())
+            .into_iter()
+            .filter(|(name, _, _)| name == tag_name)
+            .map(|(_, s, e)| text[s as usize..e as usize].iter().collect::<String>())
+            .collect::<Vec<String>>()
+            .join("|");
+    }
+
+    #[test]
+    fn concurrent_inserts_at_the_same_spot_converge() {
+        let mut host = Peer::host(Document::new());
+        host.edit(&insert(0, "ac", ""));
+        let mut guest = Peer::guest(&host);
+        assert_eq!(guest.document.text(), "ac");
+
+        let from_host = host.edit(&insert(1, "b", "")).unwrap();
+        let from_guest = guest.edit(&insert(1, "B", "bold")).unwrap();
+        host.receive(&from_guest);
+        guest.receive(&from_host);
+
+        assert_eq!(host.document, guest.document);
+        assert_eq!(tagged(&host.document, "bold"), "B");
+        host.assert_in_sync();
+        guest.assert_in_sync();
+    }
+
+    #[test]
+    fn text_typed_into_a_deleted_range_survives() {
+        let mut host = Peer::host(Document::new());
+        host.edit(&insert(0, "hello world", ""));
+        let mut guest = Peer::guest(&host);
+
+        let from_host = host.edit(&Ops::Delete((0, 6))).unwrap();
+        let from_guest = guest.edit(&insert(3, "p", "")).unwrap();
+        host.receive(&from_guest);
+        guest.receive(&from_host);
+
+        assert_eq!(host.document.text(), "pworld");
+        assert_eq!(host.document, guest.document);
+    }
+
+    #[test]
+    fn the_latest_tag_change_wins() {
+        let mut host = Peer::host(Document::new());
+        host.edit(&insert(0, "abcd", ""));
+        let mut guest = Peer::guest(&host);
+
+        let from_host = host
+            .edit(&Ops::ApplyTag(("bold".to_string(), 0, 4)))
+            .unwrap();
+        guest.receive(&from_host);
+        let from_guest = guest
+            .edit(&Ops::RemoveTag(("bold".to_string(), 1, 3)))
+            .unwrap();
+        let from_host = host
+            .edit(&Ops::ApplyTag(("italic".to_string(), 2, 4)))
+            .unwrap();
+        host.receive(&from_guest);
+        guest.receive(&from_host);
+
+        assert_eq!(tagged(&host.document, "bold"), "a|d");
+        assert_eq!(tagged(&host.document, "italic"), "cd");
+        assert_eq!(host.document, guest.document);
+    }
+
+    #[test]
+    fn anchors_follow_the_text() {
+        let mut host = Peer::host(Document::new());
+        host.edit(&insert(0, "abc", ""));
+        let anchor = host.replica.anchor(2);
+        assert_eq!(host.replica.anchor(0), None);
+
+        host.edit(&insert(0, "xy", ""));
+        assert_eq!(host.replica.anchor_offset(anchor), 4);
+        host.edit(&Ops::Delete((3, 5)));
+        assert_eq!(host.replica.anchor_offset(anchor), 3);
+        assert_eq!(host.replica.anchor_offset(None), 0);
+    }
+
+    /// Runs a host and a guest over `endpoint`, each making its edits before
+    /// it sees the other's, and checks that both end up with the same note.
+    fn share_over(endpoint: Endpoint) {
+        let listener = Listener::bind(&endpoint).unwrap();
+        let address = Endpoint::parse(listener.address().as_str());
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (host_events, host_received) = mpsc::channel();
+        host(listener, stop.clone(), move |event| {
+            host_events.send(event).unwrap();
+        });
+        let (guest_events, guest_received) = mpsc::channel();
+        let mut to_host = join(&address, stop.clone(), move |event| {
+            guest_events.send(event).unwrap();
+        })
+        .unwrap();
+        let mut to_guest = match host_received.recv().unwrap() {
+            Event::Connected(stream) => stream,
+            _ => panic!("the guest should have connected"),
+        };
+
+        let mut document = Document::new();
+        document.apply(&insert(0, "shared note", "color_tag_1"));
+        let mut host_peer = Peer::host(document);
+        to_guest
+            .send(&Message::Hello {
+                name: "note".to_string(),
+                state: host_peer.replica.state(),
+            })
+            .unwrap();
+        let mut guest_peer = match guest_received.recv().unwrap() {
+            Event::Received(Message::Hello { name, state }) => {
+                assert_eq!(name, "note");
+                let replica = Replica::join(GUEST_SITE, state);
+                Peer {
+                    document: replica.document(),
+                    replica,
+                }
+            }
+            _ => panic!("the host should have said hello"),
+        };
+
+        let host_ops = vec![
+            insert(6, "big ", "color_tag_2"),
+            Ops::ApplyTag(("bold".to_string(), 0, 6)),
+        ];
+        let guest_ops = vec![
+            Ops::Delete((0, 7)),
+            insert(1, "!", "color_tag_1"),
+            Ops::MoveCursor(2),
+        ];
+        for op in &host_ops {
+            if let Some(edit) = host_peer.edit(op) {
+                to_guest.send(&Message::Edit(edit)).unwrap();
+            }
+        }
+        for op in &guest_ops {
+            if let Some(edit) = guest_peer.edit(op) {
+                to_host.send(&Message::Edit(edit)).unwrap();
+            }
+        }
+        to_host
+            .send(&Message::Cursor(guest_peer.replica.anchor(2)))
+            .unwrap();
+
+        for _ in 0..2 {
+            match host_received.recv().unwrap() {
+                Event::Received(Message::Edit(edit)) => host_peer.receive(&edit),
+                _ => panic!("the guest sent two edits"),
+            }
+        }
+        let peer_cursor = match host_received.recv().unwrap() {
+            Event::Received(Message::Cursor(anchor)) => anchor,
+            _ => panic!("the guest sent its cursor"),
+        };
+        for _ in 0..2 {
+            match guest_received.recv().unwrap() {
+                Event::Received(Message::Edit(edit)) => guest_peer.receive(&edit),
+                _ => panic!("the host sent two edits"),
+            }
+        }
+
+        assert_eq!(host_peer.document.text(), "big n!ote");
+        assert_eq!(host_peer.document, guest_peer.document);
+        host_peer.assert_in_sync();
+        guest_peer.assert_in_sync();
+        assert_eq!(host_peer.replica.anchor_offset(peer_cursor), 6);
+
+        stop.store(true, Ordering::Relaxed);
+        to_host.shutdown();
+        assert!(matches!(
+            guest_received.recv().unwrap(),
+            Event::Closed(None)
+        ));
+        assert!(matches!(host_received.recv().unwrap(), Event::Closed(None)));
+    }
+
+    #[test]
+    fn share_over_tcp() {
+        share_over(Endpoint::Tcp("127.0.0.1:0".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn share_over_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("pretty-notes-{}.sock", std::process::id()));
+        share_over(Endpoint::Unix(path.clone()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn merging_keeps_both_lines_of_edits() {
+        let mut base = Document::new();
+        base.apply(&insert(0, "one two", ""));
+        let theirs = vec![insert(7, " three", "bold")];
+        let mine = vec![Ops::Delete((0, 4)), insert(0, "zero ", "")];
+
+        let mut document = base.clone();
+        for op in &mine {
+            document.apply(op);
+        }
+        for op in merge(&base, &theirs, &mine) {
+            document.apply(&op);
+        }
+        assert_eq!(document.text(), "zero two three");
+        assert_eq!(tagged(&document, "bold"), " three");
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_edits_converge(
+            start in prop::collection::vec(any_op(8), 0..6),
+            host_ops in prop::collection::vec(any_op(12), 0..8),
+            guest_ops in prop::collection::vec(any_op(12), 0..8),
+        ) {
+            let mut document = Document::new();
+            for op in &start {
+                document.apply(op);
+            }
+            let mut host = Peer::host(document);
+            let mut guest = Peer::guest(&host);
+
+            let from_host = host_ops.iter().filter_map(|op| host.edit(op)).collect::<Vec<Edit>>();
+            let from_guest = guest_ops.iter().filter_map(|op| guest.edit(op)).collect::<Vec<Edit>>();
+            for edit in &from_guest {
+                host.receive(edit);
+            }
+            for edit in &from_host {
+                guest.receive(edit);
+            }
+
+            prop_assert_eq!(&host.document, &guest.document);
+            prop_assert_eq!(&host.document, &host.replica.document());
+            prop_assert_eq!(&guest.document, &guest.replica.document());
+        }
+    }
+}
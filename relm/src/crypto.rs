@@ -0,0 +1,240 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+/**
+ * An encrypted note starts with these bytes and a little endian u32 format
+ * version. The key derivation costs, the salt and the nonce follow, then the
+ * encrypted note file. The whole header is authenticated along with it.
+ */
+const MAGIC: &[u8; 4] = b"PNTE";
+const FORMAT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 4 * 4 + SALT_LEN + NONCE_LEN;
+
+/// The costs of Argon2id for new passphrases, 19 MiB and two passes.
+const MEMORY_KIB: u32 = 19 * 1024;
+const ITERATIONS: u32 = 2;
+const PARALLELISM: u32 = 1;
+/// Files asking for more memory than this are not something we wrote.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+
+pub enum CryptoError {
+    /// the passphrase doesn't open the note, or the file was tampered with
+    WrongPassphrase,
+    UnsupportedVersion(u32),
+    Corrupt,
+    /// no randomness for the salt and nonce, or the key couldn't be derived
+    Failed(String),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::WrongPassphrase => write!(
+                f,
+                "The passphrase is wrong, or the note file has been tampered with"
+            ),
+            CryptoError::UnsupportedVersion(version) => write!(
+                f,
+                "The note was encrypted by a newer version of Pretty Notes (encryption version \
+                 {}, this version reads up to {})",
+                version, FORMAT_VERSION
+            ),
+            CryptoError::Corrupt => write!(f, "The encrypted note file is damaged"),
+            CryptoError::Failed(err) => write!(f, "Encryption failed: {}", err),
+        }
+    }
+}
+
+/// The Argon2id costs a note was encrypted with.
+struct KdfParams {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+const DEFAULT_PARAMS: KdfParams = KdfParams {
+    memory_kib: MEMORY_KIB,
+    iterations: ITERATIONS,
+    parallelism: PARALLELISM,
+};
+
+fn derive_key(passphrase: &str, salt: &[u8], params: &KdfParams) -> Result<Key, CryptoError> {
+    let argon2_params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(|_| CryptoError::Corrupt)?;
+
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| CryptoError::Failed(err.to_string()))?;
+    return Ok(key);
+}
+
+fn read_u32(bytes: &[u8], idx: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[MAGIC.len() + 4 * idx..MAGIC.len() + 4 * (idx + 1)]);
+    return u32::from_le_bytes(value);
+}
+
+/// Whether `bytes` is an encrypted note rather than a plain note file.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    return bytes.starts_with(MAGIC);
+}
+
+fn encrypt_with(
+    plain: &[u8],
+    passphrase: &str,
+    params: &KdfParams,
+) -> Result<Vec<u8>, CryptoError> {
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut salt).map_err(|err| CryptoError::Failed(err.to_string()))?;
+    getrandom::getrandom(&mut nonce).map_err(|err| CryptoError::Failed(err.to_string()))?;
+
+    let mut bytes = MAGIC.to_vec();
+    for value in &[
+        FORMAT_VERSION,
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&nonce);
+
+    let key = derive_key(passphrase, &salt, params)?;
+    let payload = Payload {
+        msg: plain,
+        aad: &bytes,
+    };
+    let encrypted = XChaCha20Poly1305::new(&key)
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| CryptoError::Failed("the note is too large".to_string()))?;
+
+    bytes.extend(encrypted);
+    return Ok(bytes);
+}
+
+/// Encrypts the bytes of a note file with a key derived from `passphrase`.
+pub fn encrypt(plain: &[u8], passphrase: &str) -> Result<Vec<u8>, CryptoError> {
+    return encrypt_with(plain, passphrase, &DEFAULT_PARAMS);
+}
+
+/// Turns an encrypted note back into the bytes of the note file.
+pub fn decrypt(bytes: &[u8], passphrase: &str) -> Result<Vec<u8>, CryptoError> {
+    if bytes.len() < HEADER_LEN || !is_encrypted(bytes) {
+        return Err(CryptoError::Corrupt);
+    }
+    let version = read_u32(bytes, 0);
+    if version != FORMAT_VERSION {
+        return Err(CryptoError::UnsupportedVersion(version));
+    }
+    let params = KdfParams {
+        memory_kib: read_u32(bytes, 1),
+        iterations: read_u32(bytes, 2),
+        parallelism: read_u32(bytes, 3),
+    };
+    if params.memory_kib > MAX_MEMORY_KIB {
+        return Err(CryptoError::Corrupt);
+    }
+
+    let salt_start = MAGIC.len() + 4 * 4;
+    let salt = &bytes[salt_start..salt_start + SALT_LEN];
+    let nonce = &bytes[salt_start + SALT_LEN..HEADER_LEN];
+
+    let key = derive_key(passphrase, salt, &params)?;
+    let payload = Payload {
+        msg: &bytes[HEADER_LEN..],
+        aad: &bytes[..HEADER_LEN],
+    };
+    return XChaCha20Poly1305::new(&key)
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| CryptoError::WrongPassphrase);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the real costs make tests crawl in debug builds
+    const CHEAP_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn round_trip() {
+        let plain = b"PNTS\x05\x00\x00\x00 a note".to_vec();
+        let encrypted = encrypt_with(&plain, "correct horse", &CHEAP_PARAMS)
+            .ok()
+            .unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(&plain));
+        assert!(!encrypted
+            .windows(b"a note".len())
+            .any(|window| window == b"a note"));
+        assert_eq!(decrypt(&encrypted, "correct horse").ok(), Some(plain));
+    }
+
+    #[test]
+    fn every_save_gets_a_new_salt_and_nonce() {
+        let first = encrypt_with(b"note", "passphrase", &CHEAP_PARAMS)
+            .ok()
+            .unwrap();
+        let second = encrypt_with(b"note", "passphrase", &CHEAP_PARAMS)
+            .ok()
+            .unwrap();
+        assert_ne!(first[..HEADER_LEN], second[..HEADER_LEN]);
+    }
+
+    #[test]
+    fn wrong_passphrases_and_tampering_are_refused() {
+        let encrypted = encrypt_with(b"note", "passphrase", &CHEAP_PARAMS)
+            .ok()
+            .unwrap();
+        assert!(matches!(
+            decrypt(&encrypted, "Passphrase"),
+            Err(CryptoError::WrongPassphrase)
+        ));
+
+        // a flipped bit in the text or in the header
+        for idx in &[HEADER_LEN, MAGIC.len() + 4 * 4] {
+            let mut tampered = encrypted.clone();
+            tampered[*idx] ^= 1;
+            assert!(matches!(
+                decrypt(&tampered, "passphrase"),
+                Err(CryptoError::WrongPassphrase)
+            ));
+        }
+    }
+
+    #[test]
+    fn damaged_files() {
+        let encrypted = encrypt_with(b"note", "passphrase", &CHEAP_PARAMS)
+            .ok()
+            .unwrap();
+        assert!(matches!(
+            decrypt(&encrypted[..HEADER_LEN - 1], "passphrase"),
+            Err(CryptoError::Corrupt)
+        ));
+
+        let mut newer = encrypted.clone();
+        newer[MAGIC.len()] = 2;
+        assert!(matches!(
+            decrypt(&newer, "passphrase"),
+            Err(CryptoError::UnsupportedVersion(2))
+        ));
+    }
+}
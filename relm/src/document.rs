@@ -0,0 +1,468 @@
+use crate::note_file::Snapshot;
+use crate::tags::is_view_tag;
+use crate::text_ops::{InsertOpsData, Ops, TagRun};
+use std::collections::BTreeMap;
+
+/**
+ * The contents of a note without any gtk in it: the text and, for every tag,
+ * the stretches of text it covers. Ops are applied here first, the
+ * `gtk::TextBuffer` only shows what the document holds.
+ *
+ * Positions behave like `gtk::TextBuffer` offsets. They count chars, one
+ * past the end (or below zero) means the end, and reversed ranges are turned
+ * around.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Document {
+    chars: Vec<char>,
+    /// sorted, non-overlapping and non-adjacent `(start, end)` ranges per tag
+    tags: BTreeMap<String, Vec<(i32, i32)>>,
+}
+
+/// The line breaks gtk starts a new line after.
+fn is_line_break(c: char) -> bool {
+    return c == '\n' || c == '\r' || c == '\u{2029}';
+}
+
+/// Adds `start..end` to `ranges`, merging it with the ranges it touches.
+fn add_range(ranges: &mut Vec<(i32, i32)>, start: i32, end: i32) {
+    let (mut start, mut end) = (start, end);
+    ranges.retain(|&(s, e)| {
+        if e < start || s > end {
+            return true;
+        }
+        start = start.min(s);
+        end = end.max(e);
+        false
+    });
+
+    let idx = ranges
+        .iter()
+        .position(|&(s, _)| s > start)
+        .unwrap_or(ranges.len());
+    ranges.insert(idx, (start, end));
+}
+
+/// Takes `start..end` out of `ranges`, splitting the range around it if needed.
+fn remove_range(ranges: &mut Vec<(i32, i32)>, start: i32, end: i32) {
+    if start == end {
+        return;
+    }
+    *ranges = ranges
+        .iter()
+        .flat_map(|&(s, e)| vec![(s, e.min(start)), (s.max(end), e)])
+        .filter(|(s, e)| s < e)
+        .collect();
+}
+
+impl Document {
+    pub fn new() -> Self {
+        return Document::default();
+    }
+
+    pub fn from_snapshot(snapshot: &Snapshot) -> Self {
+        let mut document = Document::new();
+        document.restore_snapshot(snapshot);
+        return document;
+    }
+
+    /// Appends the text and tags of `snapshot`, like restoring it into a buffer.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+        let end = self.char_count();
+        self.apply(&Ops::Insert(InsertOpsData::new(
+            end,
+            snapshot.text.to_string(),
+            String::new(),
+        )));
+        for (tag_name, start_offset, end_offset) in &snapshot.tags {
+            self.apply(&Ops::ApplyTag((
+                tag_name.to_string(),
+                end + start_offset,
+                end + end_offset,
+            )));
+        }
+    }
+
+    pub fn char_count(&self) -> i32 {
+        return self.chars.len() as i32;
+    }
+
+    pub fn text(&self) -> String {
+        return self.chars.iter().collect();
+    }
+
+    pub fn slice(&self, start_offset: i32, end_offset: i32) -> String {
+        let (start, end) = self.range(start_offset, end_offset);
+        return self.chars[start as usize..end as usize].iter().collect();
+    }
+
+    /// Where an offset ends up in `gtk::TextBuffer::iter_at_offset`.
+    fn clamp(&self, offset: i32) -> i32 {
+        if offset < 0 || offset > self.char_count() {
+            return self.char_count();
+        }
+        return offset;
+    }
+
+    fn range(&self, start_offset: i32, end_offset: i32) -> (i32, i32) {
+        let (start, end) = (self.clamp(start_offset), self.clamp(end_offset));
+        return (start.min(end), start.max(end));
+    }
+
+    /// The offset the line holding `offset` starts at.
+    pub fn line_start(&self, offset: i32) -> i32 {
+        let offset = self.clamp(offset);
+        return self.chars[..offset as usize]
+            .iter()
+            .rposition(|c| is_line_break(*c))
+            .map_or(0, |idx| idx as i32 + 1);
+    }
+
+    /// The offset of the line break that ends the line holding `offset`.
+    pub fn line_end(&self, offset: i32) -> i32 {
+        let offset = self.clamp(offset);
+        return self.chars[offset as usize..]
+            .iter()
+            .position(|c| is_line_break(*c))
+            .map_or(self.char_count(), |idx| offset + idx as i32);
+    }
+
+    /// The names of the tags on the char at `offset`.
+    pub fn tags_at(&self, offset: i32) -> Vec<String> {
+        return self
+            .tags
+            .iter()
+            .filter(|(_, ranges)| ranges.iter().any(|&(s, e)| s <= offset && offset < e))
+            .map(|(tag_name, _)| tag_name.to_string())
+            .collect();
+    }
+
+    /**
+     * Collects the tags covering `start_offset..end_offset` as runs, one run
+     * per contiguous stretch of the same tag, ordered by tag name.
+     */
+    pub fn tag_runs(&self, start_offset: i32, end_offset: i32) -> Vec<TagRun> {
+        let (start, end) = self.range(start_offset, end_offset);
+
+        let mut runs = vec![];
+        for (tag_name, ranges) in &self.tags {
+            for &(s, e) in ranges {
+                if s.max(start) < e.min(end) {
+                    runs.push((tag_name.to_string(), s.max(start), e.min(end)));
+                }
+            }
+        }
+        return runs;
+    }
+
+    pub fn snapshot(&self, current_tag: &str) -> Snapshot {
+        return Snapshot {
+            text: self.text(),
+            tags: self.tag_runs(0, self.char_count()),
+            current_tag: current_tag.to_string(),
+        };
+    }
+
+    /**
+     * Applies the text and tag side of `op`. Moving the cursor and picking a
+     * color change the editor, not the document, so they leave it as it is.
+     */
+    pub fn apply(&mut self, op: &Ops) {
+        match op {
+            Ops::Insert(insert_ops_data) => {
+                let offset = self.clamp(insert_ops_data.offset);
+                let content = insert_ops_data.content.chars().collect::<Vec<char>>();
+                let inserted_chars = content.len() as i32;
+                self.chars.splice(offset as usize..offset as usize, content);
+
+                // like gtk, the new text takes on the tags of the char before it
+                for ranges in self.tags.values_mut() {
+                    for range in ranges.iter_mut() {
+                        if range.0 < offset && offset <= range.1 {
+                            range.1 += inserted_chars;
+                        } else if range.0 >= offset {
+                            range.0 += inserted_chars;
+                            range.1 += inserted_chars;
+                        }
+                    }
+                }
+
+                if !insert_ops_data.tag.is_empty() {
+                    self.add_tag(
+                        insert_ops_data.tag.as_str(),
+                        offset,
+                        offset + inserted_chars,
+                    );
+                }
+            }
+            Ops::Delete((start_offset, end_offset)) => {
+                let (start, end) = self.range(*start_offset, *end_offset);
+                self.chars.drain(start as usize..end as usize);
+
+                let shift = |offset: i32| {
+                    if offset <= start {
+                        offset
+                    } else if offset >= end {
+                        offset - (end - start)
+                    } else {
+                        start
+                    }
+                };
+                for ranges in self.tags.values_mut() {
+                    let shifted = ranges
+                        .iter()
+                        .map(|&(s, e)| (shift(s), shift(e)))
+                        .filter(|(s, e)| s < e)
+                        .collect::<Vec<(i32, i32)>>();
+                    // ranges on both sides of the deleted text may meet now
+                    ranges.clear();
+                    for (s, e) in shifted {
+                        add_range(ranges, s, e);
+                    }
+                }
+                self.tags.retain(|_, ranges| !ranges.is_empty());
+            }
+            Ops::MoveCursor(_) => {}
+            Ops::SelectColorTag(_) => {}
+            Ops::ApplyTag((tag_name, start_offset, end_offset)) => {
+                let (start, end) = self.range(*start_offset, *end_offset);
+                self.add_tag(tag_name.as_str(), start, end);
+            }
+            Ops::RemoveTag((tag_name, start_offset, end_offset)) => {
+                let (start, end) = self.range(*start_offset, *end_offset);
+                if let Some(ranges) = self.tags.get_mut(tag_name) {
+                    remove_range(ranges, start, end);
+                    if ranges.is_empty() {
+                        self.tags.remove(tag_name);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_tag(&mut self, tag_name: &str, start: i32, end: i32) {
+        // the find bar's and the peer's highlights belong to the view only
+        if start == end || is_view_tag(tag_name) {
+            return;
+        }
+        add_range(
+            self.tags.entry(tag_name.to_string()).or_default(),
+            start,
+            end,
+        );
+    }
+
+    /**
+     * Computes the ops that revert the text and tag side of `op`, so it has
+     * to be called before `op` is applied.
+     */
+    pub fn inverse_ops(&self, op: &Ops) -> Vec<Ops> {
+        match op {
+            Ops::Insert(insert_ops_data) => {
+                let offset = self.clamp(insert_ops_data.offset);
+                vec![Ops::Delete((
+                    offset,
+                    offset + insert_ops_data.content.chars().count() as i32,
+                ))]
+            }
+            Ops::Delete((start_offset, end_offset)) => {
+                let (start, end) = self.range(*start_offset, *end_offset);
+                // the reinserted text would pick up the tags before it
+                let inherited = self
+                    .tag_runs(start - 1, start)
+                    .into_iter()
+                    .filter(|_| start > 0 && start < end)
+                    .map(|(tag_name, _, _)| Ops::RemoveTag((tag_name, start, end)));
+
+                let mut ops = vec![Ops::Insert(InsertOpsData::new(
+                    start,
+                    self.slice(start, end),
+                    String::new(),
+                ))];
+                ops.extend(inherited);
+                ops.extend(self.tag_runs(start, end).into_iter().map(Ops::ApplyTag));
+                ops
+            }
+            Ops::MoveCursor(_) => vec![],
+            Ops::SelectColorTag(_) => vec![],
+            Ops::ApplyTag((tag_name, start_offset, end_offset)) => {
+                // only strip the tag where it wasn't present already
+                let (start, end) = self.range(*start_offset, *end_offset);
+                let mut ops = vec![];
+                let mut untagged_start = start;
+                for (name, s, e) in self.tag_runs(start, end) {
+                    if name == *tag_name {
+                        if s > untagged_start {
+                            ops.push(Ops::RemoveTag((name.to_string(), untagged_start, s)));
+                        }
+                        untagged_start = e;
+                    }
+                }
+                if end > untagged_start {
+                    ops.push(Ops::RemoveTag((tag_name.to_string(), untagged_start, end)));
+                }
+                ops
+            }
+            Ops::RemoveTag((tag_name, start_offset, end_offset)) => {
+                let (start, end) = self.range(*start_offset, *end_offset);
+                self.tag_runs(start, end)
+                    .into_iter()
+                    .filter(|(name, _, _)| name == tag_name)
+                    .map(Ops::ApplyTag)
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    pub fn insert(offset: i32, content: &str, tag: &str) -> Ops {
+        Ops::Insert(InsertOpsData::new(
+            offset,
+            content.to_string(),
+            tag.to_string(),
+        ))
+    }
+
+    /// Any op on a note of up to `max_len` chars, offsets may point past it.
+    pub fn any_op(max_len: i32) -> impl Strategy<Value = Ops> {
+        let tag_name =
+            prop::sample::select(vec!["color_tag_1", "color_tag_2", "bold", "heading_1"]);
+        let offset = 0..max_len + 3;
+        prop_oneof![
+            (
+                offset.clone(),
+                "[ab\\né]{1,4}",
+                tag_name.clone(),
+                any::<bool>()
+            )
+                .prop_map(|(offset, content, tag_name, tagged)| {
+                    insert(offset, content.as_str(), if tagged { tag_name } else { "" })
+                }),
+            (offset.clone(), offset.clone()).prop_map(Ops::Delete),
+            offset.clone().prop_map(Ops::MoveCursor),
+            (tag_name.clone(), offset.clone(), offset.clone())
+                .prop_map(|(tag_name, s, e)| Ops::ApplyTag((tag_name.to_string(), s, e))),
+            (tag_name, offset.clone(), offset).prop_map(|(tag_name, s, e)| Ops::RemoveTag((
+                tag_name.to_string(),
+                s,
+                e
+            ))),
+        ]
+    }
+
+    fn document(ops: &[Ops]) -> Document {
+        let mut document = Document::new();
+        for op in ops {
+            document.apply(op);
+        }
+        return document;
+    }
+
+    #[test]
+    fn inserted_text_takes_on_the_tags_before_it() {
+        let mut document = document(&[
+            insert(0, "ab", "bold"),
+            insert(2, "cd", ""),
+            insert(0, "<", ""),
+        ]);
+        assert_eq!(document.text(), "<abcd");
+        assert_eq!(document.tag_runs(0, 5), vec![("bold".to_string(), 1, 5)]);
+
+        document.apply(&Ops::RemoveTag(("bold".to_string(), 2, 3)));
+        document.apply(&insert(2, "x", ""));
+        assert_eq!(
+            document.tag_runs(0, 6),
+            vec![("bold".to_string(), 1, 3), ("bold".to_string(), 4, 6)]
+        );
+    }
+
+    #[test]
+    fn deletes_merge_the_tags_around_them() {
+        let mut document = document(&[
+            insert(0, "abcde", ""),
+            Ops::ApplyTag(("bold".to_string(), 0, 2)),
+            Ops::ApplyTag(("bold".to_string(), 3, 5)),
+            Ops::ApplyTag(("italic".to_string(), 2, 3)),
+        ]);
+        document.apply(&Ops::Delete((3, 2)));
+        assert_eq!(document.text(), "abde");
+        assert_eq!(document.tag_runs(0, 4), vec![("bold".to_string(), 0, 4)]);
+        assert_eq!(document.tags_at(3), vec!["bold".to_string()]);
+    }
+
+    #[test]
+    fn offsets_past_the_end_mean_the_end() {
+        let mut document = document(&[insert(0, "ab", ""), insert(10, "c", "")]);
+        document.apply(&Ops::ApplyTag(("bold".to_string(), -1, 1)));
+        assert_eq!(document.text(), "abc");
+        assert_eq!(document.tag_runs(0, 3), vec![("bold".to_string(), 1, 3)]);
+        assert_eq!(document.slice(2, 99), "c");
+    }
+
+    #[test]
+    fn empty_ranges_change_nothing() {
+        let mut document = document(&[insert(0, "abc", "bold")]);
+        let before = document.clone();
+        document.apply(&Ops::RemoveTag(("bold".to_string(), 1, 1)));
+        document.apply(&Ops::ApplyTag(("italic".to_string(), 2, 2)));
+        document.apply(&Ops::Delete((3, 3)));
+        assert_eq!(document, before);
+    }
+
+    #[test]
+    fn lines() {
+        let document = document(&[insert(0, "one\ntwo\r\nthree", "")]);
+        assert_eq!(document.line_start(5), 4);
+        assert_eq!(document.line_end(5), 7);
+        assert_eq!(document.line_start(12), 9);
+        assert_eq!(document.line_end(12), 14);
+        assert_eq!(document.line_start(0), 0);
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let document = document(&[
+            insert(0, "hello world", "color_tag_1"),
+            Ops::ApplyTag(("bold".to_string(), 0, 5)),
+        ]);
+        let restored = Document::from_snapshot(&document.snapshot("color_tag_1"));
+        assert_eq!(restored, document);
+    }
+
+    proptest! {
+        #[test]
+        fn inverse_ops_revert_any_op(
+            ops in prop::collection::vec(any_op(12), 0..12),
+            op in any_op(12),
+        ) {
+            let mut document = document(&ops);
+            let before = document.clone();
+
+            let inverse = document.inverse_ops(&op);
+            document.apply(&op);
+            for inverse_op in &inverse {
+                document.apply(inverse_op);
+            }
+            prop_assert_eq!(document, before);
+        }
+
+        #[test]
+        fn tags_stay_within_the_text(ops in prop::collection::vec(any_op(12), 0..24)) {
+            let document = document(&ops);
+            for ranges in document.tags.values() {
+                prop_assert!(!ranges.is_empty());
+                for window in ranges.windows(2) {
+                    prop_assert!(window[0].1 < window[1].0);
+                }
+                for &(s, e) in ranges {
+                    prop_assert!(0 <= s && s < e && e <= document.char_count());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,798 @@
+use crate::tags::{
+    BLOCK_MARKER_TAG, BLOCK_TAGS, BOLD_TAG, CODE_TAG, ITALIC_TAG, LINK_TAG, LINK_URL_TAG,
+    LIST_MARKER_TAG, RULE_TAG, STRIKETHROUGH_TAG,
+};
+use crate::text_ops::TagRun;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A stretch of text and the names of the tags covering it.
+pub type Span = (String, Vec<String>);
+
+pub struct MarkdownOptions {
+    /**
+     * color tags become `<span style="color: …">` when set, otherwise the
+     * colors are dropped and only the text and its emphasis remain.
+     * Highlighted text is a `<mark>` either way, with its background color
+     * only when this is set.
+     */
+    pub keep_colors: bool,
+}
+
+/**
+ * Splits `text` wherever the set of tags covering it changes. Every span
+ * comes with the names of its tags, sorted so equal sets compare equal.
+ */
+pub fn tagged_spans(text: &str, runs: &[TagRun]) -> Vec<Span> {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut char_tags: Vec<Vec<String>> = vec![vec![]; chars.len()];
+
+    for (tag_name, start_offset, end_offset) in runs {
+        let start = (*start_offset).max(0) as usize;
+        let end = ((*end_offset).max(0) as usize).min(chars.len());
+        for tags in char_tags.iter_mut().take(end).skip(start) {
+            if !tags.contains(tag_name) {
+                tags.push(tag_name.to_string());
+            }
+        }
+    }
+
+    let mut spans: Vec<Span> = vec![];
+    for (c, mut tags) in chars.into_iter().zip(char_tags.into_iter()) {
+        tags.sort();
+        match spans.last_mut() {
+            Some((span_text, span_tags)) if *span_tags == tags => span_text.push(c),
+            _ => spans.push((c.to_string(), tags)),
+        }
+    }
+
+    return spans;
+}
+
+/// The CSS colors of the color and highlight tags, by tag name.
+struct TagColors<'a> {
+    foreground: &'a HashMap<String, String>,
+    background: &'a HashMap<String, String>,
+}
+
+/**
+ * Renders a note as CommonMark, the inline formatting tags go back to the
+ * Markdown they were typed as. `colors` maps color tag names to the CSS
+ * colors used for them and `highlights` does the same for the background
+ * colors of highlight tags.
+ */
+pub fn to_markdown(
+    text: &str,
+    runs: &[TagRun],
+    colors: &HashMap<String, String>,
+    highlights: &HashMap<String, String>,
+    options: &MarkdownOptions,
+) -> String {
+    let colors = TagColors {
+        foreground: colors,
+        background: highlights,
+    };
+
+    let mut lines: Vec<Vec<Span>> = vec![vec![]];
+    for (span_text, tags) in tagged_spans(text, runs) {
+        for (idx, part) in span_text.split('\n').enumerate() {
+            if idx > 0 {
+                lines.push(vec![]);
+            }
+            if !part.is_empty() {
+                lines
+                    .last_mut()
+                    .expect("there is always a line to add to")
+                    .push((part.to_string(), tags.to_vec()));
+            }
+        }
+    }
+
+    let rendered = lines
+        .iter()
+        .map(|line| markdown_line(line, &colors, options))
+        .collect::<Vec<String>>();
+    // headings, list items, quotes and rules are lines of their own already
+    let is_block = lines
+        .iter()
+        .map(|line| {
+            line.first().map_or(false, |(_, tags)| {
+                tags.iter()
+                    .any(|tag| tag == RULE_TAG || BLOCK_TAGS.contains(&tag.as_str()))
+            })
+        })
+        .collect::<Vec<bool>>();
+
+    let mut markdown = String::new();
+    for (idx, line) in rendered.iter().enumerate() {
+        markdown.push_str(line);
+        if idx + 1 < rendered.len() {
+            // keep the line breaks of the note instead of letting
+            // consecutive lines flow into one paragraph
+            let next_is_empty = rendered[idx + 1].is_empty();
+            if !line.is_empty() && !next_is_empty && !is_block[idx] && !is_block[idx + 1] {
+                markdown.push('\\');
+            }
+            markdown.push('\n');
+        }
+    }
+    markdown.push('\n');
+
+    return markdown;
+}
+
+/// Formatting tags from the outermost to the innermost, with the markers that
+/// open and close them.
+const EMPHASIS: [(&str, &str, &str); 5] = [
+    (LINK_TAG, "[", "]"),
+    (STRIKETHROUGH_TAG, "~~", "~~"),
+    (BOLD_TAG, "**", "**"),
+    (ITALIC_TAG, "*", "*"),
+    (CODE_TAG, "`", "`"),
+];
+
+/// Text under these tags is Markdown syntax already, or code, and isn't escaped.
+const LITERAL_TAGS: [&str; 5] = [
+    CODE_TAG,
+    LINK_URL_TAG,
+    BLOCK_MARKER_TAG,
+    LIST_MARKER_TAG,
+    RULE_TAG,
+];
+
+fn markdown_line(spans: &[Span], colors: &TagColors, options: &MarkdownOptions) -> String {
+    let mut line = String::new();
+    push_emphasized(&mut line, spans, &EMPHASIS, colors, options);
+    return line;
+}
+
+/**
+ * Wraps every stretch of spans carrying the first emphasis tag in its markers
+ * and handles the remaining tags inside of it, so `**big *world***` nests
+ * instead of producing ambiguous runs of asterisks.
+ */
+fn push_emphasized(
+    out: &mut String,
+    spans: &[Span],
+    emphasis: &[(&str, &str, &str)],
+    colors: &TagColors,
+    options: &MarkdownOptions,
+) {
+    let (tag_name, open_marker, close_marker) = match emphasis.first() {
+        Some(layer) => *layer,
+        None => {
+            for (span_text, tags) in spans {
+                let is_literal = tags.iter().any(|tag| LITERAL_TAGS.contains(&tag.as_str()));
+                let escaped = if is_literal {
+                    span_text.to_string()
+                } else {
+                    escape_markdown(span_text, out.is_empty())
+                };
+                out.push_str(colored(escaped, tags, colors, options).as_str());
+            }
+            return;
+        }
+    };
+
+    let mut idx = 0;
+    while idx < spans.len() {
+        let has_tag = spans[idx].1.iter().any(|tag| tag == tag_name);
+        let group_start = idx;
+        while idx < spans.len() && spans[idx].1.iter().any(|tag| tag == tag_name) == has_tag {
+            idx += 1;
+        }
+        let group = &spans[group_start..idx];
+
+        if !has_tag {
+            push_emphasized(out, group, &emphasis[1..], colors, options);
+            continue;
+        }
+
+        // emphasis markers must hug the text, so surrounding whitespace goes
+        // outside of them
+        let (leading, inner, trailing) = split_outer_whitespace(group);
+        out.push_str(leading.as_str());
+        if !inner.is_empty() {
+            out.push_str(open_marker);
+            push_emphasized(out, &inner, &emphasis[1..], colors, options);
+            out.push_str(close_marker);
+        }
+        out.push_str(trailing.as_str());
+    }
+}
+
+fn split_outer_whitespace(spans: &[Span]) -> (String, Vec<Span>, String) {
+    let text = spans
+        .iter()
+        .map(|(span_text, _)| span_text.as_str())
+        .collect::<String>();
+    let inner_start = text.len() - text.trim_start().len();
+    let inner_end = inner_start.max(text.trim_end().len());
+
+    let mut inner = vec![];
+    let mut span_start = 0;
+    for (span_text, tags) in spans {
+        let span_end = span_start + span_text.len();
+        let start = inner_start.max(span_start).min(span_end) - span_start;
+        let end = inner_end.max(span_start).min(span_end) - span_start;
+        if start < end {
+            inner.push((span_text[start..end].to_string(), tags.to_vec()));
+        }
+        span_start = span_end;
+    }
+
+    return (
+        text[..inner_start].to_string(),
+        inner,
+        text[inner_end..].to_string(),
+    );
+}
+
+fn colored(text: String, tags: &[String], colors: &TagColors, options: &MarkdownOptions) -> String {
+    if text.trim().is_empty() {
+        return text;
+    }
+
+    let text = match tags.iter().find_map(|tag| colors.foreground.get(tag)) {
+        Some(color) if options.keep_colors => {
+            format!("<span style=\"color: {}\">{}</span>", color, text)
+        }
+        _ => text,
+    };
+
+    match tags.iter().find_map(|tag| colors.background.get(tag)) {
+        Some(color) if options.keep_colors => format!(
+            "<mark style=\"background-color: {}\">{}</mark>",
+            color, text
+        ),
+        Some(_) => format!("<mark>{}</mark>", text),
+        None => text,
+    }
+}
+
+/// Escapes everything that would otherwise be read as Markdown syntax or an
+/// HTML entity.
+fn escape_markdown(text: &str, at_line_start: bool) -> String {
+    let mut escaped = String::new();
+
+    for (idx, c) in text.chars().enumerate() {
+        let starts_block = at_line_start && idx == 0 && (c == '-' || c == '+' || c == '=');
+        if starts_block || "\\`*_[]<>&#~|".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    // "1. " at the start of a line would turn into a list item
+    if at_line_start {
+        let digits = escaped.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 && escaped[digits..].starts_with(|c| c == '.' || c == ')') {
+            escaped.insert(digits, '\\');
+        }
+    }
+
+    return escaped;
+}
+
+/// The formats a note can be exported to, picked by the file extension.
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    PlainText,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("html") | Some("htm") => ExportFormat::Html,
+            Some("txt") => ExportFormat::PlainText,
+            _ => ExportFormat::Markdown,
+        }
+    }
+}
+
+/// The text the way the note shows it, without the hidden link urls and markers.
+pub fn to_plain_text(text: &str, runs: &[TagRun]) -> String {
+    return tagged_spans(text, runs)
+        .into_iter()
+        .filter(|(_, tags)| {
+            !tags
+                .iter()
+                .any(|tag_name| tag_name == LINK_URL_TAG || tag_name == BLOCK_MARKER_TAG)
+        })
+        .map(|(span_text, _)| span_text)
+        .collect();
+}
+
+/// The look of a text tag, only the properties set on the tag are `Some`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagStyle {
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub font_family: Option<String>,
+    pub size_points: Option<f64>,
+    pub italic: bool,
+    pub weight: Option<i32>,
+    pub strikethrough: bool,
+    pub underline: bool,
+    pub invisible: bool,
+    /// font size relative to the surrounding text
+    pub scale: Option<f64>,
+    pub left_margin: Option<i32>,
+}
+
+impl TagStyle {
+    fn css_declarations(&self) -> String {
+        let mut declarations = vec![];
+        // the colors and fonts come from the editable palette, anything
+        // that isn't plainly a color is left out
+        if let Some(color) = self.foreground.as_ref().filter(|color| is_css_color(color)) {
+            declarations.push(format!("color: {};", color));
+        }
+        if let Some(color) = self.background.as_ref().filter(|color| is_css_color(color)) {
+            declarations.push(format!("background-color: {};", color));
+        }
+        if let Some(family) = &self.font_family {
+            declarations.push(format!(
+                "font-family: \"{}\", monospace;",
+                escape_css_string(family)
+            ));
+        }
+        if let Some(size) = self.size_points {
+            declarations.push(format!("font-size: {}pt;", size));
+        }
+        if self.italic {
+            declarations.push("font-style: italic;".to_string());
+        }
+        if let Some(weight) = self.weight {
+            declarations.push(format!("font-weight: {};", weight));
+        }
+        match (self.underline, self.strikethrough) {
+            (true, true) => {
+                declarations.push("text-decoration: underline line-through;".to_string())
+            }
+            (true, false) => declarations.push("text-decoration: underline;".to_string()),
+            (false, true) => declarations.push("text-decoration: line-through;".to_string()),
+            (false, false) => {}
+        }
+        if let Some(scale) = self.scale {
+            declarations.push(format!("font-size: {}em;", scale));
+        }
+        if let Some(margin) = self.left_margin {
+            declarations.push(format!("padding-left: {}px;", margin));
+        }
+        if self.invisible {
+            declarations.push("display: none;".to_string());
+        }
+        return declarations.join(" ");
+    }
+}
+
+/**
+ * Renders a note as a self-contained HTML page. `styles` holds the tags in
+ * priority order, later rules win in CSS just like higher priority tags do in
+ * the text view.
+ */
+pub fn to_html(title: &str, text: &str, runs: &[TagRun], styles: &[(String, TagStyle)]) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(format!("<title>{}</title>\n", escape_html(title)).as_str());
+    html.push_str("<style>\n");
+    html.push_str(
+        ".note { white-space: pre-wrap; overflow-wrap: break-word; margin: 15px 20px; }\n",
+    );
+    for (tag_name, style) in styles {
+        html.push_str(
+            format!(
+                ".note .{} {{ {} }}\n",
+                css_class(tag_name),
+                style.css_declarations()
+            )
+            .as_str(),
+        );
+    }
+    html.push_str("</style>\n</head>\n<body>\n<div class=\"note\">");
+
+    for (span_text, tags) in tagged_spans(text, runs) {
+        let escaped = escape_html(span_text.as_str());
+        if tags.is_empty() {
+            html.push_str(escaped.as_str());
+        } else {
+            let classes = tags
+                .iter()
+                .map(|tag| css_class(tag))
+                .collect::<Vec<String>>()
+                .join(" ");
+            html.push_str(format!("<span class=\"{}\">{}</span>", classes, escaped).as_str());
+        }
+    }
+
+    html.push_str("</div>\n</body>\n</html>\n");
+    return html;
+}
+
+/// Colors like `#F5E050`, `rgb(245, 224, 80)` or `gold`.
+fn is_css_color(color: &str) -> bool {
+    return !color.trim().is_empty()
+        && color
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "#(),.% ".contains(c));
+}
+
+/// Escapes everything but letters and digits, which also keeps `</style>`
+/// from ending the style sheet early.
+fn escape_css_string(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || c == ' ' {
+            escaped.push(c);
+        } else {
+            escaped.push_str(format!("\\{:x} ", c as u32).as_str());
+        }
+    }
+    return escaped;
+}
+
+/**
+ * Tag names may contain anything, class names only a safe subset. Every other
+ * char becomes its code between dashes, so different tags never share a
+ * class, and the prefix keeps names starting with a digit valid selectors.
+ */
+fn css_class(tag_name: &str) -> String {
+    let mut class = String::from("tag-");
+    for c in tag_name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            class.push(c);
+        } else {
+            class.push_str(format!("-{:x}-", c as u32).as_str());
+        }
+    }
+    return class;
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::HEADING_1_TAG;
+
+    fn run(tag_name: &str, start_offset: i32, end_offset: i32) -> TagRun {
+        (tag_name.to_string(), start_offset, end_offset)
+    }
+
+    fn markdown(text: &str, runs: &[TagRun], keep_colors: bool) -> String {
+        let colors = vec![("red".to_string(), "#ff0000".to_string())]
+            .into_iter()
+            .collect::<HashMap<String, String>>();
+        let highlights = vec![("yellow".to_string(), "#ffff00".to_string())]
+            .into_iter()
+            .collect::<HashMap<String, String>>();
+        return to_markdown(
+            text,
+            runs,
+            &colors,
+            &highlights,
+            &MarkdownOptions { keep_colors },
+        );
+    }
+
+    #[test]
+    fn markdown_escapes_plain_text() {
+        let cases = vec![
+            ("a < b > c", "a \\< b \\> c\n"),
+            ("AT&amp;T", "AT\\&amp;T\n"),
+            ("2 * 3 * 4", "2 \\* 3 \\* 4\n"),
+            ("call `ls`", "call \\`ls\\`\n"),
+            ("snake_case [x]", "snake\\_case \\[x\\]\n"),
+            ("# not a heading", "\\# not a heading\n"),
+            ("- not a list", "\\- not a list\n"),
+            ("1. not a list", "1\\. not a list\n"),
+            ("a - b 1. c", "a - b 1. c\n"),
+            // consecutive lines stay apart instead of forming a paragraph
+            ("one\ntwo\n\nthree", "one\\\ntwo\n\nthree\n"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(markdown(text, &[], false), expected, "exporting {:?}", text);
+        }
+    }
+
+    #[test]
+    fn markdown_formatting() {
+        let cases = vec![
+            ("bold", vec![run(BOLD_TAG, 0, 4)], "**bold**\n"),
+            // markers hug the text
+            ("a big word", vec![run(ITALIC_TAG, 1, 6)], "a *big* word\n"),
+            (
+                "big world",
+                vec![run(BOLD_TAG, 0, 9), run(ITALIC_TAG, 4, 9)],
+                "**big *world***\n",
+            ),
+            // overlapping runs nest where they meet
+            (
+                "one two three",
+                vec![run(BOLD_TAG, 0, 7), run(ITALIC_TAG, 4, 13)],
+                "**one *two*** *three*\n",
+            ),
+            (
+                "one two three",
+                vec![run(ITALIC_TAG, 0, 7), run(BOLD_TAG, 4, 13)],
+                "*one* ***two* three**\n",
+            ),
+            // code is taken as it is
+            ("a*b", vec![run(CODE_TAG, 0, 3)], "`a*b`\n"),
+            ("gone", vec![run(STRIKETHROUGH_TAG, 0, 4)], "~~gone~~\n"),
+            (
+                "# Title\ntext",
+                vec![run(BLOCK_MARKER_TAG, 0, 2), run(HEADING_1_TAG, 0, 7)],
+                "# Title\ntext\n",
+            ),
+            (
+                "site(https://a.b)",
+                vec![run(LINK_TAG, 0, 4), run(LINK_URL_TAG, 4, 17)],
+                "[site](https://a.b)\n",
+            ),
+        ];
+
+        for (text, runs, expected) in cases {
+            assert_eq!(
+                markdown(text, &runs, false),
+                expected,
+                "exporting {:?} with {:?}",
+                text,
+                runs
+            );
+        }
+    }
+
+    #[test]
+    fn markdown_colors() {
+        let cases = vec![
+            (vec![run("red", 0, 4)], true, "<span style=\"color: #ff0000\">warm</span>\n"),
+            (vec![run("red", 0, 4)], false, "warm\n"),
+            (
+                vec![run("yellow", 0, 4)],
+                true,
+                "<mark style=\"background-color: #ffff00\">warm</mark>\n",
+            ),
+            (vec![run("yellow", 0, 4)], false, "<mark>warm</mark>\n"),
+            (
+                vec![run("red", 0, 4), run("yellow", 0, 4)],
+                true,
+                "<mark style=\"background-color: #ffff00\"><span style=\"color: #ff0000\">warm</span></mark>\n",
+            ),
+            (
+                vec![run("red", 0, 4), run(BOLD_TAG, 0, 4)],
+                true,
+                "**<span style=\"color: #ff0000\">warm</span>**\n",
+            ),
+        ];
+
+        for (runs, keep_colors, expected) in cases {
+            assert_eq!(
+                markdown("warm", &runs, keep_colors),
+                expected,
+                "exporting {:?}, keeping colors: {}",
+                runs,
+                keep_colors
+            );
+        }
+    }
+
+    #[test]
+    fn plain_text_leaves_out_hidden_text() {
+        let cases = vec![
+            ("plain *text*", vec![], "plain *text*"),
+            (
+                "# Title",
+                vec![run(BLOCK_MARKER_TAG, 0, 2), run(HEADING_1_TAG, 0, 7)],
+                "Title",
+            ),
+            (
+                "see site(https://a.b)!",
+                vec![run(LINK_TAG, 4, 8), run(LINK_URL_TAG, 8, 21)],
+                "see site!",
+            ),
+            (
+                "- item",
+                vec![run(LIST_MARKER_TAG, 0, 2), run(BOLD_TAG, 2, 6)],
+                "- item",
+            ),
+        ];
+
+        for (text, runs, expected) in cases {
+            assert_eq!(to_plain_text(text, &runs), expected, "exporting {:?}", text);
+        }
+    }
+
+    fn style() -> TagStyle {
+        TagStyle {
+            foreground: None,
+            background: None,
+            font_family: None,
+            size_points: None,
+            italic: false,
+            weight: None,
+            strikethrough: false,
+            underline: false,
+            invisible: false,
+            scale: None,
+            left_margin: None,
+        }
+    }
+
+    #[test]
+    fn html_escapes_the_name_and_text() {
+        let html = to_html(
+            "<b>Tom & \"Jerry\"</b>",
+            "1 < 2 & 3 > \"2\"\n<script>",
+            &[run(BOLD_TAG, 0, 1)],
+            &[],
+        );
+
+        assert!(html.contains("<title>&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;</title>"));
+        assert!(html.contains(
+            "<div class=\"note\"><span class=\"tag-bold\">1</span> &lt; 2 &amp; 3 &gt; &quot;2&quot;\n&lt;script&gt;</div>"
+        ));
+        assert!(!html.contains("<b>"));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn html_styles_tags() {
+        let cases = vec![
+            (
+                TagStyle {
+                    weight: Some(700),
+                    ..style()
+                },
+                "font-weight: 700;",
+            ),
+            (
+                TagStyle {
+                    italic: true,
+                    ..style()
+                },
+                "font-style: italic;",
+            ),
+            (
+                TagStyle {
+                    foreground: Some("#ff0000".to_string()),
+                    background: Some("#ffff00".to_string()),
+                    ..style()
+                },
+                "color: #ff0000; background-color: #ffff00;",
+            ),
+            (
+                TagStyle {
+                    font_family: Some("Monospace".to_string()),
+                    size_points: Some(10.5),
+                    ..style()
+                },
+                "font-family: \"Monospace\", monospace; font-size: 10.5pt;",
+            ),
+            (
+                TagStyle {
+                    strikethrough: true,
+                    ..style()
+                },
+                "text-decoration: line-through;",
+            ),
+            (
+                TagStyle {
+                    underline: true,
+                    strikethrough: true,
+                    ..style()
+                },
+                "text-decoration: underline line-through;",
+            ),
+            (
+                TagStyle {
+                    scale: Some(1.5),
+                    left_margin: Some(20),
+                    ..style()
+                },
+                "font-size: 1.5em; padding-left: 20px;",
+            ),
+            (
+                TagStyle {
+                    invisible: true,
+                    ..style()
+                },
+                "display: none;",
+            ),
+            (style(), ""),
+        ];
+
+        for (tag_style, declarations) in cases {
+            let html = to_html("", "", &[], &[("tag".to_string(), tag_style.clone())]);
+            let rule = format!(".note .tag-tag {{ {} }}\n", declarations);
+            assert!(html.contains(rule.as_str()), "styling {:?}", tag_style);
+        }
+    }
+
+    #[test]
+    fn html_classes_are_safe_tag_names() {
+        let html = to_html(
+            "",
+            "ab",
+            &[run("my \"tag\"", 0, 1), run(BOLD_TAG, 0, 2)],
+            &[
+                (BOLD_TAG.to_string(), style()),
+                ("my \"tag\"".to_string(), style()),
+            ],
+        );
+
+        // later tags take priority, so their rules come later too
+        let bold_rule = html
+            .find(".note .tag-bold {")
+            .expect("bold should be styled");
+        let tag_rule = html
+            .find(".note .tag-my-20--22-tag-22- {")
+            .expect("the tag should be styled");
+        assert!(bold_rule < tag_rule);
+        assert!(
+            html.contains("<span class=\"tag-bold tag-my-20--22-tag-22-\">a</span><span class=\"tag-bold\">b</span>")
+        );
+    }
+
+    #[test]
+    fn html_keeps_palette_values_inside_the_style_sheet() {
+        let tag_style = TagStyle {
+            foreground: Some("red; } body { display: none".to_string()),
+            background: Some("rgb(245, 224, 80)".to_string()),
+            font_family: Some("Evil\" } </style><script>".to_string()),
+            ..style()
+        };
+        let html = to_html("", "", &[], &[("tag".to_string(), tag_style)]);
+
+        assert!(html.contains(
+            ".note .tag-tag { background-color: rgb(245, 224, 80); \
+             font-family: \"Evil\\22  \\7d  \\3c \\2f style\\3e \\3c script\\3e \", monospace; }"
+        ));
+        assert!(!html.contains("display: none"));
+        assert_eq!(html.matches("</style>").count(), 1);
+    }
+
+    #[test]
+    fn tags_get_classes_of_their_own() {
+        let names = vec![
+            "color_tag_1",
+            "color-tag-1",
+            "color tag 1",
+            "color.tag.1",
+            "1",
+            "-31-",
+        ];
+        let classes = names
+            .iter()
+            .map(|tag_name| css_class(tag_name))
+            .collect::<Vec<String>>();
+
+        assert_eq!(classes[0], "tag-color_tag_1");
+        for (idx, class) in classes.iter().enumerate() {
+            assert!(
+                !classes[idx + 1..].contains(class),
+                "{:?} is taken twice",
+                class
+            );
+        }
+    }
+}
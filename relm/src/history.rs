@@ -0,0 +1,530 @@
+use crate::text_ops::{InsertOpsData, Ops, TagRun};
+
+/// One entry of the undo (or redo) stack.
+///
+/// Keystrokes are kept as `Typed`/`Deleted` so that consecutive ones can be
+/// merged into a single step, everything else stores the inverse ops directly.
+pub enum UndoStep {
+    Typed {
+        offset: i32,
+        content: String,
+    },
+    Deleted {
+        offset: i32,
+        content: String,
+        tags: Vec<TagRun>,
+    },
+    Inverse(Vec<Ops>),
+}
+
+impl UndoStep {
+    /// The ops that revert this step when applied in order.
+    pub fn inverse_ops(&self) -> Vec<Ops> {
+        match self {
+            UndoStep::Typed { offset, content } => {
+                vec![Ops::Delete((*offset, *offset + char_len(content)))]
+            }
+            UndoStep::Deleted {
+                offset,
+                content,
+                tags,
+            } => {
+                let mut ops = vec![Ops::Insert(InsertOpsData::new(
+                    *offset,
+                    content.to_string(),
+                    String::new(),
+                ))];
+                for (tag_name, start_offset, end_offset) in tags {
+                    ops.push(Ops::ApplyTag((
+                        tag_name.to_string(),
+                        *start_offset,
+                        *end_offset,
+                    )));
+                }
+                ops
+            }
+            UndoStep::Inverse(ops) => ops.to_vec(),
+        }
+    }
+
+    /// Moves the step past `op`, an edit made by someone else.
+    fn transform(&mut self, op: &Ops) {
+        match self {
+            UndoStep::Typed { offset, content } => {
+                let start_offset = *offset;
+                let end_offset = start_offset + char_len(content);
+                match op {
+                    // the typed text got split in two, both halves go on undo
+                    Ops::Insert(insert_ops_data)
+                        if start_offset < insert_ops_data.offset
+                            && insert_ops_data.offset < end_offset =>
+                    {
+                        let inserted = char_len(&insert_ops_data.content);
+                        *self = UndoStep::Inverse(vec![
+                            Ops::Delete((insert_ops_data.offset + inserted, end_offset + inserted)),
+                            Ops::Delete((start_offset, insert_ops_data.offset)),
+                        ]);
+                    }
+                    // some of the typed text is gone already
+                    Ops::Delete((delete_start, delete_end))
+                        if *delete_start < end_offset && start_offset < *delete_end =>
+                    {
+                        let (start_offset, end_offset) = shift_range(start_offset, end_offset, op);
+                        *self = UndoStep::Inverse(vec![Ops::Delete((start_offset, end_offset))]);
+                    }
+                    _ => *offset = shift_start(start_offset, op),
+                }
+            }
+            UndoStep::Deleted { offset, tags, .. } => {
+                let shift = shift_start(*offset, op) - *offset;
+                *offset += shift;
+                for (_, start_offset, end_offset) in tags.iter_mut() {
+                    *start_offset += shift;
+                    *end_offset += shift;
+                }
+            }
+            UndoStep::Inverse(ops) => {
+                for step_op in ops.iter_mut() {
+                    match step_op {
+                        Ops::Insert(insert_ops_data) => {
+                            insert_ops_data.offset = shift_start(insert_ops_data.offset, op);
+                        }
+                        Ops::Delete((start_offset, end_offset))
+                        | Ops::ApplyTag((_, start_offset, end_offset))
+                        | Ops::RemoveTag((_, start_offset, end_offset)) => {
+                            let range = shift_range(*start_offset, *end_offset, op);
+                            *start_offset = range.0;
+                            *end_offset = range.1;
+                        }
+                        Ops::MoveCursor(offset) => *offset = shift_start(*offset, op),
+                        Ops::SelectColorTag(_) => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Where `offset` ends up after `op`, text inserted right at it goes before it.
+fn shift_start(offset: i32, op: &Ops) -> i32 {
+    return match op {
+        Ops::Insert(insert_ops_data) if insert_ops_data.offset <= offset => {
+            offset + char_len(&insert_ops_data.content)
+        }
+        Ops::Delete((start_offset, end_offset)) => {
+            shift_deleted(offset, *start_offset, *end_offset)
+        }
+        _ => offset,
+    };
+}
+
+/// Where `start_offset..end_offset` ends up after `op`, text inserted right
+/// at either end stays outside of it.
+fn shift_range(start_offset: i32, end_offset: i32, op: &Ops) -> (i32, i32) {
+    let end_offset = match op {
+        Ops::Insert(insert_ops_data) if insert_ops_data.offset < end_offset => {
+            end_offset + char_len(&insert_ops_data.content)
+        }
+        Ops::Delete((delete_start, delete_end)) => {
+            shift_deleted(end_offset, *delete_start, *delete_end)
+        }
+        _ => end_offset,
+    };
+    return (shift_start(start_offset, op).min(end_offset), end_offset);
+}
+
+fn shift_deleted(offset: i32, start_offset: i32, end_offset: i32) -> i32 {
+    if offset >= end_offset {
+        return offset - (end_offset - start_offset);
+    }
+    return offset.min(start_offset);
+}
+
+pub struct History {
+    undo_stack: Vec<UndoStep>,
+    redo_stack: Vec<UndoStep>,
+    /**
+     * false once something other than a keystroke happened (color change,
+     * auto formatting, undo), so the next keystroke starts a new step
+     */
+    can_merge: bool,
+}
+
+impl History {
+    pub fn new() -> Self {
+        return History {
+            undo_stack: vec![],
+            redo_stack: vec![],
+            can_merge: false,
+        };
+    }
+
+    /// Records text typed (or pasted) by the user at `offset`.
+    pub fn record_insert(&mut self, offset: i32, content: String) {
+        self.redo_stack.clear();
+
+        if self.can_merge && char_len(&content) == 1 {
+            if let Some(UndoStep::Typed {
+                offset: step_offset,
+                content: step_content,
+            }) = self.undo_stack.last_mut()
+            {
+                let is_contiguous = *step_offset + char_len(step_content) == offset;
+                let ends_word = step_content
+                    .chars()
+                    .last()
+                    .map_or(false, |c| c.is_whitespace())
+                    && !content.chars().all(char::is_whitespace);
+
+                if is_contiguous && !ends_word {
+                    step_content.push_str(content.as_str());
+                    return;
+                }
+            }
+        }
+
+        self.undo_stack.push(UndoStep::Typed { offset, content });
+        self.can_merge = true;
+    }
+
+    /// Records text removed by the user, `tags` holds the tags the removed
+    /// text carried so they can be restored on undo.
+    pub fn record_delete(
+        &mut self,
+        start_offset: i32,
+        end_offset: i32,
+        content: String,
+        tags: Vec<TagRun>,
+    ) {
+        self.redo_stack.clear();
+
+        if self.can_merge && end_offset - start_offset == 1 {
+            if let Some(UndoStep::Deleted {
+                offset: step_offset,
+                content: step_content,
+                tags: step_tags,
+            }) = self.undo_stack.last_mut()
+            {
+                if end_offset == *step_offset {
+                    // backspace, the removed text goes in front of the step
+                    *step_offset = start_offset;
+                    step_content.insert_str(0, content.as_str());
+                    step_tags.extend(tags);
+                    return;
+                } else if start_offset == *step_offset {
+                    // forward delete, the removed text follows the step
+                    let shift = char_len(step_content);
+                    step_content.push_str(content.as_str());
+                    step_tags.extend(
+                        tags.into_iter()
+                            .map(|(tag_name, s, e)| (tag_name, s + shift, e + shift)),
+                    );
+                    return;
+                }
+            }
+        }
+
+        self.undo_stack.push(UndoStep::Deleted {
+            offset: start_offset,
+            content,
+            tags,
+        });
+        self.can_merge = true;
+    }
+
+    /// Pushes a step that is undone on its own, like an auto formatting
+    /// conversion.
+    pub fn record_inverse(&mut self, inverse_ops: Vec<Ops>) {
+        self.redo_stack.clear();
+        self.undo_stack.push(UndoStep::Inverse(inverse_ops));
+        self.can_merge = false;
+    }
+
+    /// Stops the next keystroke from merging into the current step.
+    pub fn break_group(&mut self) {
+        self.can_merge = false;
+    }
+
+    pub fn undo(&mut self) -> Option<UndoStep> {
+        self.can_merge = false;
+        self.undo_stack.pop()
+    }
+
+    pub fn redo(&mut self) -> Option<UndoStep> {
+        self.can_merge = false;
+        self.redo_stack.pop()
+    }
+
+    /**
+     * Keeps the steps pointing at the user's own edits when a peer edits the
+     * note, `op` is the peer's edit as applied to the note.
+     */
+    pub fn transform(&mut self, op: &Ops) {
+        for step in self.undo_stack.iter_mut().chain(self.redo_stack.iter_mut()) {
+            step.transform(op);
+        }
+        self.can_merge = false;
+    }
+
+    /// Stores the ops that revert an undo.
+    pub fn push_redo(&mut self, inverse_ops: Vec<Ops>) {
+        self.redo_stack.push(UndoStep::Inverse(inverse_ops));
+    }
+
+    /// Stores the ops that revert a redo, keeping the rest of the redo stack.
+    pub fn push_undo(&mut self, inverse_ops: Vec<Ops>) {
+        self.undo_stack.push(UndoStep::Inverse(inverse_ops));
+    }
+}
+
+/**
+ * Applies `ops` to `target` in order and returns the ops that revert them,
+ * `inverse_ops` gives the inverse of an op before it is applied.
+ */
+pub fn apply_reverting<T>(
+    target: &mut T,
+    ops: Vec<Ops>,
+    inverse_ops: impl Fn(&T, &Ops) -> Vec<Ops>,
+    mut apply: impl FnMut(&mut T, Ops),
+) -> Vec<Ops> {
+    let mut inverse = vec![];
+    for op in ops {
+        let mut op_inverse = inverse_ops(target, &op);
+        op_inverse.append(&mut inverse);
+        inverse = op_inverse;
+        apply(target, op);
+    }
+    return inverse;
+}
+
+fn char_len(content: &str) -> i32 {
+    content.chars().count() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::insert;
+    use crate::document::Document;
+    use crate::tags::{format_insert, BOLD_TAG};
+
+    /// Types `content` at `offset` one keystroke at a time.
+    fn type_text(document: &mut Document, history: &mut History, offset: i32, content: &str) {
+        for (idx, c) in content.chars().enumerate() {
+            let offset = offset + idx as i32;
+            document.apply(&insert(offset, c.to_string().as_str(), ""));
+            history.record_insert(offset, c.to_string());
+        }
+    }
+
+    fn backspace(document: &mut Document, history: &mut History, offset: i32) {
+        let content = document.slice(offset - 1, offset);
+        let tags = document.tag_runs(offset - 1, offset);
+        document.apply(&Ops::Delete((offset - 1, offset)));
+        history.record_delete(offset - 1, offset, content, tags);
+    }
+
+    fn apply_edit(document: &mut Document, ops: Vec<Ops>) -> Vec<Ops> {
+        return apply_reverting(document, ops, Document::inverse_ops, |document, op| {
+            document.apply(&op)
+        });
+    }
+
+    /// Types `content` at the end of the note and formats it as it goes.
+    fn type_formatted(document: &mut Document, history: &mut History, content: &str) {
+        for c in content.chars() {
+            let offset = document.char_count();
+            type_text(document, history, offset, c.to_string().as_str());
+
+            let line_start = document.line_start(offset);
+            let line_before = document.slice(line_start, offset);
+            let ops = format_insert(line_before.as_str(), line_start, c.to_string().as_str());
+            if !ops.is_empty() {
+                let inverse_ops = apply_edit(document, ops);
+                history.record_inverse(inverse_ops);
+            }
+        }
+    }
+
+    fn undo(document: &mut Document, history: &mut History) {
+        let step = history.undo().expect("there should be a step to undo");
+        let redo_ops = apply_edit(document, step.inverse_ops());
+        history.push_redo(redo_ops);
+    }
+
+    fn redo(document: &mut Document, history: &mut History) {
+        let step = history.redo().expect("there should be a step to redo");
+        let undo_ops = apply_edit(document, step.inverse_ops());
+        history.push_undo(undo_ops);
+    }
+
+    #[test]
+    fn keystrokes_merge_into_one_step() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "hello");
+
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "");
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn words_are_undone_one_at_a_time() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "hello big world");
+
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "hello big ");
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "hello ");
+    }
+
+    #[test]
+    fn typing_somewhere_else_starts_a_new_step() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "ab");
+        // the cursor went back to the start
+        type_text(&mut document, &mut history, 0, "XY");
+        history.break_group();
+        type_text(&mut document, &mut history, 4, "cd");
+        assert_eq!(document.text(), "XYabcd");
+
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "XYab");
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "ab");
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "");
+    }
+
+    #[test]
+    fn backspaces_merge_and_bring_their_tags_back() {
+        let mut document = Document::new();
+        document.apply(&insert(0, "abc", "bold"));
+        let mut history = History::new();
+        backspace(&mut document, &mut history, 3);
+        backspace(&mut document, &mut history, 2);
+        assert_eq!(document.text(), "a");
+
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "abc");
+        assert_eq!(document.tag_runs(0, 3), vec![("bold".to_string(), 0, 3)]);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn redo_reverts_undo() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "one two");
+        let typed = document.clone();
+        let inverse_ops = apply_edit(
+            &mut document,
+            vec![Ops::ApplyTag(("bold".to_string(), 0, 3))],
+        );
+        history.record_inverse(inverse_ops);
+        let formatted = document.clone();
+
+        undo(&mut document, &mut history);
+        assert_eq!(document, typed);
+        undo(&mut document, &mut history);
+        undo(&mut document, &mut history);
+        assert_eq!(document, Document::new());
+
+        redo(&mut document, &mut history);
+        redo(&mut document, &mut history);
+        assert_eq!(document, typed);
+        redo(&mut document, &mut history);
+        assert_eq!(document, formatted);
+        assert!(history.redo().is_none());
+    }
+
+    #[test]
+    fn new_edits_drop_what_could_be_redone() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "ab");
+        undo(&mut document, &mut history);
+
+        type_text(&mut document, &mut history, 0, "x");
+        assert!(history.redo().is_none());
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "");
+    }
+
+    #[test]
+    fn undoing_a_conversion_brings_the_markers_back() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_formatted(&mut document, &mut history, "**word** ");
+        assert_eq!(document.text(), "word ");
+        assert_eq!(document.tag_runs(0, 5), vec![(BOLD_TAG.to_string(), 0, 4)]);
+
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "**word** ");
+        assert_eq!(document.tag_runs(0, 9), vec![]);
+
+        redo(&mut document, &mut history);
+        assert_eq!(document.text(), "word ");
+        assert_eq!(document.tag_runs(0, 5), vec![(BOLD_TAG.to_string(), 0, 4)]);
+    }
+
+    #[test]
+    fn undo_skips_over_the_peers_edits() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "world");
+
+        for op in vec![insert(0, "hello ", ""), insert(11, "!", "")] {
+            document.apply(&op);
+            history.transform(&op);
+        }
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "hello !");
+
+        redo(&mut document, &mut history);
+        assert_eq!(document.text(), "hello world!");
+        let op = Ops::Delete((0, 6));
+        document.apply(&op);
+        history.transform(&op);
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "!");
+    }
+
+    #[test]
+    fn peer_edits_inside_a_step() {
+        let mut document = Document::new();
+        let mut history = History::new();
+        type_text(&mut document, &mut history, 0, "abcd");
+        let op = insert(2, "XY", "");
+        document.apply(&op);
+        history.transform(&op);
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "XY");
+
+        let mut document = Document::new();
+        let mut history = History::new();
+        document.apply(&insert(0, "0123", ""));
+        type_text(&mut document, &mut history, 4, "abcd");
+        let op = Ops::Delete((2, 6));
+        document.apply(&op);
+        history.transform(&op);
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "01");
+
+        let mut document = Document::new();
+        document.apply(&insert(0, "abcdef", "bold"));
+        let mut history = History::new();
+        backspace(&mut document, &mut history, 4);
+        backspace(&mut document, &mut history, 3);
+        let op = insert(0, "12", "");
+        document.apply(&op);
+        history.transform(&op);
+        undo(&mut document, &mut history);
+        assert_eq!(document.text(), "12abcdef");
+        assert!(document.tags_at(4).contains(&"bold".to_string()));
+    }
+}
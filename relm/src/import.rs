@@ -0,0 +1,327 @@
+use crate::tags::{format_insert, BLOCK_TAGS};
+use crate::text_ops::{InsertOpsData, Ops, TagRun};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Stands in for escaped chars towards the formatter, it is no marker and
+/// neither whitespace nor part of a word.
+const LITERAL_PLACEHOLDER: char = '\u{FFFC}';
+
+/**
+ * Turns a Markdown document into the ops that type it into an empty note:
+ * the text with its markers removed, inserted with `color_tag`, followed by
+ * the tags. Every line goes through the formatter the way typed text does,
+ * so an imported note looks exactly like one typed in.
+ */
+pub fn markdown_to_ops(markdown: &str, color_tag: &str) -> Vec<Ops> {
+    let mut text = String::new();
+    let mut tag_ops = vec![];
+
+    for (idx, line) in markdown.lines().enumerate() {
+        if idx > 0 {
+            text.push('\n');
+        }
+        let line_offset = text.chars().count() as i32;
+        let (line_text, runs) = type_line(line);
+        text.push_str(line_text.as_str());
+
+        for (tag_name, start_offset, end_offset) in runs {
+            tag_ops.push(Ops::ApplyTag((
+                tag_name,
+                line_offset + start_offset,
+                line_offset + end_offset,
+            )));
+        }
+    }
+
+    let mut ops = vec![Ops::SelectColorTag(color_tag.to_string())];
+    if !text.is_empty() {
+        ops.push(Ops::Insert(InsertOpsData::new(
+            0,
+            text,
+            color_tag.to_string(),
+        )));
+    }
+    ops.extend(tag_ops);
+    return ops;
+}
+
+/**
+ * Types a line grapheme by grapheme, applying the formatter's conversions as
+ * they happen, and returns what is left of it with its tags in char offsets.
+ */
+fn type_line(line: &str) -> (String, Vec<TagRun>) {
+    // a trailing backslash is a hard line break, the newline itself stays
+    let line = match line.strip_suffix('\\') {
+        Some(stripped) if !stripped.ends_with('\\') => stripped,
+        _ => line,
+    };
+
+    // the chars typed so far, escaped ones are marked as literal
+    let mut typed: Vec<(char, bool)> = vec![];
+    let mut runs: Vec<TagRun> = vec![];
+
+    let graphemes = line.graphemes(true).collect::<Vec<&str>>();
+    let mut idx = 0;
+    while idx <= graphemes.len() {
+        // the end of the line closes what is still open, like a newline
+        let (grapheme, is_literal) = match graphemes.get(idx) {
+            Some(&"\\")
+                if graphemes
+                    .get(idx + 1)
+                    .map_or(false, |next| is_escapable(next)) =>
+            {
+                idx += 1;
+                (graphemes[idx], true)
+            }
+            Some(grapheme) => (*grapheme, false),
+            None => ("\n", false),
+        };
+        idx += 1;
+
+        let placeholder = LITERAL_PLACEHOLDER.to_string();
+        let inserted = if is_literal {
+            placeholder.as_str()
+        } else {
+            grapheme
+        };
+        let inserted_len = if grapheme == "\n" {
+            0
+        } else {
+            typed.extend(grapheme.chars().map(|c| (c, is_literal)));
+            grapheme.chars().count()
+        };
+
+        let line_before = typed[..typed.len() - inserted_len]
+            .iter()
+            .map(|(c, is_literal)| if *is_literal { LITERAL_PLACEHOLDER } else { *c })
+            .collect::<String>();
+        for op in format_insert(line_before.as_str(), 0, inserted) {
+            match op {
+                Ops::Delete((start_offset, end_offset)) => {
+                    typed.drain(start_offset as usize..end_offset as usize);
+                    for (_, run_start, run_end) in runs.iter_mut() {
+                        *run_start = shift_deleted(*run_start, start_offset, end_offset);
+                        *run_end = shift_deleted(*run_end, start_offset, end_offset);
+                    }
+                }
+                Ops::ApplyTag(run) => runs.push(run),
+                _ => {}
+            }
+        }
+    }
+
+    // a heading, list item or quote takes in the rest of its line
+    let line_end = typed.len() as i32;
+    for (tag_name, _, end_offset) in runs.iter_mut() {
+        if BLOCK_TAGS.contains(&tag_name.as_str()) {
+            *end_offset = line_end;
+        }
+    }
+
+    let text = typed.into_iter().map(|(c, _)| c).collect::<String>();
+    return (text, runs);
+}
+
+/// A backslash escapes the ASCII punctuation following it.
+fn is_escapable(grapheme: &str) -> bool {
+    let mut chars = grapheme.chars();
+    return match (chars.next(), chars.next()) {
+        (Some(c), None) => c.is_ascii_punctuation(),
+        _ => false,
+    };
+}
+
+fn shift_deleted(offset: i32, start_offset: i32, end_offset: i32) -> i32 {
+    if offset >= end_offset {
+        return offset - (end_offset - start_offset);
+    }
+    return offset.min(start_offset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::insert;
+    use crate::document::Document;
+    use crate::export::{to_markdown, MarkdownOptions};
+    use crate::tags::{
+        BLOCKQUOTE_TAG, BLOCK_MARKER_TAG, BOLD_TAG, CODE_TAG, HEADING_1_TAG, HEADING_2_TAG,
+        ITALIC_TAG, LINK_TAG, LINK_URL_TAG, LIST_ITEM_TAG, LIST_MARKER_TAG, RULE_TAG,
+        STRIKETHROUGH_TAG,
+    };
+    use std::collections::HashMap;
+
+    fn run(tag_name: &str, start_offset: i32, end_offset: i32) -> TagRun {
+        (tag_name.to_string(), start_offset, end_offset)
+    }
+
+    /// The text and tag runs the ops leave in an empty note.
+    fn imported(markdown: &str) -> (String, Vec<TagRun>) {
+        let mut document = Document::new();
+        for op in markdown_to_ops(markdown, "") {
+            document.apply(&op);
+        }
+        let mut runs = document.tag_runs(0, document.char_count());
+        runs.sort();
+        return (document.text(), runs);
+    }
+
+    #[test]
+    fn exported_notes_import_unchanged() {
+        let cases = vec![
+            ("plain text", vec![]),
+            ("a big word", vec![run(ITALIC_TAG, 2, 5)]),
+            ("a big word", vec![run(BOLD_TAG, 2, 5)]),
+            ("un believ able", vec![run(ITALIC_TAG, 3, 9)]),
+            (
+                "big world",
+                vec![run(BOLD_TAG, 0, 9), run(ITALIC_TAG, 4, 9)],
+            ),
+            ("a b c", vec![run(BOLD_TAG, 0, 5), run(ITALIC_TAG, 2, 3)]),
+            ("both", vec![run(BOLD_TAG, 0, 4), run(ITALIC_TAG, 0, 4)]),
+            ("one\ntwo\n\nthree *", vec![run(BOLD_TAG, 4, 7)]),
+            ("1 < 2 & 3 * 4 \\ 5 `x` [y] _z_ ~~", vec![]),
+            ("# not a heading\n- not a list\n1. not a list", vec![]),
+            ("日本語 *ok*", vec![run(ITALIC_TAG, 0, 3)]),
+            ("gone for good", vec![run(STRIKETHROUGH_TAG, 0, 4)]),
+            ("run a*b now", vec![run(CODE_TAG, 4, 7)]),
+            ("bold code", vec![run(BOLD_TAG, 0, 9), run(CODE_TAG, 5, 9)]),
+            (
+                "see site(https://a.b/c_d)!",
+                vec![run(LINK_TAG, 4, 8), run(LINK_URL_TAG, 8, 25)],
+            ),
+            (
+                "# Title\n## Sub\n> quote",
+                vec![
+                    run(BLOCKQUOTE_TAG, 15, 22),
+                    run(BLOCK_MARKER_TAG, 0, 2),
+                    run(BLOCK_MARKER_TAG, 8, 11),
+                    run(BLOCK_MARKER_TAG, 15, 17),
+                    run(HEADING_1_TAG, 0, 7),
+                    run(HEADING_2_TAG, 8, 14),
+                ],
+            ),
+            (
+                "- item\n12. more\n---",
+                vec![
+                    run(BOLD_TAG, 2, 6),
+                    run(LIST_ITEM_TAG, 0, 6),
+                    run(LIST_ITEM_TAG, 7, 15),
+                    run(LIST_MARKER_TAG, 0, 2),
+                    run(LIST_MARKER_TAG, 7, 11),
+                    run(RULE_TAG, 16, 19),
+                ],
+            ),
+        ];
+
+        for (text, mut runs) in cases {
+            runs.sort();
+            let markdown = to_markdown(
+                text,
+                &runs,
+                &HashMap::new(),
+                &HashMap::new(),
+                &MarkdownOptions { keep_colors: false },
+            );
+            assert_eq!(
+                imported(markdown.as_str()),
+                (text.to_string(), runs),
+                "importing {:?}",
+                markdown
+            );
+        }
+    }
+
+    /// Markdown written elsewhere reads like it would have been typed.
+    #[test]
+    fn markup_the_export_doesnt_write() {
+        let cases = vec![
+            ("__bold__", "bold", vec![run(BOLD_TAG, 0, 4)]),
+            ("_it_ ok", "it ok", vec![run(ITALIC_TAG, 0, 2)]),
+            (
+                "___both___",
+                "both",
+                vec![run(BOLD_TAG, 0, 4), run(ITALIC_TAG, 0, 4)],
+            ),
+            (
+                "***both***",
+                "both",
+                vec![run(BOLD_TAG, 0, 4), run(ITALIC_TAG, 0, 4)],
+            ),
+            ("x ~~y~~z", "x yz", vec![run(STRIKETHROUGH_TAG, 2, 3)]),
+            ("``a`b``", "a`b", vec![run(CODE_TAG, 0, 3)]),
+            (
+                "> *quoted*",
+                "> quoted",
+                vec![
+                    run(BLOCKQUOTE_TAG, 0, 8),
+                    run(BLOCK_MARKER_TAG, 0, 2),
+                    run(ITALIC_TAG, 2, 8),
+                ],
+            ),
+        ];
+
+        for (markdown, text, mut runs) in cases {
+            runs.sort();
+            assert_eq!(
+                imported(markdown),
+                (text.to_string(), runs),
+                "importing {:?}",
+                markdown
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_markup_stays_text() {
+        let cases = vec![
+            ("**unclosed", "**unclosed", vec![]),
+            ("*unclosed", "*unclosed", vec![]),
+            ("closed**", "closed**", vec![]),
+            ("a ** b * c", "a ** b * c", vec![]),
+            ("** a**", "** a**", vec![]),
+            ("**", "**", vec![]),
+            ("**bold*", "**bold*", vec![]),
+            ("~~gone", "~~gone", vec![]),
+            ("`code", "`code", vec![]),
+            ("``", "``", vec![]),
+            ("snake_case_name", "snake_case_name", vec![]),
+            ("[a](b c)", "[a](b c)", vec![]),
+            ("[a]()", "[a]()", vec![]),
+            ("\\*escaped\\*", "*escaped*", vec![]),
+            ("\\# no heading", "# no heading", vec![]),
+            // the unused asterisk stays outside of the emphasis
+            ("*italic**", "italic*", vec![run(ITALIC_TAG, 0, 6)]),
+            ("*a **b*", "a **b", vec![run(ITALIC_TAG, 0, 5)]),
+            ("**a\nb**", "**a\nb**", vec![]),
+            ("trailing\\", "trailing", vec![]),
+            ("trailing\\\\", "trailing\\", vec![]),
+        ];
+
+        for (markdown, text, mut runs) in cases {
+            runs.sort();
+            assert_eq!(
+                imported(markdown),
+                (text.to_string(), runs),
+                "importing {:?}",
+                markdown
+            );
+        }
+    }
+
+    #[test]
+    fn text_takes_the_color_tag() {
+        assert_eq!(
+            markdown_to_ops("*x*", "red"),
+            vec![
+                Ops::SelectColorTag("red".to_string()),
+                insert(0, "x", "red"),
+                Ops::ApplyTag((ITALIC_TAG.to_string(), 0, 1)),
+            ]
+        );
+        assert_eq!(
+            markdown_to_ops("", "red"),
+            vec![Ops::SelectColorTag("red".to_string())]
+        );
+    }
+}
@@ -1,145 +1,2372 @@
 extern crate pango;
 
+use gtk::gdk;
+use gtk::gio;
 use gtk::prelude::*;
 use gtk::Orientation::{Horizontal, Vertical};
-use gtk::{Align, Box, Button, Inhibit, Label, TextBuffer, TextTag, TextView, Window, WindowType};
-use relm::{connect, Relm, Update, Widget};
+use gtk::{
+    Align, Box, Button, Entry, Inhibit, Label, ListBox, ListBoxRow, ScrolledWindow, TextBuffer,
+    TextTag, TextView, Window, WindowType,
+};
+use relm::{connect, timeout, Relm, Update, Widget};
 use relm_derive::Msg;
-use std::fs::File;
-use std::io::Read;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use unicode_segmentation::UnicodeSegmentation;
 
+mod buffer_ops;
+mod cli;
+mod collab;
+mod crypto;
+mod document;
+mod export;
+mod history;
+mod import;
+mod note_file;
+mod notebook;
+mod search;
+mod shortcuts;
 mod tags;
 mod text_ops;
-use tags::BoldItalicTagState;
+mod timeline;
+use collab::{CharId, Message, Replica};
+use common::palette::Palette;
+use crypto::CryptoError;
+use document::Document;
+use export::{ExportFormat, MarkdownOptions};
+use history::History;
+use note_file::{Note, Snapshot};
+use notebook::Notebook;
+use search::{SearchMatch, SearchOptions};
 use text_ops::{DeleteTextEventData, InsertOpsData, InsertTextEventData, Ops};
 
-const COLORS: [&str; 3] = ["#F5E050", "#F38E94", "#CC8CF3"];
+/// Background colors of the highlighter, `highlight_tag_1` is the first one.
+const HIGHLIGHTS: [&str; 3] = ["#FFF3A3", "#C8F7C5", "#BDE0FE"];
+/// The single note file older versions kept in the working directory, it is
+/// imported into the notebook on first launch.
 const NOTE_FILE_NAME: &str = "note-backup.bin";
+const IMPORTED_NOTE_NAME: &str = "note-backup";
+/// The spot in the buffer where the peer of a shared note has its cursor.
+const PEER_CURSOR_MARK: &str = "peer_cursor";
+/// Edits are saved once the note has been left alone for this long.
+const AUTOSAVE_DELAY_MS: u32 = 2000;
 
 struct Model {
+    notebook: Notebook,
+    palette: Palette,
+    current_note: String,
     current_tag: String,
     previous_tag: String,
+    snapshot: Option<Snapshot>,
+    /**
+     * ops applied since `snapshot` was taken
+     */
     ops: Vec<Ops>,
+    /**
+     * when each op of `ops` was applied, see `Note::op_times`
+     */
+    op_times: Vec<u64>,
+    /**
+     * the text and tags of the open note, every op lands here first and the
+     * buffer is kept showing the same
+     */
+    document: Document,
+    history: History,
+    /**
+     * true when the buffer has changes that haven't been written yet, bumping
+     * `edit_generation` on every edit lets a pending autosave notice that it
+     * got superseded
+     */
+    is_dirty: bool,
+    edit_generation: u64,
+    /**
+     * keeps a failing autosave from opening a new error dialog every time
+     */
+    save_failed: bool,
     is_hydrating: bool,
+    /**
+     * true while ops applied by the app itself (formatting, undo, redo) are
+     * echoed back through the buffer signals, which must not be recorded again
+     */
+    is_replaying: bool,
+    /**
+     * the passphrase the open note is encrypted with, it gets saved encrypted
+     * again as long as this is set
+     */
+    passphrase: Option<String>,
+    /**
+     * true when the open note couldn't be read, because its passphrase wasn't
+     * given or the file is damaged. Saving would overwrite it with an empty
+     * note, so nothing is saved until another note is opened.
+     */
+    is_locked: bool,
+    /**
+     * matches of the find bar in the open note, highlighted with the search
+     * match tag while the bar is open
+     */
+    search_matches: Vec<SearchMatch>,
+    /**
+     * the connection to the peer while the open note is shared, bumping
+     * `session_id` for every new one lets events of an old one be ignored
+     */
+    session: Option<Session>,
+    session_id: u64,
+    /**
+     * watches the file of the open note for changes made by other programs
+     */
+    file_monitor: Option<gio::FileMonitor>,
+    /**
+     * the fingerprint of the note file as it was last read or written, a
+     * file that doesn't match it any more was changed by someone else
+     */
+    known_fingerprint: Option<u64>,
+    /**
+     * true while the note file has changes by another program that haven't
+     * been reloaded, merged or overwritten yet, autosaving waits until then
+     */
+    has_external_change: bool,
     relm: Relm<Win>,
+}
+
+/// A note shared with a peer, see `collab`.
+struct Session {
+    id: u64,
+    /// set once the peer has joined, or the host has said hello
+    replica: Option<Replica>,
+    /// set once the peer has joined
+    stream: Option<collab::Stream>,
+    /// ends the waiting for a peer and tells the reading thread to be quiet
+    stop: Arc<AtomicBool>,
+    /// the char the peer's cursor is right after, once it has told
+    peer_cursor: Option<Option<CharId>>,
+    sent_cursor: Option<Option<CharId>>,
+}
+
+#[derive(Msg)]
+enum Msg {
+    Quit,
+    InsertText(InsertTextEventData),
+    DeleteText(DeleteTextEventData),
+    SelectColor(String),
+    ToggleTag(String),
+    Highlight(Option<String>),
+    SelectPaletteColor(usize),
+    Find,
+    SearchChanged,
+    RefreshSearch,
+    FindNext,
+    FindPrevious,
+    Replace,
+    ReplaceAll,
+    CloseSearch,
+    ShowShortcuts,
+    SaveNote,
+    Autosave(u64),
+    Hydrate,
+    OpenNote(String),
+    NewNote,
+    RenameNote,
+    DeleteNote,
+    DuplicateNote,
+    SetHydrating(bool),
+    SetReplaying(bool),
+    Undo,
+    Redo,
+    ShowHistory,
+    ExportNote,
+    ImportNote,
+    EditPalette,
+    EditPassphrase,
+    ShareNote,
+    Session(u64, collab::Event),
+    CursorMoved,
+    NoteFileChanged,
+    ResolveExternalChange(ExternalChange),
+    // UpdateTagState((bool, i32)),
+}
+
+fn get_button_with_label(name: &str) -> Button {
+    let button = Button::new();
+    button.set_widget_name(name);
+    button.set_size_request(140, 50);
+    button.set_margin_start(10);
+    button.set_margin_end(10);
+    button.set_border_width(0);
+    button.set_label(name);
+    return button;
+}
+
+fn show_error_dialog(error_msg: &str) {
+    let dialog = gtk::Dialog::new();
+    dialog.set_title("Error");
+    dialog.add_button("Okay", gtk::ResponseType::Ok);
+    dialog.set_valign(Align::Center);
+    dialog.connect_response(|d, _r| {
+        d.close();
+    });
+    dialog.set_size_request(400, 250);
+    let content_box = dialog.content_area();
+    let error_label = Label::new(Some(error_msg));
+    content_box.pack_start(&error_label, false, false, 0);
+
+    dialog.show_all();
+}
+
+/// Asks for a line of text, returns `None` when the dialog is cancelled.
+fn prompt_text(parent: &Window, title: &str, initial_text: &str) -> Option<String> {
+    let dialog = gtk::Dialog::with_buttons(
+        Some(title),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Okay", gtk::ResponseType::Ok),
+        ],
+    );
+    dialog.set_default_response(gtk::ResponseType::Ok);
+    let entry = Entry::new();
+    entry.set_text(initial_text);
+    entry.set_activates_default(true);
+    entry.set_margin_start(10);
+    entry.set_margin_end(10);
+    dialog.content_area().pack_start(&entry, false, false, 10);
+    dialog.show_all();
+
+    let response = dialog.run();
+    let text = entry.text().to_string();
+    dialog.close();
+
+    if response == gtk::ResponseType::Ok {
+        return Some(text);
+    }
+    return None;
+}
+
+/// Asks for a passphrase without showing it, returns `None` when cancelled.
+fn prompt_passphrase(parent: &Window, title: &str, message: &str) -> Option<String> {
+    let dialog = gtk::Dialog::with_buttons(
+        Some(title),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Unlock", gtk::ResponseType::Ok),
+        ],
+    );
+    dialog.set_default_response(gtk::ResponseType::Ok);
+    let label = Label::new(Some(message));
+    let entry = Entry::new();
+    entry.set_visibility(false);
+    entry.set_activates_default(true);
+    for widget in &[label.upcast_ref::<gtk::Widget>(), entry.upcast_ref()] {
+        widget.set_margin_start(10);
+        widget.set_margin_end(10);
+        dialog.content_area().pack_start(*widget, false, false, 5);
+    }
+    dialog.show_all();
+
+    let response = dialog.run();
+    let passphrase = entry.text().to_string();
+    dialog.close();
+
+    if response == gtk::ResponseType::Ok {
+        return Some(passphrase);
+    }
+    return None;
+}
+
+/// What to do with the passphrase of the open note.
+enum PassphraseChoice {
+    Set(String),
+    Remove,
+}
+
+/**
+ * Asks for a new passphrase twice, until both match. Notes that are
+ * encrypted already can have their passphrase removed instead.
+ */
+fn choose_passphrase(parent: &Window, is_encrypted: bool) -> Option<PassphraseChoice> {
+    const REMOVE_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(1);
+
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Note Passphrase"),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[("Cancel", gtk::ResponseType::Cancel)],
+    );
+    if is_encrypted {
+        dialog.add_button("Remove Passphrase", REMOVE_RESPONSE);
+    }
+    dialog.add_button("Set Passphrase", gtk::ResponseType::Ok);
+    dialog.set_default_response(gtk::ResponseType::Ok);
+
+    let grid = gtk::Grid::new();
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(10);
+    grid.set_margin_start(10);
+    grid.set_margin_end(10);
+    let passphrase = Entry::new();
+    let repeated = Entry::new();
+    for (row, (label, entry)) in [("New passphrase", &passphrase), ("Repeat it", &repeated)]
+        .iter()
+        .enumerate()
+    {
+        let label = Label::new(Some(*label));
+        label.set_halign(Align::Start);
+        entry.set_visibility(false);
+        entry.set_activates_default(true);
+        grid.attach(&label, 0, row as i32, 1, 1);
+        grid.attach(*entry, 1, row as i32, 1, 1);
+    }
+    let mismatch = Label::new(None);
+    grid.attach(&mismatch, 0, 2, 2, 1);
+    dialog.content_area().pack_start(&grid, false, false, 10);
+    dialog.show_all();
+
+    let choice = loop {
+        match dialog.run() {
+            gtk::ResponseType::Ok if passphrase.text().is_empty() => {
+                mismatch.set_text("The passphrase can't be empty");
+            }
+            gtk::ResponseType::Ok if passphrase.text() != repeated.text() => {
+                mismatch.set_text("The passphrases don't match");
+            }
+            gtk::ResponseType::Ok => {
+                break Some(PassphraseChoice::Set(passphrase.text().to_string()))
+            }
+            response if response == REMOVE_RESPONSE => break Some(PassphraseChoice::Remove),
+            _ => break None,
+        }
+    };
+    dialog.close();
+    return choice;
+}
+
+enum ShareRole {
+    Host,
+    Join,
+}
+
+/**
+ * Asks for the address to share the open note on, or to join a note shared
+ * by another instance on.
+ */
+fn choose_share(parent: &Window) -> Option<(ShareRole, String)> {
+    const JOIN_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(1);
+
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Share Note"),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Join", JOIN_RESPONSE),
+            ("Share", gtk::ResponseType::Ok),
+        ],
+    );
+    dialog.set_default_response(gtk::ResponseType::Ok);
+
+    let message = Label::new(Some(
+        "Share this note on a TCP address or the path of a Unix socket, or join\n\
+         the note another Pretty Notes shares there. Joining opens it as a new note.",
+    ));
+    let entry = Entry::new();
+    entry.set_text("127.0.0.1:7878");
+    entry.set_activates_default(true);
+    let content_box = dialog.content_area();
+    content_box.pack_start(&message, false, false, 10);
+    content_box.pack_start(&entry, false, false, 10);
+    dialog.show_all();
+
+    let response = dialog.run();
+    let address = entry.text().trim().to_string();
+    dialog.close();
+
+    if address.is_empty() {
+        return None;
+    }
+    return match response {
+        gtk::ResponseType::Ok => Some((ShareRole::Host, address)),
+        response if response == JOIN_RESPONSE => Some((ShareRole::Join, address)),
+        _ => None,
+    };
+}
+
+fn confirm(parent: &Window, message: &str) -> bool {
+    let dialog = gtk::MessageDialog::new(
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        gtk::MessageType::Question,
+        gtk::ButtonsType::YesNo,
+        message,
+    );
+    let response = dialog.run();
+    dialog.close();
+    return response == gtk::ResponseType::Yes;
+}
+
+/**
+ * Asks where to export a note to, returns the chosen path and whether the
+ * colors should be kept in Markdown, or `None` when the dialog is cancelled.
+ * The format follows the extension of the chosen file.
+ */
+fn choose_export_file(parent: &Window, default_name: &str) -> Option<(PathBuf, bool)> {
+    let dialog = gtk::FileChooserDialog::with_buttons(
+        Some("Export Note"),
+        Some(parent),
+        gtk::FileChooserAction::Save,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Export", gtk::ResponseType::Accept),
+        ],
+    );
+    dialog.set_current_name(default_name);
+    dialog.set_do_overwrite_confirmation(true);
+
+    for (name, pattern) in &[
+        ("Markdown", "*.md"),
+        ("HTML", "*.html"),
+        ("Plain text", "*.txt"),
+    ] {
+        let filter = gtk::FileFilter::new();
+        filter.set_name(Some(*name));
+        filter.add_pattern(pattern);
+        dialog.add_filter(&filter);
+    }
+
+    let keep_colors = gtk::CheckButton::with_label("Keep colors in Markdown as HTML spans");
+    keep_colors.set_active(true);
+    dialog.set_extra_widget(&keep_colors);
+
+    let response = dialog.run();
+    let path = dialog.filename();
+    let keep_colors = keep_colors.is_active();
+    dialog.close();
+
+    if response == gtk::ResponseType::Accept {
+        return path.map(|path| (path, keep_colors));
+    }
+    return None;
+}
+
+/// Asks for a Markdown file to import, returns `None` when cancelled.
+fn choose_import_file(parent: &Window) -> Option<PathBuf> {
+    let dialog = gtk::FileChooserDialog::with_buttons(
+        Some("Import Markdown"),
+        Some(parent),
+        gtk::FileChooserAction::Open,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Import", gtk::ResponseType::Accept),
+        ],
+    );
+
+    let filter = gtk::FileFilter::new();
+    filter.set_name(Some("Markdown"));
+    filter.add_pattern("*.md");
+    filter.add_pattern("*.markdown");
+    dialog.add_filter(&filter);
+
+    let response = dialog.run();
+    let path = dialog.filename();
+    dialog.close();
+
+    if response == gtk::ResponseType::Accept {
+        return path;
+    }
+    return None;
+}
+
+/// Maps the name of every color tag to the color it is drawn in.
+fn color_tag_colors(palette: &Palette) -> HashMap<String, String> {
+    return palette
+        .all_colors()
+        .map(|color| (color.tag_name(), color.color.to_string()))
+        .collect();
+}
+
+/// Maps the name of every highlight tag to its background color.
+fn highlight_tag_colors() -> HashMap<String, String> {
+    return HIGHLIGHTS
+        .iter()
+        .enumerate()
+        .map(|(idx, color)| (format!("highlight_tag_{}", idx + 1), color.to_string()))
+        .collect();
+}
+
+/// Fills `button` with `color` instead of the theme's button background.
+fn paint_button(button: &Button, color: &str) {
+    let provider = gtk::CssProvider::new();
+    let css = format!(
+        "button {{ background-image: none; background-color: {}; }}",
+        color
+    );
+    if provider.load_from_data(css.as_bytes()).is_ok() {
+        button
+            .style_context()
+            .add_provider(&provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    }
+}
+
+/// Formats `rgba` the way palette colors are written down, as `#RRGGBB`.
+fn rgba_to_hex(rgba: &gdk::RGBA) -> String {
+    let channel = |value: f64| (value * 255.0).round() as u8;
+    return format!(
+        "#{:02X}{:02X}{:02X}",
+        channel(rgba.red()),
+        channel(rgba.green()),
+        channel(rgba.blue())
+    );
+}
+
+/// Lists the colors of `palette` in `rows`, each with a color picker and
+/// buttons to move or remove it. Every change fills the list again.
+fn fill_palette_rows(rows: &ListBox, palette: &Rc<RefCell<Palette>>) {
+    for row in rows.children() {
+        rows.remove(&row);
+    }
+
+    let colors = palette.borrow().colors.clone();
+    for (idx, color) in colors.iter().enumerate() {
+        let row_box = gtk::Box::new(Horizontal, 5);
+        let rgba = gdk::RGBA::parse(color.color.as_str()).unwrap_or(gdk::RGBA::BLACK);
+        let color_button = gtk::ColorButton::with_rgba(&rgba);
+        let up_button = Button::with_label("Up");
+        let down_button = Button::with_label("Down");
+        let remove_button = Button::with_label("Remove");
+        up_button.set_sensitive(idx > 0);
+        down_button.set_sensitive(idx + 1 < colors.len());
+        remove_button.set_sensitive(colors.len() > 1);
+
+        row_box.pack_start(&color_button, true, true, 0);
+        row_box.pack_start(&up_button, false, false, 0);
+        row_box.pack_start(&down_button, false, false, 0);
+        row_box.pack_start(&remove_button, false, false, 0);
+
+        let (rows_ref, palette_ref) = (rows.clone(), palette.clone());
+        color_button.connect_color_set(move |button| {
+            let color = rgba_to_hex(&button.rgba());
+            palette_ref.borrow_mut().set_color(idx, color.as_str());
+            fill_palette_rows(&rows_ref, &palette_ref);
+        });
+        let (rows_ref, palette_ref) = (rows.clone(), palette.clone());
+        up_button.connect_clicked(move |_| {
+            palette_ref.borrow_mut().shift(idx, -1);
+            fill_palette_rows(&rows_ref, &palette_ref);
+        });
+        let (rows_ref, palette_ref) = (rows.clone(), palette.clone());
+        down_button.connect_clicked(move |_| {
+            palette_ref.borrow_mut().shift(idx, 1);
+            fill_palette_rows(&rows_ref, &palette_ref);
+        });
+        let (rows_ref, palette_ref) = (rows.clone(), palette.clone());
+        remove_button.connect_clicked(move |_| {
+            palette_ref.borrow_mut().remove(idx);
+            fill_palette_rows(&rows_ref, &palette_ref);
+        });
+
+        rows.add(&row_box);
+    }
+    rows.show_all();
+}
+
+/// Lets the user add, remove, reorder and recolor the palette colors, returns
+/// the edited palette or `None` when the dialog is cancelled.
+fn run_palette_editor(parent: &Window, palette: &Palette) -> Option<Palette> {
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Palette"),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Okay", gtk::ResponseType::Ok),
+        ],
+    );
+    dialog.set_default_size(360, 300);
+
+    let palette = Rc::new(RefCell::new(palette.clone()));
+    let rows = ListBox::new();
+    rows.set_selection_mode(gtk::SelectionMode::None);
+    fill_palette_rows(&rows, &palette);
+
+    let add_button = Button::with_label("Add Color…");
+    let (rows_ref, palette_ref, dialog_ref) = (rows.clone(), palette.clone(), dialog.clone());
+    add_button.connect_clicked(move |_| {
+        let chooser = gtk::ColorChooserDialog::new(Some("Add Color"), Some(&dialog_ref));
+        let response = chooser.run();
+        let color = rgba_to_hex(&chooser.rgba());
+        chooser.close();
+
+        if response == gtk::ResponseType::Ok {
+            palette_ref.borrow_mut().add(color.as_str());
+            fill_palette_rows(&rows_ref, &palette_ref);
+        }
+    });
+
+    let content_box = dialog.content_area();
+    content_box.pack_start(&rows, true, true, 10);
+    content_box.pack_start(&add_button, false, false, 10);
+    dialog.show_all();
+
+    let response = dialog.run();
+    dialog.close();
+
+    if response == gtk::ResponseType::Ok {
+        return Some(palette.borrow().clone());
+    }
+    return None;
+}
+
+/// How the version picked in the history viewer gets restored.
+enum RestoreChoice {
+    /// the open note is edited back to the version, which can be undone
+    Here,
+    AsNewNote,
+}
+
+/// When an op was applied, in the local time zone.
+fn format_op_time(time: u64) -> String {
+    if time == note_file::UNKNOWN_TIME {
+        return "at an unknown time".to_string();
+    }
+    return gtk::glib::DateTime::from_unix_local((time / 1000) as i64)
+        .and_then(|date_time| date_time.format("on %x at %X"))
+        .map(|formatted| formatted.to_string())
+        .unwrap_or_default();
+}
+
+/**
+ * Shows the note as it was after the first n ops of its log in a preview
+ * that can't be edited, n is picked with a slider. Returns the picked n and
+ * how to restore that version, or `None` when the dialog is closed.
+ */
+fn run_history_viewer(
+    parent: &Window,
+    tag_table: &gtk::TextTagTable,
+    snapshot: Option<&Snapshot>,
+    ops: &[Ops],
+    op_times: &[u64],
+) -> Option<(usize, RestoreChoice)> {
+    const RESTORE_AS_NEW_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(1);
+
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Note History"),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[
+            ("Close", gtk::ResponseType::Cancel),
+            ("Restore as New Note", RESTORE_AS_NEW_RESPONSE),
+            ("Restore This Version", gtk::ResponseType::Ok),
+        ],
+    );
+    dialog.set_default_size(640, 480);
+
+    let preview_buffer = TextBuffer::new(Some(tag_table));
+    let preview = TextView::with_buffer(&preview_buffer);
+    preview.set_editable(false);
+    preview.set_cursor_visible(false);
+    preview.set_left_margin(20);
+    preview.set_right_margin(20);
+    preview.set_top_margin(15);
+    let preview_scroll = ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+    preview_scroll.add(&preview);
+
+    let version_label = Label::new(None);
+    let label_ref = version_label.clone();
+    let slider = gtk::Scale::with_range(Horizontal, 0.0, ops.len() as f64, 1.0);
+    slider.set_digits(0);
+    slider.set_round_digits(0);
+
+    let snapshot = snapshot.cloned();
+    let (ops, op_times) = (ops.to_vec(), op_times.to_vec());
+    let show_version = move |slider: &gtk::Scale| {
+        let op_count = slider.value() as usize;
+        let (document, _) = timeline::version_at(snapshot.as_ref(), &ops, op_count);
+        preview_buffer.set_text("");
+        buffer_ops::restore_snapshot(&preview_buffer, &document.snapshot(""));
+
+        let description = match op_count {
+            0 => "Before the first change".to_string(),
+            _ => format!(
+                "After change {} of {}, made {}",
+                op_count,
+                ops.len(),
+                format_op_time(op_times.get(op_count - 1).copied().unwrap_or_default())
+            ),
+        };
+        label_ref.set_text(description.as_str());
+    };
+    slider.set_value(slider.adjustment().upper());
+    show_version(&slider);
+    slider.connect_value_changed(show_version);
+
+    let content_box = dialog.content_area();
+    content_box.pack_start(&preview_scroll, true, true, 10);
+    content_box.pack_start(&slider, false, false, 0);
+    content_box.pack_start(&version_label, false, false, 10);
+    dialog.show_all();
+
+    let response = dialog.run();
+    let op_count = slider.value() as usize;
+    dialog.close();
+
+    return match response {
+        gtk::ResponseType::Ok => Some((op_count, RestoreChoice::Here)),
+        response if response == RESTORE_AS_NEW_RESPONSE => {
+            Some((op_count, RestoreChoice::AsNewNote))
+        }
+        _ => None,
+    };
+}
+
+/**
+ * Adds a `color_tag_<id>` tag for every palette color, retired ones included,
+ * that the buffer doesn't know yet.
+ */
+fn register_color_tags(tb: &TextBuffer, palette: &Palette) {
+    let tag_table = tb.tag_table().unwrap();
+
+    for color in palette.all_colors() {
+        let tag_name = color.tag_name();
+        if tag_table.lookup(tag_name.as_str()).is_some() {
+            continue;
+        }
+
+        let tag = gtk::builders::TextTagBuilder::new()
+            .name(tag_name.as_str())
+            .size_points(14.0)
+            .foreground(color.color.as_str())
+            // .font("Mononoki Nerd Font Mono")
+            .font("JetBrains Mono")
+            .build();
+        tag_table.add(&tag);
+        // colors stay below the formatting tags, links and quotes bring
+        // their own foreground
+        tag.set_priority(0);
+    }
+}
+
+/// Puts a button for every color of the palette into `color_box`.
+fn fill_color_buttons(relm: &Relm<Win>, color_box: &gtk::Box, palette: &Palette) {
+    for button in color_box.children() {
+        color_box.remove(&button);
+    }
+
+    for color in &palette.colors {
+        let button = get_button_with_label(color.color.as_str());
+        let tag_name = color.tag_name();
+        connect!(
+            relm,
+            button,
+            connect_clicked(_),
+            Msg::SelectColor(tag_name.clone())
+        );
+        color_box.add(&button);
+    }
+    color_box.show_all();
+}
+
+fn load_palette() -> Palette {
+    return match Palette::load(&Palette::default_path()) {
+        Ok(palette) => palette,
+        Err(err) => {
+            show_error_dialog(format!("Couldn't read the palette: {}", err).as_str());
+            Palette::default()
+        }
+    };
+}
+
+/// Opens the per-user notebook, moving the old single note file into it.
+fn open_notebook() -> Notebook {
+    let notebook = Notebook::open_default().expect("Fatal: Cannot create the notes directory");
+
+    let legacy_note = std::path::Path::new(NOTE_FILE_NAME);
+    if legacy_note.exists() && !notebook.exists(IMPORTED_NOTE_NAME) {
+        if let Err(err) = std::fs::copy(legacy_note, notebook.path(IMPORTED_NOTE_NAME)) {
+            show_error_dialog(err.to_string().as_str());
+        }
+    }
+
+    return notebook;
+}
+
+/**
+ * Builds the find bar: the search term with its options and next/previous
+ * buttons on top, the replacement and the replace buttons below.
+ */
+fn search_bar(relm: &Relm<Win>) -> SearchWidgets {
+    let entry = gtk::SearchEntry::new();
+    entry.set_width_chars(30);
+    let replace_entry = Entry::new();
+    replace_entry.set_placeholder_text(Some("Replace with"));
+    replace_entry.set_width_chars(30);
+
+    let search = SearchWidgets {
+        bar: gtk::SearchBar::new(),
+        entry,
+        replace_entry,
+        case_sensitive: gtk::CheckButton::with_label("Match case"),
+        regex: gtk::CheckButton::with_label("Regex"),
+        whole_word: gtk::CheckButton::with_label("Whole words"),
+    };
+    search.bar.set_show_close_button(true);
+    search.bar.connect_entry(&search.entry);
+
+    let find_row = gtk::Box::new(Horizontal, 5);
+    find_row.pack_start(&search.entry, true, true, 0);
+    let previous_button = Button::with_label("Previous");
+    let next_button = Button::with_label("Next");
+    find_row.pack_start(&previous_button, false, false, 0);
+    find_row.pack_start(&next_button, false, false, 0);
+    find_row.pack_start(&search.case_sensitive, false, false, 0);
+    find_row.pack_start(&search.regex, false, false, 0);
+    find_row.pack_start(&search.whole_word, false, false, 0);
+
+    let replace_row = gtk::Box::new(Horizontal, 5);
+    replace_row.pack_start(&search.replace_entry, true, true, 0);
+    let replace_button = Button::with_label("Replace");
+    let replace_all_button = Button::with_label("Replace All");
+    replace_row.pack_start(&replace_button, false, false, 0);
+    replace_row.pack_start(&replace_all_button, false, false, 0);
+
+    let rows = gtk::Box::new(Vertical, 5);
+    rows.pack_start(&find_row, false, false, 0);
+    rows.pack_start(&replace_row, false, false, 0);
+    search.bar.add(&rows);
+
+    connect!(
+        relm,
+        search.entry,
+        connect_search_changed(_),
+        Msg::SearchChanged
+    );
+    connect!(relm, search.entry, connect_activate(_), Msg::FindNext);
+    connect!(relm, search.entry, connect_next_match(_), Msg::FindNext);
+    connect!(
+        relm,
+        search.entry,
+        connect_previous_match(_),
+        Msg::FindPrevious
+    );
+    connect!(relm, previous_button, connect_clicked(_), Msg::FindPrevious);
+    connect!(relm, next_button, connect_clicked(_), Msg::FindNext);
+    for check_button in &[&search.case_sensitive, &search.regex, &search.whole_word] {
+        connect!(relm, check_button, connect_toggled(_), Msg::SearchChanged);
+    }
+
+    connect!(
+        relm,
+        search.replace_entry,
+        connect_changed(_),
+        Msg::RefreshSearch
+    );
+    connect!(
+        relm,
+        search.replace_entry,
+        connect_activate(_),
+        Msg::Replace
+    );
+    connect!(relm, replace_button, connect_clicked(_), Msg::Replace);
+    connect!(
+        relm,
+        replace_all_button,
+        connect_clicked(_),
+        Msg::ReplaceAll
+    );
+
+    // closing the bar by any means drops the highlighted matches
+    connect!(
+        relm,
+        search.bar,
+        connect_search_mode_enabled_notify(bar),
+        if bar.is_search_mode_enabled() {
+            None
+        } else {
+            Some(Msg::CloseSearch)
+        }
+    );
+
+    return search;
+}
+
+/// What to do about a note file that was changed by another program.
+#[derive(Clone, Copy)]
+enum ExternalChange {
+    /// throw away the edits made here and open the file as it is now
+    Reload,
+    /// overwrite the file with the note as it is here
+    KeepMine,
+    /// apply the other program's edits on top of the ones made here
+    Merge,
+}
+
+/**
+ * Builds the bar that tells about changes another program made to the open
+ * note file. It stays hidden until such a change shows up.
+ */
+fn change_bar(relm: &Relm<Win>) -> gtk::InfoBar {
+    const RELOAD_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(1);
+    const KEEP_MINE_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(2);
+    const MERGE_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(3);
+
+    let bar = gtk::InfoBar::new();
+    bar.set_message_type(gtk::MessageType::Warning);
+    bar.content_area().add(&Label::new(Some(
+        "This note was changed by another program. Reload it, keep your version, or merge both?",
+    )));
+    bar.add_button("Reload", RELOAD_RESPONSE);
+    bar.add_button("Keep Mine", KEEP_MINE_RESPONSE);
+    bar.add_button("Merge", MERGE_RESPONSE);
+    bar.set_revealed(false);
+
+    connect!(relm, bar, connect_response(_, response), {
+        let choice = match response {
+            response if response == RELOAD_RESPONSE => Some(ExternalChange::Reload),
+            response if response == KEEP_MINE_RESPONSE => Some(ExternalChange::KeepMine),
+            response if response == MERGE_RESPONSE => Some(ExternalChange::Merge),
+            _ => None,
+        };
+        choice.map(Msg::ResolveExternalChange)
+    });
+    return bar;
+}
+
+/// Adds an action named `name` to `actions` that sends `msg` when activated.
+fn add_action(relm: &Relm<Win>, actions: &gio::SimpleActionGroup, name: &str, msg: fn() -> Msg) {
+    let action = gio::SimpleAction::new(name, None);
+    connect!(relm, action, connect_activate(_, _), msg());
+    actions.add_action(&action);
+}
+
+/**
+ * The actions of the window's "win" group, every keyboard shortcut activates
+ * one of them.
+ */
+fn window_actions(relm: &Relm<Win>) -> gio::SimpleActionGroup {
+    let actions = gio::SimpleActionGroup::new();
+    add_action(relm, &actions, "new-note", || Msg::NewNote);
+    add_action(relm, &actions, "save", || Msg::SaveNote);
+    add_action(relm, &actions, "find", || Msg::Find);
+    add_action(relm, &actions, "undo", || Msg::Undo);
+    add_action(relm, &actions, "redo", || Msg::Redo);
+    add_action(relm, &actions, "toggle-bold", || {
+        Msg::ToggleTag(String::from(tags::BOLD_TAG))
+    });
+    add_action(relm, &actions, "toggle-italic", || {
+        Msg::ToggleTag(String::from(tags::ITALIC_TAG))
+    });
+    add_action(relm, &actions, "show-shortcuts", || Msg::ShowShortcuts);
+
+    let select_color = gio::SimpleAction::new("select-color", Some(gtk::glib::VariantTy::INT32));
+    connect!(
+        relm,
+        select_color,
+        connect_activate(_, parameter),
+        parameter
+            .and_then(|parameter| parameter.get::<i32>())
+            .map(|idx| Msg::SelectPaletteColor(idx as usize))
+    );
+    actions.add_action(&select_color);
+
+    return actions;
+}
+
+// Create the structure that holds the widgets used in the view.
+#[derive(Clone)]
+struct Widgets {
+    buffer: TextBuffer,
+    text_view: TextView,
+    search: SearchWidgets,
+    change_bar: gtk::InfoBar,
+    color_box: gtk::Box,
+    notes_list: ListBox,
+    save_button: Button,
+    window: Window,
+}
+
+#[derive(Clone)]
+struct SearchWidgets {
+    bar: gtk::SearchBar,
+    entry: gtk::SearchEntry,
+    replace_entry: Entry,
+    case_sensitive: gtk::CheckButton,
+    regex: gtk::CheckButton,
+    whole_word: gtk::CheckButton,
+}
+
+struct Win {
+    model: Model,
+    widgets: Widgets,
+}
+
+impl Win {
+    /**
+     * Applies `op` to the document, brings the buffer up to date and appends
+     * `op` to the op log. The buffer signals it triggers are ignored while
+     * hydrating or replaying.
+     */
+    fn apply_ops(&mut self, op: Ops) {
+        self.model.document.apply(&op);
+        buffer_ops::apply(&self.widgets.buffer, &op);
+
+        if let Ops::SelectColorTag(color) = &op {
+            self.model.previous_tag = self.model.current_tag.to_string();
+            self.model.current_tag = color.to_string();
+        }
+
+        self.log_op(op);
+    }
+
+    /**
+     * Appends `op` to the op log along with the time it was applied at, and
+     * sends it to the peer when the note is shared.
+     */
+    fn log_op(&mut self, op: Ops) {
+        let edit = self
+            .model
+            .session
+            .as_mut()
+            .and_then(|session| session.replica.as_mut())
+            .and_then(|replica| replica.local(&op));
+        if let Some(edit) = edit {
+            self.send(&Message::Edit(edit));
+        }
+
+        self.model.ops.push(op);
+        self.model.op_times.push(timeline::now());
+    }
+
+    /// Marks the note as changed and (re)starts the autosave countdown.
+    fn note_changed(&mut self) {
+        self.model.is_dirty = true;
+        self.model.edit_generation += 1;
+
+        let generation = self.model.edit_generation;
+        timeout(self.model.relm.stream(), AUTOSAVE_DELAY_MS, move || {
+            Msg::Autosave(generation)
+        });
+
+        // queued, so the matches are looked for once the edit is complete
+        if self.widgets.search.bar.is_search_mode_enabled() {
+            self.model.relm.stream().emit(Msg::RefreshSearch);
+        }
+        // text typed right after the peer's cursor takes on its mark
+        if self.model.session.is_some() {
+            self.show_peer_cursor();
+        }
+    }
+
+    /// The bytes of `note`, encrypted when the open note has a passphrase.
+    fn encode_note(&self, note: &Note) -> Result<Vec<u8>, String> {
+        return note_file::encode(note)
+            .map_err(|err| err.to_string())
+            .and_then(|serialized_note| match &self.model.passphrase {
+                Some(passphrase) => crypto::encrypt(&serialized_note, passphrase.as_str())
+                    .map_err(|err| err.to_string()),
+                None => Ok(serialized_note),
+            });
+    }
+
+    /**
+     * Writes the open note to its file. Returns whether the note is safe on
+     * disk, which it isn't when writing failed or overwriting the changes of
+     * another program was declined. A locked note has nothing to write.
+     */
+    fn save_note(&mut self) -> bool {
+        if self.model.is_locked {
+            return true;
+        }
+        if self.model.has_external_change || self.is_changed_on_disk() {
+            self.show_change_bar(true);
+            let message = format!(
+                "\"{}\" was changed by another program since it was opened. Overwrite those \
+                 changes?",
+                self.model.current_note
+            );
+            if !confirm(&self.widgets.window, message.as_str()) {
+                return false;
+            }
+            self.show_change_bar(false);
+        }
+        let mut note = Note {
+            snapshot: self.model.snapshot.clone(),
+            ops: self.model.ops.clone(),
+            op_times: self.model.op_times.clone(),
+            tags: buffer_ops::tag_styles(&self.widgets.buffer),
+        };
+        let current_tag = self.model.current_tag.as_str();
+        if note.compact(&self.model.document, current_tag) {
+            self.model.snapshot = note.snapshot.clone();
+            self.model.ops.clear();
+            self.model.op_times.clear();
+        }
+        let saved = match self.encode_note(&note) {
+            Ok(serialized_note) => self
+                .model
+                .notebook
+                .write(self.model.current_note.as_str(), &serialized_note)
+                .map(|_| notebook::fingerprint(&serialized_note))
+                .map_err(|err| format!("Couldn't save the note: {}", err)),
+            Err(err) => Err(err),
+        };
+
+        match saved {
+            Ok(fingerprint) => {
+                self.model.known_fingerprint = Some(fingerprint);
+                self.model.is_dirty = false;
+                self.model.save_failed = false;
+                return true;
+            }
+            Err(err) => {
+                if !self.model.save_failed {
+                    show_error_dialog(err.as_str());
+                }
+                self.model.save_failed = true;
+                return false;
+            }
+        }
+    }
+
+    /**
+     * Asks for the passphrase of an encrypted note until it opens and keeps
+     * it for saving, plain notes come back as they are and drop it. `None`
+     * when the prompt is cancelled or the note can't be decrypted at all.
+     */
+    fn unlock(&mut self, bytes: Vec<u8>) -> Option<Vec<u8>> {
+        if !crypto::is_encrypted(&bytes) {
+            self.model.passphrase = None;
+            return Some(bytes);
+        }
+        // a note that is opened again is tried with the passphrase it had
+        if let Some(passphrase) = &self.model.passphrase {
+            if let Ok(plain) = crypto::decrypt(&bytes, passphrase.as_str()) {
+                return Some(plain);
+            }
+        }
+
+        let mut message = format!(
+            "\"{}\" is encrypted, enter its passphrase to open it.",
+            self.model.current_note
+        );
+        loop {
+            let passphrase =
+                prompt_passphrase(&self.widgets.window, "Unlock Note", message.as_str())?;
+            match crypto::decrypt(&bytes, passphrase.as_str()) {
+                Ok(plain) => {
+                    self.model.passphrase = Some(passphrase);
+                    return Some(plain);
+                }
+                Err(CryptoError::WrongPassphrase) => {
+                    message = "That passphrase doesn't open the note, try again.".to_string();
+                }
+                Err(err) => {
+                    show_error_dialog(err.to_string().as_str());
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn hydrate(&mut self) {
+        self.model.is_hydrating = true;
+
+        self.watch_note_file();
+        let read = self
+            .model
+            .notebook
+            .read(self.model.current_note.as_str())
+            .map(|buf| {
+                if !buf.is_empty() {
+                    self.model.known_fingerprint = Some(notebook::fingerprint(&buf));
+                }
+                self.unlock(buf)
+            });
+        match read {
+            Ok(None) => self.lock(),
+            Ok(Some(buf)) => match note_file::decode(&buf) {
+                Ok(note) => {
+                    buffer_ops::register_tags(&self.widgets.buffer, &note.tags);
+                    if let Some(snapshot) = &note.snapshot {
+                        self.restore_snapshot(snapshot);
+                    }
+                    self.model.snapshot = note.snapshot;
+
+                    for op in note.ops {
+                        self.apply_ops(op);
+                    }
+                    // replaying logged every op again with the time it is now
+                    self.model.op_times = note.op_times;
+                }
+                Err(err) => {
+                    show_error_dialog(err.to_string().as_str());
+                    self.lock();
+                }
+            },
+            Err(err) => {
+                show_error_dialog(err.to_string().as_str());
+                self.lock();
+            }
+        };
+
+        self.model
+            .relm
+            .stream()
+            .clone()
+            .emit(Msg::SetHydrating(false));
+        if self.widgets.search.bar.is_search_mode_enabled() {
+            self.model.relm.stream().emit(Msg::RefreshSearch);
+        }
+
+        // timeout(self.model.relm.stream(), 1000 as u32, ||  Msg::SetHydrating(false));
+    }
+
+    /// Keeps the note that couldn't be opened from being edited and saved.
+    fn lock(&mut self) {
+        self.model.is_locked = true;
+        self.widgets.text_view.set_editable(false);
+    }
+
+    /**
+     * Sets, changes or removes the passphrase of the open note and saves it
+     * right away. A note that is locked gets asked for its passphrase again.
+     */
+    fn edit_passphrase(&mut self) {
+        if self.model.is_locked {
+            let current_note = self.model.current_note.to_string();
+            self.load_note(current_note);
+            return;
+        }
+
+        let is_encrypted = self.model.passphrase.is_some();
+        self.model.passphrase = match choose_passphrase(&self.widgets.window, is_encrypted) {
+            Some(PassphraseChoice::Set(passphrase)) => Some(passphrase),
+            Some(PassphraseChoice::Remove) => None,
+            None => return,
+        };
+
+        self.model.save_failed = false;
+        self.save_note();
+        // the backups still hold the note readable without the new passphrase
+        if !self.model.save_failed {
+            if let Err(err) = self
+                .model
+                .notebook
+                .delete_backups(self.model.current_note.as_str())
+            {
+                show_error_dialog(format!("Couldn't delete the old backups: {}", err).as_str());
+            }
+        }
+    }
+
+    /**
+     * Saves the open note before something else takes its place. A note that
+     * can't be saved is only left when its changes may be thrown away,
+     * returns whether it can be left.
+     */
+    fn leave_note(&mut self) -> bool {
+        if !self.model.is_dirty {
+            return true;
+        }
+        // leaving always reports why the note couldn't be saved
+        self.model.save_failed = false;
+        if self.save_note() {
+            return true;
+        }
+
+        let message = format!(
+            "\"{}\" wasn't saved. Discard your changes to it?",
+            self.model.current_note
+        );
+        if !confirm(&self.widgets.window, message.as_str()) {
+            return false;
+        }
+        self.model.is_dirty = false;
+        return true;
+    }
+
+    /**
+     * Saves the open note and loads `name` into the buffer in its place. The
+     * open note stays when it can't be saved and its changes are kept.
+     */
+    fn switch_note(&mut self, name: String) {
+        if !self.leave_note() {
+            // the sidebar goes back to the note that stays open
+            self.refresh_notes_list();
+            return;
+        }
+        self.load_note(name);
+    }
+
+    fn load_note(&mut self, name: String) {
+        self.stop_sharing();
+        // the deletes this causes are ignored like the ones while hydrating
+        self.model.is_hydrating = true;
+        self.widgets.buffer.set_text("");
+
+        // reloading an encrypted note doesn't ask for its passphrase again
+        if name != self.model.current_note {
+            self.model.passphrase = None;
+        }
+        self.model.current_note = name;
+        self.model.snapshot = None;
+        self.model.ops.clear();
+        self.model.op_times.clear();
+        self.model.document = Document::new();
+        self.model.history = History::new();
+        self.model.is_dirty = false;
+        self.model.is_locked = false;
+        self.model.known_fingerprint = None;
+        self.show_change_bar(false);
+        self.widgets.text_view.set_editable(true);
+        self.hydrate();
+        self.refresh_notes_list();
+    }
+
+    fn refresh_notes_list(&self) {
+        let notes_list = &self.widgets.notes_list;
+        for row in notes_list.children() {
+            notes_list.remove(&row);
+        }
+
+        let names = match self.model.notebook.list() {
+            Ok(names) => names,
+            Err(err) => {
+                show_error_dialog(err.to_string().as_str());
+                return;
+            }
+        };
+
+        for name in names {
+            let row = ListBoxRow::new();
+            row.set_widget_name(name.as_str());
+            let label = Label::new(Some(name.as_str()));
+            label.set_halign(Align::Start);
+            label.set_margin_start(10);
+            label.set_margin_top(5);
+            label.set_margin_bottom(5);
+            row.add(&label);
+            notes_list.add(&row);
+
+            if name == self.model.current_note {
+                notes_list.select_row(Some(&row));
+            }
+        }
+        notes_list.show_all();
+    }
+
+    fn export_note(&self) {
+        let default_name = format!("{}.md", self.model.current_note);
+        let (path, keep_colors) =
+            match choose_export_file(&self.widgets.window, default_name.as_str()) {
+                Some(choice) => choice,
+                None => return,
+            };
+
+        let document = &self.model.document;
+        let text = document.text();
+        let runs = document.tag_runs(0, document.char_count());
+        let exported = match ExportFormat::from_path(&path) {
+            ExportFormat::Markdown => export::to_markdown(
+                text.as_str(),
+                &runs,
+                &color_tag_colors(&self.model.palette),
+                &highlight_tag_colors(),
+                &MarkdownOptions { keep_colors },
+            ),
+            ExportFormat::Html => export::to_html(
+                self.model.current_note.as_str(),
+                text.as_str(),
+                &runs,
+                &buffer_ops::tag_styles(&self.widgets.buffer),
+            ),
+            ExportFormat::PlainText => export::to_plain_text(text.as_str(), &runs),
+        };
+        if let Err(err) = std::fs::write(path, exported) {
+            show_error_dialog(format!("Couldn't export the note: {}", err).as_str());
+        }
+    }
+
+    /// Opens the find bar, searching for the selected text if there is any.
+    fn find(&mut self) {
+        let search = &self.widgets.search;
+        if let Some((start, end)) = self.widgets.buffer.selection_bounds() {
+            if start.line() == end.line() {
+                if let Some(text) = start.text(&end) {
+                    search.entry.set_text(text.as_str());
+                }
+            }
+        }
+        search.bar.set_search_mode_enabled(true);
+        search.entry.grab_focus();
+    }
+
+    fn search_options(&self) -> SearchOptions {
+        let search = &self.widgets.search;
+        return SearchOptions {
+            case_sensitive: search.case_sensitive.is_active(),
+            regex: search.regex.is_active(),
+            whole_word: search.whole_word.is_active(),
+        };
+    }
+
+    /**
+     * Looks for the search term again and highlights every match. A regex
+     * that doesn't compile marks the search entry instead.
+     */
+    fn refresh_search(&mut self) {
+        let tb = &self.widgets.buffer;
+        let search = &self.widgets.search;
+        tb.remove_tag_by_name(tags::SEARCH_MATCH_TAG, &tb.start_iter(), &tb.end_iter());
+
+        let found = search::find_matches(
+            self.model.document.text().as_str(),
+            search.entry.text().as_str(),
+            &self.search_options(),
+            search.replace_entry.text().as_str(),
+        );
+        let style_context = search.entry.style_context();
+        self.model.search_matches = match found {
+            Ok(matches) => {
+                style_context.remove_class("error");
+                search.entry.set_tooltip_text(None);
+                matches
+            }
+            Err(err) => {
+                style_context.add_class("error");
+                search.entry.set_tooltip_text(Some(err.as_str()));
+                vec![]
+            }
+        };
+
+        for found in &self.model.search_matches {
+            tb.apply_tag_by_name(
+                tags::SEARCH_MATCH_TAG,
+                &tb.iter_at_offset(found.start_offset),
+                &tb.iter_at_offset(found.end_offset),
+            );
+        }
+    }
+
+    fn clear_search(&mut self) {
+        let tb = &self.widgets.buffer;
+        tb.remove_tag_by_name(tags::SEARCH_MATCH_TAG, &tb.start_iter(), &tb.end_iter());
+        self.model.search_matches.clear();
+    }
+
+    /// The match that is selected right now, if any.
+    fn selected_match(&self) -> Option<usize> {
+        let (start, end) = self.widgets.buffer.selection_bounds()?;
+        return self.model.search_matches.iter().position(|found| {
+            found.start_offset == start.offset() && found.end_offset == end.offset()
+        });
+    }
+
+    /**
+     * Selects the first match after the cursor, or the last one before it
+     * when going `backwards`, wrapping around at the ends of the note.
+     */
+    fn select_match(&mut self, backwards: bool) {
+        let tb = &self.widgets.buffer;
+        let (selection_start, selection_end) = match tb.selection_bounds() {
+            Some((start, end)) => (start.offset(), end.offset()),
+            None => (tb.cursor_position(), tb.cursor_position()),
+        };
+
+        let matches = &self.model.search_matches;
+        let next = if backwards {
+            matches
+                .iter()
+                .rev()
+                .find(|found| found.end_offset <= selection_start)
+                .or_else(|| matches.last())
+        } else {
+            matches
+                .iter()
+                .find(|found| found.start_offset >= selection_end)
+                .or_else(|| matches.first())
+        };
+
+        if let Some(found) = next {
+            let mut start = tb.iter_at_offset(found.start_offset);
+            tb.select_range(&start, &tb.iter_at_offset(found.end_offset));
+            self.widgets
+                .text_view
+                .scroll_to_iter(&mut start, 0.1, false, 0.0, 0.0);
+        }
+    }
+
+    /**
+     * The ops that put `found.replacement` in place of the match. The new
+     * text takes the color and the other tags of the first replaced char.
+     */
+    fn replace_ops(&self, found: &SearchMatch) -> Vec<Ops> {
+        let tag_names = self
+            .model
+            .document
+            .tag_runs(found.start_offset, found.start_offset + 1)
+            .into_iter()
+            .map(|(tag_name, _, _)| tag_name)
+            .collect::<Vec<String>>();
+        let color_tag = tag_names
+            .iter()
+            .find(|tag_name| tag_name.starts_with("color_tag_"))
+            .cloned()
+            .unwrap_or_else(|| self.model.current_tag.to_string());
+        let replacement_end = found.start_offset + found.replacement.chars().count() as i32;
+
+        let mut ops = vec![Ops::Delete((found.start_offset, found.end_offset))];
+        if !found.replacement.is_empty() {
+            ops.push(Ops::Insert(InsertOpsData::new(
+                found.start_offset,
+                found.replacement.to_string(),
+                color_tag.to_string(),
+            )));
+            for tag_name in tag_names
+                .into_iter()
+                .filter(|tag_name| *tag_name != color_tag)
+            {
+                ops.push(Ops::ApplyTag((
+                    tag_name,
+                    found.start_offset,
+                    replacement_end,
+                )));
+            }
+        }
+        return ops;
+    }
+
+    /// Replaces the selected match and moves on to the next one.
+    fn replace(&mut self) {
+        let idx = match self.selected_match() {
+            Some(idx) => idx,
+            None => return self.select_match(false),
+        };
+
+        let ops = self.replace_ops(&self.model.search_matches[idx]);
+        let inverse_ops = self.apply_edit(ops);
+        self.model.history.record_inverse(inverse_ops);
+        self.note_changed();
+
+        self.refresh_search();
+        self.select_match(false);
+    }
+
+    /// Replaces every match as a single undo step.
+    fn replace_all(&mut self) {
+        // from the end, so the offsets of the matches before stay valid
+        let ops = self
+            .model
+            .search_matches
+            .iter()
+            .rev()
+            .flat_map(|found| self.replace_ops(found))
+            .collect::<Vec<Ops>>();
+        if ops.is_empty() {
+            return;
+        }
+
+        let inverse_ops = self.apply_edit(ops);
+        self.model.history.record_inverse(inverse_ops);
+        self.note_changed();
+        self.refresh_search();
+    }
+
+    fn show_shortcuts(&self) {
+        let shortcuts = shortcuts::shortcuts(self.model.palette.colors.len());
+        match shortcuts::shortcuts_window(&shortcuts) {
+            Some(window) => {
+                window.set_transient_for(Some(&self.widgets.window));
+                window.show_all();
+            }
+            None => show_error_dialog("Couldn't build the keyboard shortcuts window"),
+        }
+    }
+
+    /**
+     * Opens the history viewer on the op log of the open note and restores
+     * the version picked there. The log only goes back to the last time it
+     * was compacted.
+     */
+    fn show_history(&mut self) {
+        if self.model.is_locked {
+            return;
+        }
+        if self.model.ops.is_empty() {
+            show_error_dialog("The note hasn't changed since its history was last compacted");
+            return;
+        }
+
+        let tag_table = self
+            .widgets
+            .buffer
+            .tag_table()
+            .expect("Couldn't get hold of a tag table!");
+        let (op_count, choice) = match run_history_viewer(
+            &self.widgets.window,
+            &tag_table,
+            self.model.snapshot.as_ref(),
+            &self.model.ops,
+            &self.model.op_times,
+        ) {
+            Some(picked) => picked,
+            None => return,
+        };
+        let (version, current_tag) =
+            timeline::version_at(self.model.snapshot.as_ref(), &self.model.ops, op_count);
+
+        match choice {
+            RestoreChoice::Here => {
+                let ops = timeline::restore_ops(&self.model.document, &version);
+                if ops.is_empty() {
+                    return;
+                }
+                let inverse_ops = self.apply_edit(ops);
+                self.model.history.record_inverse(inverse_ops);
+                self.note_changed();
+            }
+            RestoreChoice::AsNewNote => {
+                let current_tag = current_tag.unwrap_or_else(|| self.model.current_tag.clone());
+                self.restore_as_new_note(version.snapshot(current_tag.as_str()));
+            }
+        }
+    }
+
+    /**
+     * Saves `snapshot` as a note named after the open one and opens it. The
+     * copy of an encrypted note is encrypted with the same passphrase.
+     */
+    fn restore_as_new_note(&mut self, snapshot: Snapshot) {
+        if !self.leave_note() {
+            return;
+        }
+        let note = Note::new(
+            Some(snapshot),
+            vec![],
+            buffer_ops::tag_styles(&self.widgets.buffer),
+        );
+        let serialized_note = match self.encode_note(&note) {
+            Ok(serialized_note) => serialized_note,
+            Err(err) => {
+                show_error_dialog(err.as_str());
+                return;
+            }
+        };
+
+        let notebook = &self.model.notebook;
+        let base_name = format!("{} restored", self.model.current_note);
+        let restored = notebook
+            .create_named(base_name.as_str())
+            .or_else(|_| notebook.create())
+            .and_then(|name| {
+                notebook.write(name.as_str(), &serialized_note)?;
+                Ok(name)
+            });
+
+        match restored {
+            Ok(name) => self.switch_note(name),
+            Err(err) => show_error_dialog(format!("Couldn't restore the note: {}", err).as_str()),
+        }
+    }
+
+    /// Starts sharing the open note or joining a shared one, or stops sharing.
+    fn share_note(&mut self) {
+        if self.model.session.is_some() {
+            if confirm(&self.widgets.window, "Stop sharing this note?") {
+                self.stop_sharing();
+            }
+            return;
+        }
+        if self.model.is_locked {
+            return;
+        }
+        let (role, address) = match choose_share(&self.widgets.window) {
+            Some(choice) => choice,
+            None => return,
+        };
+
+        let endpoint = collab::Endpoint::parse(address.as_str());
+        self.model.session_id += 1;
+        let id = self.model.session_id;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // the connection's thread hands its events over to the main loop
+        let (sender, receiver) = gtk::glib::MainContext::channel(gtk::glib::PRIORITY_DEFAULT);
+        let relm_stream = self.model.relm.stream().clone();
+        receiver.attach(None, move |event| {
+            relm_stream.emit(Msg::Session(id, event));
+            gtk::glib::Continue(true)
+        });
+        let on_event = move |event: collab::Event| {
+            // nobody listens anymore once the main loop is gone
+            let _ = sender.send(event);
+        };
+
+        let stream = match role {
+            ShareRole::Host => match collab::Listener::bind(&endpoint) {
+                Ok(listener) => {
+                    let status = format!("waiting for a peer on {}", listener.address());
+                    self.set_sharing_status(Some(status.as_str()));
+                    collab::host(listener, stop.clone(), on_event);
+                    None
+                }
+                Err(err) => {
+                    show_error_dialog(format!("Couldn't share the note: {}", err).as_str());
+                    return;
+                }
+            },
+            ShareRole::Join => match collab::join(&endpoint, stop.clone(), on_event) {
+                Ok(stream) => {
+                    self.set_sharing_status(Some(format!("joining {}", address).as_str()));
+                    Some(stream)
+                }
+                Err(err) => {
+                    show_error_dialog(format!("Couldn't join the note: {}", err).as_str());
+                    return;
+                }
+            },
+        };
+
+        self.model.session = Some(Session {
+            id,
+            replica: None,
+            stream,
+            stop,
+            peer_cursor: None,
+            sent_cursor: None,
+        });
+    }
+
+    fn session_event(&mut self, id: u64, event: collab::Event) {
+        // left over from a session that has ended
+        if self.model.session.as_ref().map(|session| session.id) != Some(id) {
+            return;
+        }
+
+        match event {
+            collab::Event::Connected(stream) => {
+                let replica = Replica::new(collab::HOST_SITE, &self.model.document);
+                let hello = Message::Hello {
+                    name: self.model.current_note.to_string(),
+                    state: replica.state(),
+                };
+                if let Some(session) = &mut self.model.session {
+                    session.replica = Some(replica);
+                    session.stream = Some(stream);
+                }
+                self.send(&hello);
+                self.set_sharing_status(Some("shared with a peer"));
+                self.send_cursor();
+            }
+            collab::Event::Received(Message::Hello { name, state }) => {
+                self.join_shared_note(name, state)
+            }
+            collab::Event::Received(Message::Edit(edit)) => {
+                let ops = match self
+                    .model
+                    .session
+                    .as_mut()
+                    .and_then(|session| session.replica.as_mut())
+                    .map(|replica| replica.remote(&edit))
+                {
+                    Some(Some(ops)) => ops,
+                    Some(None) => {
+                        self.stop_sharing();
+                        show_error_dialog("The shared note got out of step with the peer");
+                        return;
+                    }
+                    None => return,
+                };
+                self.apply_remote_ops(ops);
+            }
+            collab::Event::Received(Message::Cursor(anchor)) => {
+                if let Some(session) = &mut self.model.session {
+                    session.peer_cursor = Some(anchor);
+                }
+                self.show_peer_cursor();
+            }
+            collab::Event::Closed(reason) => {
+                self.stop_sharing();
+                if let Some(reason) = reason {
+                    show_error_dialog(reason.as_str());
+                }
+            }
+        }
+    }
+
+    /// Opens the note the host shares as a new note and starts following it.
+    fn join_shared_note(&mut self, name: String, state: Vec<collab::Element>) {
+        let mut session = match self.model.session.take() {
+            Some(session) if session.replica.is_none() => session,
+            // only the host says hello, and only once
+            session => {
+                self.model.session = session;
+                return;
+            }
+        };
+
+        if !self.leave_note() {
+            self.model.session = Some(session);
+            self.stop_sharing();
+            return;
+        }
+        let created = self
+            .model
+            .notebook
+            .create_named(name.as_str())
+            .or_else(|_| self.model.notebook.create());
+        let name = match created {
+            Ok(name) => name,
+            Err(err) => {
+                self.model.session = Some(session);
+                self.stop_sharing();
+                show_error_dialog(format!("Couldn't join the note: {}", err).as_str());
+                return;
+            }
+        };
+        self.switch_note(name);
+
+        let replica = Replica::join(collab::GUEST_SITE, state);
+        let ops = timeline::restore_ops(&self.model.document, &replica.document());
+        session.replica = Some(replica);
+        self.model.session = Some(session);
+        self.apply_remote_ops(ops);
+        self.set_sharing_status(Some("shared with a peer"));
+        self.send_cursor();
+    }
+
+    /**
+     * Applies ops that bring the note up to date with the peer. They are
+     * logged like any other, but not sent back.
+     */
+    fn apply_remote_ops(&mut self, ops: Vec<Ops>) {
+        self.model.is_replaying = true;
+        for op in ops {
+            self.model.document.apply(&op);
+            buffer_ops::apply(&self.widgets.buffer, &op);
+            // the undo steps hold offsets from before the peer's edit
+            self.model.history.transform(&op);
+            self.model.ops.push(op);
+            self.model.op_times.push(timeline::now());
+        }
+        self.model
+            .relm
+            .stream()
+            .clone()
+            .emit(Msg::SetReplaying(false));
+
+        self.note_changed();
+    }
+
+    /// Sends `message` to the peer, a broken connection ends the sharing.
+    fn send(&mut self, message: &Message) {
+        let sent = match self
+            .model
+            .session
+            .as_mut()
+            .and_then(|session| session.stream.as_mut())
+        {
+            Some(stream) => stream.send(message),
+            None => return,
+        };
+        if let Err(err) = sent {
+            self.stop_sharing();
+            show_error_dialog(format!("Sharing the note stopped: {}", err).as_str());
+        }
+    }
+
+    /// Tells the peer where the cursor is, if it moved since the last time.
+    fn send_cursor(&mut self) {
+        let cursor_position = self.widgets.buffer.cursor_position();
+        let anchor = match &mut self.model.session {
+            Some(Session {
+                replica: Some(replica),
+                sent_cursor,
+                ..
+            }) => {
+                let anchor = replica.anchor(cursor_position);
+                if *sent_cursor == Some(anchor) {
+                    return;
+                }
+                *sent_cursor = Some(anchor);
+                anchor
+            }
+            _ => return,
+        };
+        self.send(&Message::Cursor(anchor));
+    }
+
+    /**
+     * Marks the char after the peer's cursor and puts a visible mark where
+     * the cursor is. Without a peer, both are taken off.
+     */
+    fn show_peer_cursor(&self) {
+        let tb = &self.widgets.buffer;
+        tb.remove_tag_by_name(tags::PEER_CURSOR_TAG, &tb.start_iter(), &tb.end_iter());
+
+        let offset = match &self.model.session {
+            Some(Session {
+                replica: Some(replica),
+                peer_cursor: Some(anchor),
+                ..
+            }) => replica.anchor_offset(*anchor),
+            _ => {
+                if tb.mark(PEER_CURSOR_MARK).is_some() {
+                    tb.delete_mark_by_name(PEER_CURSOR_MARK);
+                }
+                return;
+            }
+        };
+
+        let iter = tb.iter_at_offset(offset);
+        match tb.mark(PEER_CURSOR_MARK) {
+            Some(mark) => tb.move_mark(&mark, &iter),
+            None => {
+                if let Some(mark) = tb.create_mark(Some(PEER_CURSOR_MARK), &iter, false) {
+                    mark.set_visible(true);
+                }
+            }
+        }
+        tb.apply_tag_by_name(tags::PEER_CURSOR_TAG, &iter, &tb.iter_at_offset(offset + 1));
+    }
+
+    fn stop_sharing(&mut self) {
+        if let Some(session) = self.model.session.take() {
+            session.stop.store(true, Ordering::Relaxed);
+            if let Some(stream) = &session.stream {
+                stream.shutdown();
+            }
+            self.show_peer_cursor();
+            self.set_sharing_status(None);
+        }
+    }
+
+    fn set_sharing_status(&self, status: Option<&str>) {
+        let title = match status {
+            Some(status) => format!("Pretty Notes ({})", status),
+            None => "Pretty Notes".to_string(),
+        };
+        self.widgets.window.set_title(title.as_str());
+    }
+
+    /**
+     * Watches the file of the open note in place of the one watched so far.
+     * Other programs writing it show up as `Msg::NoteFileChanged`, and so
+     * do our own saves, which `known_fingerprint` tells apart.
+     */
+    fn watch_note_file(&mut self) {
+        if let Some(monitor) = self.model.file_monitor.take() {
+            monitor.cancel();
+        }
+
+        let path = self.model.notebook.path(self.model.current_note.as_str());
+        // a note that can't be watched still opens, saving checks it anyway
+        let monitor = match gio::File::for_path(path)
+            .monitor_file(gio::FileMonitorFlags::NONE, gio::Cancellable::NONE)
+        {
+            Ok(monitor) => monitor,
+            Err(_) => return,
+        };
+        // files are saved by renaming a new one over them, which shows up as
+        // the file being created again
+        connect!(
+            self.model.relm,
+            monitor,
+            connect_changed(_, _, _, event),
+            match event {
+                gio::FileMonitorEvent::ChangesDoneHint | gio::FileMonitorEvent::Created => {
+                    Some(Msg::NoteFileChanged)
+                }
+                _ => None,
+            }
+        );
+        self.model.file_monitor = Some(monitor);
+    }
+
+    /// The fingerprint of the note file as it is now, `None` when it's gone.
+    fn disk_fingerprint(&self) -> Option<u64> {
+        return match self.model.notebook.read(self.model.current_note.as_str()) {
+            Ok(bytes) if !bytes.is_empty() => Some(notebook::fingerprint(&bytes)),
+            _ => None,
+        };
+    }
+
+    /// Whether another program wrote the note file since we last did.
+    fn is_changed_on_disk(&self) -> bool {
+        return match self.disk_fingerprint() {
+            Some(fingerprint) => Some(fingerprint) != self.model.known_fingerprint,
+            None => false,
+        };
+    }
+
+    /// Shows or hides the change bar, autosaving waits while it's shown.
+    fn show_change_bar(&mut self, is_shown: bool) {
+        self.model.has_external_change = is_shown;
+        self.widgets.change_bar.set_revealed(is_shown);
+    }
+
+    fn resolve_external_change(&mut self, choice: ExternalChange) {
+        match choice {
+            ExternalChange::Reload => {
+                let current_note = self.model.current_note.to_string();
+                self.load_note(current_note);
+            }
+            ExternalChange::KeepMine => {
+                self.model.known_fingerprint = self.disk_fingerprint();
+                self.show_change_bar(false);
+                self.model.save_failed = false;
+                self.save_note();
+            }
+            ExternalChange::Merge => self.merge_external_change(),
+        }
+    }
+
     /**
-     * should probably use a hashmap here
+     * Applies the edits another program made to the note file on top of the
+     * ones made here as one undoable edit, and saves the result. The edits
+     * made since the op logs parted ways are merged like those of a shared
+     * note, so a note the other program compacted can't be merged.
      */
-    bold_italic_tag_state: BoldItalicTagState,
-    // italic_tag_state: TextTagState,
-}
+    fn merge_external_change(&mut self) {
+        let bytes = match self.model.notebook.read(self.model.current_note.as_str()) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                show_error_dialog(format!("Couldn't read the note: {}", err).as_str());
+                return;
+            }
+        };
+        let plain = match &self.model.passphrase {
+            _ if !crypto::is_encrypted(&bytes) => Ok(bytes.clone()),
+            Some(passphrase) => {
+                crypto::decrypt(&bytes, passphrase.as_str()).map_err(|err| err.to_string())
+            }
+            // the note was plain when it was opened here
+            None => Err("The other program encrypted the note, reload it to open it".to_string()),
+        };
+        let theirs = match plain
+            .and_then(|plain| note_file::decode(&plain).map_err(|err| err.to_string()))
+        {
+            Ok(note) => note,
+            Err(err) => {
+                show_error_dialog(err.as_str());
+                return;
+            }
+        };
+        if theirs.snapshot != self.model.snapshot {
+            show_error_dialog(
+                "The other program compacted the note, so it can only be reloaded or \
+                 overwritten with your version",
+            );
+            return;
+        }
 
-#[derive(Msg)]
-enum Msg {
-    Quit,
-    InsertText(InsertTextEventData),
-    DeleteText(DeleteTextEventData),
-    SelectColor(String),
-    SaveNote,
-    Hydrate,
-    SetHydrating(bool),
-    // UpdateTagState((bool, i32)),
-    // UpdateBoldItalicTagState(),
-}
+        let shared_op_count = self
+            .model
+            .ops
+            .iter()
+            .zip(&theirs.ops)
+            .take_while(|(mine, their)| mine == their)
+            .count();
+        let (base, _) = timeline::version_at(
+            self.model.snapshot.as_ref(),
+            &self.model.ops,
+            shared_op_count,
+        );
+        let ops = collab::merge(
+            &base,
+            &theirs.ops[shared_op_count..],
+            &self.model.ops[shared_op_count..],
+        );
 
-fn get_button_with_label(name: &str) -> Button {
-    let button = Button::new();
-    button.set_widget_name(name);
-    button.set_size_request(140, 50);
-    button.set_margin_start(10);
-    button.set_margin_end(10);
-    button.set_border_width(0);
-    button.set_label(name);
-    return button;
-}
+        buffer_ops::register_tags(&self.widgets.buffer, &theirs.tags);
+        if !ops.is_empty() {
+            let inverse_ops = self.apply_edit(ops);
+            self.model.history.record_inverse(inverse_ops);
+            self.note_changed();
+        }
 
-fn show_error_dialog(error_msg: &str) {
-    let dialog = gtk::Dialog::new();
-    dialog.set_title("Error");
-    dialog.add_button("Okay", gtk::ResponseType::Ok);
-    dialog.set_valign(Align::Center);
-    dialog.connect_response(|d, _r| {
-        d.close();
-    });
-    dialog.set_size_request(400, 250);
-    let content_box = dialog.content_area();
-    let error_label = Label::new(Some(error_msg));
-    content_box.pack_start(&error_label, false, false, 0);
+        self.model.known_fingerprint = Some(notebook::fingerprint(&bytes));
+        self.show_change_bar(false);
+        self.model.save_failed = false;
+        self.save_note();
+    }
 
-    dialog.show_all();
-}
+    fn edit_palette(&mut self) {
+        let palette = match run_palette_editor(&self.widgets.window, &self.model.palette) {
+            Some(palette) => palette,
+            None => return,
+        };
 
-// Create the structure that holds the widgets used in the view.
-#[derive(Clone)]
-struct Widgets {
-    buffer: TextBuffer,
-    save_button: Button,
-    window: Window,
-}
+        if let Err(err) = palette.save(&Palette::default_path()) {
+            show_error_dialog(format!("Couldn't save the palette: {}", err).as_str());
+        }
+        register_color_tags(&self.widgets.buffer, &palette);
+        fill_color_buttons(&self.model.relm, &self.widgets.color_box, &palette);
+        self.model.palette = palette;
+    }
 
-struct Win {
-    model: Model,
-    widgets: Widgets,
-}
+    /// Turns a Markdown file into a new note and opens it.
+    fn import_note(&mut self) {
+        let path = match choose_import_file(&self.widgets.window) {
+            Some(path) => path,
+            None => return,
+        };
+        if !self.leave_note() {
+            return;
+        }
+        let markdown = match std::fs::read_to_string(&path) {
+            Ok(markdown) => markdown,
+            Err(err) => {
+                show_error_dialog(format!("Couldn't read {}: {}", path.display(), err).as_str());
+                return;
+            }
+        };
 
-impl Win {
-    fn apply_ops(&mut self, op: Ops) {
-        let tb = &self.widgets.buffer;
-        let tag_table = self
-            .widgets
-            .buffer
-            .tag_table()
-            .expect("Couldn't get hold of a tag table!");
+        let ops = import::markdown_to_ops(markdown.as_str(), self.model.current_tag.as_str());
+        let base_name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
 
-        match op {
-            Ops::Insert(insert_ops_data) => {
-                self.model
-                    .relm
-                    .stream()
-                    .clone()
-                    .emit(Msg::SelectColor(self.model.current_tag.clone()));
+        let tags = buffer_ops::tag_styles(&self.widgets.buffer);
+        let mut note = Note::new(None, ops, tags);
+        note.op_times = vec![timeline::now(); note.ops.len()];
+        let serialized_note = match note_file::encode(&note) {
+            Ok(serialized_note) => serialized_note,
+            Err(err) => {
+                show_error_dialog(err.to_string().as_str());
+                return;
+            }
+        };
+
+        // file names that aren't valid note names fall back to "Untitled"
+        let notebook = &self.model.notebook;
+        let imported = notebook
+            .create_named(base_name.as_str())
+            .or_else(|_| notebook.create())
+            .and_then(|name| {
+                notebook.write(name.as_str(), &serialized_note)?;
+                Ok(name)
+            });
+
+        match imported {
+            Ok(name) => self.switch_note(name),
+            Err(err) => show_error_dialog(format!("Couldn't import the note: {}", err).as_str()),
+        }
+    }
+
+    /**
+     * Text inserted into a heading, list item or quote takes on the block
+     * tags of its line, up to the end of the line.
+     */
+    fn line_format_ops(&self, offset: i32, content: &str) -> Vec<Ops> {
+        let document = &self.model.document;
+        let line_start = document.line_start(offset);
+        if line_start == offset {
+            return vec![];
+        }
+
+        let line_len = match content.find('\n') {
+            Some(idx) => content[..=idx].chars().count(),
+            None => content.chars().count(),
+        } as i32;
 
-                tb.insert_at_cursor(insert_ops_data.content.as_str());
+        return document
+            .tags_at(line_start)
+            .into_iter()
+            .filter(|tag_name| tags::BLOCK_TAGS.contains(&tag_name.as_str()))
+            .map(|tag_name| Ops::ApplyTag((tag_name.to_string(), offset, offset + line_len)))
+            .collect();
+    }
 
-                let tag = tag_table
-                    .lookup(insert_ops_data.tag.as_str())
-                    .expect("Fatal: Cannot find tag color_tag_1");
+    /// Runs the Markdown formatter for `inserted`, a grapheme at `offset`.
+    fn markdown_formatting_ops(&self, offset: i32, inserted: &str) -> Vec<Ops> {
+        let document = &self.model.document;
+        let line_start = document.line_start(offset);
+        // hidden link urls count towards the offsets too
+        let line_before = document.slice(line_start, offset);
 
-                let cursor_offset = tb.cursor_position();
+        return tags::format_insert(line_before.as_str(), line_start, inserted);
+    }
 
-                tb.apply_tag(
-                    &tag,
-                    &tb.iter_at_offset(cursor_offset - 1),
-                    &tb.iter_at_offset(cursor_offset),
-                );
-            }
-            Ops::Delete(offsets) => {
-                let (start_offset, end_offset) = offsets;
-                tb.delete(
-                    &mut tb.iter_at_offset(start_offset),
-                    &mut tb.iter_at_offset(end_offset),
-                );
+    /**
+     * Formats inserted text the way it would have been formatted had it been
+     * typed, every grapheme goes through the Markdown formatter in turn so
+     * pasted text gets its markers converted too. Returns the inverse of all
+     * conversions.
+     */
+    fn format_inserted_text(&mut self, offset: i32, content: &str) -> Vec<Ops> {
+        let graphemes = content.graphemes(true).collect::<Vec<&str>>();
+        let mut offset = offset;
+        let mut inverse = vec![];
+
+        for (idx, grapheme) in graphemes.iter().enumerate() {
+            let mut ops = self.markdown_formatting_ops(offset, grapheme);
+            offset += grapheme.chars().count() as i32;
+            if ops.is_empty() {
+                continue;
             }
-            Ops::MoveCursor(position) => self
-                .widgets
-                .buffer
-                .place_cursor(&tb.iter_at_offset(position)),
-            Ops::SelectColorTag(color) => {
-                self.model.previous_tag = self.model.current_tag.to_string();
-                self.model.current_tag = color.to_string();
+
+            // markers are only ever removed in front of the grapheme
+            offset -= ops
+                .iter()
+                .map(|op| match op {
+                    Ops::Delete((start_offset, end_offset)) => end_offset - start_offset,
+                    _ => 0,
+                })
+                .sum::<i32>();
+
+            // a line that became a heading, list item or quote takes the
+            // rest of its inserted text along
+            let mut rest_of_line = 0;
+            for rest in &graphemes[idx + 1..] {
+                rest_of_line += rest.chars().count() as i32;
+                if rest.ends_with('\n') {
+                    break;
+                }
             }
-            Ops::ApplyTag((tag_name, start_offset, end_offset)) => {
-                let tag = tag_table
-                    .lookup(tag_name.as_str())
-                    .expect("Fatal: Cannot find tag color_tag_1");
+            let block_ops = ops
+                .iter()
+                .filter_map(|op| match op {
+                    Ops::ApplyTag((tag_name, _, end_offset))
+                        if rest_of_line > 0 && tags::BLOCK_TAGS.contains(&tag_name.as_str()) =>
+                    {
+                        Some(Ops::ApplyTag((
+                            tag_name.to_string(),
+                            *end_offset,
+                            end_offset + rest_of_line,
+                        )))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<Ops>>();
+            ops.extend(block_ops);
+
+            let mut op_inverse = self.apply_edit(ops);
+            op_inverse.append(&mut inverse);
+            inverse = op_inverse;
+        }
 
-                tb.apply_tag(
-                    &tag,
-                    &tb.iter_at_offset(start_offset),
-                    &tb.iter_at_offset(end_offset),
-                );
+        if graphemes.len() > 1 {
+            let ops = self.boundary_formatting_ops(offset);
+            if !ops.is_empty() {
+                let mut op_inverse = self.apply_edit(ops);
+                op_inverse.append(&mut inverse);
+                inverse = op_inverse;
             }
         }
+
+        return inverse;
+    }
+
+    /// Closes a marker that inserted text ends with right before `offset`.
+    fn boundary_formatting_ops(&self, offset: i32) -> Vec<Ops> {
+        let document = &self.model.document;
+        let line_start = document.line_start(offset);
+        let line_before = document.slice(line_start, offset);
+        let line_after = document.slice(offset, document.line_end(offset));
+
+        return tags::format_boundary(
+            line_before.as_str(),
+            line_start,
+            line_after.graphemes(true).next(),
+        );
+    }
+
+    /**
+     * Gives `start_offset..end_offset` the tag `tag_name`, taking any other
+     * tag whose name starts with `tag_prefix` off of it. With `None` those
+     * tags are only taken off.
+     */
+    fn replace_tag_ops(
+        &self,
+        tag_prefix: &str,
+        tag_name: Option<&str>,
+        start_offset: i32,
+        end_offset: i32,
+    ) -> Vec<Ops> {
+        let mut conflicting_tags = self
+            .model
+            .document
+            .tag_runs(start_offset, end_offset)
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .filter(|name| name.starts_with(tag_prefix) && Some(name.as_str()) != tag_name)
+            .collect::<Vec<String>>();
+        conflicting_tags.sort();
+        conflicting_tags.dedup();
+
+        let mut ops = conflicting_tags
+            .into_iter()
+            .map(|name| Ops::RemoveTag((name, start_offset, end_offset)))
+            .collect::<Vec<Ops>>();
+        if let Some(tag_name) = tag_name {
+            ops.push(Ops::ApplyTag((
+                tag_name.to_string(),
+                start_offset,
+                end_offset,
+            )));
+        }
+        return ops;
+    }
+
+    /// Removes `tag_name` if all of `start_offset..end_offset` has it, applies
+    /// it to the whole range otherwise.
+    fn toggle_tag_op(&self, tag_name: &str, start_offset: i32, end_offset: i32) -> Ops {
+        let tagged_chars: i32 = self
+            .model
+            .document
+            .tag_runs(start_offset, end_offset)
+            .into_iter()
+            .filter(|(name, _, _)| name == tag_name)
+            .map(|(_, s, e)| e - s)
+            .sum();
+
+        if tagged_chars == end_offset - start_offset {
+            return Ops::RemoveTag((tag_name.to_string(), start_offset, end_offset));
+        }
+        return Ops::ApplyTag((tag_name.to_string(), start_offset, end_offset));
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+        self.model.document.restore_snapshot(snapshot);
+        buffer_ops::restore_snapshot(&self.widgets.buffer, snapshot);
+
+        self.model.previous_tag = self.model.current_tag.to_string();
+        self.model.current_tag = snapshot.current_tag.to_string();
+    }
+
+    /**
+     * Computes the ops that revert `op` against the current state, so it has
+     * to be called before `op` is applied.
+     */
+    fn inverse_ops(&self, op: &Ops) -> Vec<Ops> {
+        match op {
+            Ops::MoveCursor(_) => vec![Ops::MoveCursor(self.widgets.buffer.cursor_position())],
+            Ops::SelectColorTag(_) => vec![Ops::SelectColorTag(self.model.current_tag.clone())],
+            _ => self.model.document.inverse_ops(op),
+        }
+    }
+
+    /**
+     * Applies ops produced by the app rather than typed by the user and
+     * returns the ops that revert them.
+     */
+    fn apply_edit(&mut self, ops: Vec<Ops>) -> Vec<Ops> {
+        self.model.is_replaying = true;
+        let inverse = history::apply_reverting(self, ops, Win::inverse_ops, Win::apply_ops);
+
+        // queued behind the buffer signals emitted by the ops above
+        self.model
+            .relm
+            .stream()
+            .clone()
+            .emit(Msg::SetReplaying(false));
+
+        return inverse;
     }
 }
 
@@ -152,181 +2379,288 @@ impl Update for Win {
     type Msg = Msg;
 
     fn model(relm: &Relm<Self>, _: ()) -> Model {
+        let notebook = open_notebook();
+        let palette = load_palette();
+        let current_note = match notebook.list() {
+            Ok(names) if !names.is_empty() => names[0].to_string(),
+            _ => notebook
+                .create()
+                .expect("Fatal: Cannot create a note in the notes directory"),
+        };
+
         Model {
-            current_tag: String::from("color_tag_1"),
-            previous_tag: String::from("color_tag_1"),
+            notebook,
+            current_note,
+            current_tag: palette.first_tag_name(),
+            previous_tag: palette.first_tag_name(),
+            palette,
+            snapshot: None,
             ops: vec![],
+            op_times: vec![],
+            document: Document::new(),
+            history: History::new(),
+            is_dirty: false,
+            edit_generation: 0,
+            save_failed: false,
             is_hydrating: true,
+            is_replaying: false,
+            passphrase: None,
+            is_locked: false,
+            search_matches: vec![],
+            session: None,
+            session_id: 0,
+            file_monitor: None,
+            known_fingerprint: None,
+            has_external_change: false,
             relm: relm.clone(),
-            // italic_tag_state: TextTagState::new(TagType::Italic, '*'),
-            bold_italic_tag_state: BoldItalicTagState::new(
-                "italic".to_string(),
-                "bold".to_string(),
-            ),
         }
     }
 
     fn update(&mut self, event: Msg) {
-        let tb = &self.widgets.buffer;
+        let tb = self.widgets.buffer.clone();
 
         match event {
             Msg::SelectColor(color) => {
-                self.model.ops.push(Ops::SelectColorTag(color.to_string()));
+                // a selection is recolored on top of choosing the color for
+                // what gets typed next
+                if let Some((start, end)) = tb.selection_bounds() {
+                    let ops = self.replace_tag_ops(
+                        "color_tag_",
+                        Some(color.as_str()),
+                        start.offset(),
+                        end.offset(),
+                    );
+                    let inverse_ops = self.apply_edit(ops);
+                    self.model.history.record_inverse(inverse_ops);
+                }
+
+                self.log_op(Ops::SelectColorTag(color.to_string()));
+                self.model.history.break_group();
                 self.model.previous_tag = self.model.current_tag.clone();
                 self.model.current_tag = color;
+                self.note_changed();
+            }
+            Msg::ToggleTag(tag_name) => {
+                if let Some((start, end)) = tb.selection_bounds() {
+                    let op = self.toggle_tag_op(tag_name.as_str(), start.offset(), end.offset());
+                    let inverse_ops = self.apply_edit(vec![op]);
+                    self.model.history.record_inverse(inverse_ops);
+                    self.note_changed();
+                }
+            }
+            Msg::SelectPaletteColor(idx) => {
+                if let Some(color) = self.model.palette.colors.get(idx) {
+                    let tag_name = color.tag_name();
+                    self.update(Msg::SelectColor(tag_name));
+                }
+            }
+            Msg::Find => self.find(),
+            Msg::SearchChanged => {
+                self.refresh_search();
+                self.select_match(false);
+            }
+            Msg::RefreshSearch => self.refresh_search(),
+            Msg::FindNext => self.select_match(false),
+            Msg::FindPrevious => self.select_match(true),
+            Msg::Replace => self.replace(),
+            Msg::ReplaceAll => self.replace_all(),
+            Msg::CloseSearch => self.clear_search(),
+            Msg::ShowShortcuts => self.show_shortcuts(),
+            Msg::Highlight(highlight) => {
+                if let Some((start, end)) = tb.selection_bounds() {
+                    let ops = self.replace_tag_ops(
+                        "highlight_tag_",
+                        highlight.as_deref(),
+                        start.offset(),
+                        end.offset(),
+                    );
+                    if !ops.is_empty() {
+                        let inverse_ops = self.apply_edit(ops);
+                        self.model.history.record_inverse(inverse_ops);
+                        self.note_changed();
+                    }
+                }
             }
             Msg::SetHydrating(hydrating) => {
                 self.model.is_hydrating = hydrating;
             }
+            Msg::SetReplaying(replaying) => {
+                self.model.is_replaying = replaying;
+            }
+            Msg::Undo => {
+                if let Some(step) = self.model.history.undo() {
+                    let redo_ops = self.apply_edit(step.inverse_ops());
+                    self.model.history.push_redo(redo_ops);
+                    self.note_changed();
+                }
+            }
+            Msg::Redo => {
+                if let Some(step) = self.model.history.redo() {
+                    let undo_ops = self.apply_edit(step.inverse_ops());
+                    self.model.history.push_undo(undo_ops);
+                    self.note_changed();
+                }
+            }
             Msg::InsertText(insert_text_data) => {
-                if self.model.is_hydrating == false {
-                    let tag_table = tb.tag_table().expect("Couldn't get hold of a tag table!");
-
-                    let tag = tag_table
-                        .lookup(self.model.current_tag.as_str())
-                        .expect("Fatal: Cannot find tag color_tag_1");
-
-                    tb.apply_tag(
-                        &tag,
-                        &tb.iter_at_offset(insert_text_data.offset),
-                        &tb.iter_at_offset(insert_text_data.offset + 1),
+                if self.model.is_hydrating == false && self.model.is_replaying == false {
+                    // the buffer has the text already, only the document
+                    // has to catch up
+                    let op = buffer_ops::record_insert(
+                        &tb,
+                        &insert_text_data,
+                        self.model.current_tag.as_str(),
                     );
-
-                    let tagged_string = if self.model.bold_italic_tag_state.single_asterisk_active {
-                        println!(
-                            "start offset = {}, end-offset = {}",
-                            self.model.bold_italic_tag_state.start_offset, insert_text_data.offset
-                        );
-                        tb.text(
-                            &tb.iter_at_offset(self.model.bold_italic_tag_state.start_offset + 1),
-                            &tb.iter_at_offset(insert_text_data.offset - 1),
-                            false,
-                        )
-                        .expect("Error while trying to read gtk buffer to find tagged_string")
-                        .to_string()
-                    } else {
-                        println!("in the else block, single asterisk is not active");
-                        "".to_string()
-                    };
-
-                    let s_offset = self.model.bold_italic_tag_state.start_offset;
-
-                    let (_, operations_to_perform) = self.model.bold_italic_tag_state.update_state(
+                    self.model.document.apply(&op);
+                    self.log_op(op);
+                    self.model.history.record_insert(
                         insert_text_data.offset,
-                        tagged_string,
                         insert_text_data.content.to_string(),
                     );
+                    self.note_changed();
 
-                    for ops in operations_to_perform {
-                        self.apply_ops(ops);
+                    // tags only, the inserted text's undo step removes them too
+                    for op in self
+                        .line_format_ops(insert_text_data.offset, insert_text_data.content.as_str())
+                    {
+                        self.apply_ops(op);
                     }
 
-                    // if let Some(tag_name) = tag_to_apply {
-                    //     let tag = tag_table
-                    //         .lookup(tag_name.as_str())
-                    //         .expect("Fatal: Cannot find tag");
-                    //
-                    //     let text_to_apply_tag = tb.get_text(
-                    //         &tb.get_iter_at_offset(
-                    //             s_offset + if tag_name.as_str() == "bold" { 2 } else { 1 },
-                    //         ),
-                    //         &tb.get_iter_at_offset(
-                    //             insert_text_data.offset + 1
-                    //                 - if tag_name.as_str() == "bold" { 2 } else { 1 },
-                    //         ),
-                    //         true,
-                    //     );
-                    //
-                    //     println!("text to apply tag = {}", text_to_apply_tag.unwrap());
-                    //     tb.apply_tag(
-                    //         &tag,
-                    //         &tb.get_iter_at_offset(
-                    //             s_offset + if tag_name.as_str() == "bold" { 2 } else { 1 },
-                    //         ),
-                    //         &tb.get_iter_at_offset(
-                    //             insert_text_data.offset + 1
-                    //                 - if tag_name.as_str() == "bold" { 2 } else { 1 },
-                    //         ),
-                    //     );
-                    //
-                    //     tb.delete(
-                    //         &mut tb.get_iter_at_offset(s_offset),
-                    //         &mut tb.get_iter_at_offset(
-                    //             s_offset + if tag_name.as_str() == "bold" { 2 } else { 1 },
-                    //         ),
-                    //     );
-                    //
-                    //     let text_to_delete = tb
-                    //         .get_text(
-                    //             &tb.get_iter_at_offset(
-                    //                 insert_text_data.offset
-                    //                     - if tag_name.as_str() == "bold" { 3 } else { 2 },
-                    //             ),
-                    //             &tb.get_iter_at_offset(insert_text_data.offset),
-                    //             true,
-                    //         )
-                    //         .unwrap();
-                    //
-                    //     println!("Text to delete ${}$", text_to_delete);
-                    //
-                    //     tb.delete(
-                    //         &mut tb.get_iter_at_offset(
-                    //             insert_text_data.offset
-                    //                 - if tag_name.as_str() == "bold" { 3 } else { 2 },
-                    //         ),
-                    //         &mut tb.get_iter_at_offset(insert_text_data.offset + 1),
-                    //     );
-                    // }
-                }
-
-                self.model.ops.push(Ops::Insert(InsertOpsData::new(
-                    String::from(insert_text_data.content),
-                    self.model.current_tag.to_string(),
-                )));
+                    // the conversion is its own undo step, undoing it brings
+                    // back the literal markers
+                    let inverse_ops = self.format_inserted_text(
+                        insert_text_data.offset,
+                        insert_text_data.content.as_str(),
+                    );
+                    if !inverse_ops.is_empty() {
+                        self.model.history.record_inverse(inverse_ops);
+                    }
+                }
             }
-            Msg::DeleteText(delete_text_event_data) => self.model.ops.push(Ops::Delete((
-                delete_text_event_data.start_offset,
-                delete_text_event_data.end_offset,
-            ))),
-            Msg::SaveNote => match bincode::serialize(&self.model.ops) {
-                Ok(serialized_note) => {
-                    std::fs::write(NOTE_FILE_NAME, serialized_note);
+            Msg::DeleteText(delete_text_event_data) => {
+                if self.model.is_hydrating == false && self.model.is_replaying == false {
+                    let op = buffer_ops::record_delete(&delete_text_event_data);
+                    self.model.document.apply(&op);
+                    self.log_op(op);
+                    self.model.history.record_delete(
+                        delete_text_event_data.start_offset,
+                        delete_text_event_data.end_offset,
+                        delete_text_event_data.content,
+                        delete_text_event_data.tags,
+                    );
+                    self.note_changed();
                 }
-                Err(err) => {
-                    show_error_dialog(err.to_string().as_str());
+            }
+            Msg::SaveNote => {
+                // an explicit save always reports its outcome
+                self.model.save_failed = false;
+                self.save_note();
+            }
+            Msg::Autosave(generation) => {
+                // a note changed by another program waits for the choice on
+                // the change bar
+                if generation == self.model.edit_generation
+                    && self.model.is_dirty
+                    && !self.model.has_external_change
+                {
+                    self.save_note();
                 }
-            },
+            }
             Msg::Hydrate => {
-                match File::open(NOTE_FILE_NAME) {
-                    Ok(mut file) => {
-                        let mut buf: Vec<u8> = vec![];
-                        match file.read_to_end(&mut buf) {
-                            Ok(_size) => {
-                                let ops = bincode::deserialize::<Vec<Ops>>(&buf)
-                                    .expect("Error trying to deserialize binary data");
-                                for op in ops {
-                                    self.apply_ops(op);
-                                }
-                            }
-                            Err(err) => {
-                                show_error_dialog(err.to_string().as_str());
-                            }
+                self.hydrate();
+                self.refresh_notes_list();
+            }
+            Msg::OpenNote(name) => {
+                if name != self.model.current_note {
+                    self.switch_note(name);
+                }
+            }
+            Msg::NewNote => {
+                if self.leave_note() {
+                    match self.model.notebook.create() {
+                        Ok(name) => self.switch_note(name),
+                        Err(err) => show_error_dialog(err.to_string().as_str()),
+                    }
+                }
+            }
+            Msg::RenameNote => {
+                let current_note = self.model.current_note.to_string();
+                if let Some(new_name) =
+                    prompt_text(&self.widgets.window, "Rename Note", current_note.as_str())
+                {
+                    // the unsaved ops would otherwise end up with a file
+                    // that never got them
+                    if self.model.is_dirty && !self.save_note() {
+                        return;
+                    }
+                    match self
+                        .model
+                        .notebook
+                        .rename(current_note.as_str(), new_name.trim())
+                    {
+                        Ok(()) => {
+                            self.model.current_note = new_name.trim().to_string();
+                            self.watch_note_file();
+                            self.refresh_notes_list();
                         }
+                        Err(err) => show_error_dialog(err.to_string().as_str()),
                     }
-                    Err(err) => {
+                }
+            }
+            Msg::DeleteNote => {
+                let current_note = self.model.current_note.to_string();
+                let message = format!("Delete \"{}\"? This cannot be undone.", current_note);
+                if confirm(&self.widgets.window, message.as_str()) {
+                    if let Err(err) = self.model.notebook.delete(current_note.as_str()) {
                         show_error_dialog(err.to_string().as_str());
+                        return;
                     }
-                };
-
-                self.model
-                    .relm
-                    .stream()
-                    .clone()
-                    .emit(Msg::SetHydrating(false));
 
-                // timeout(self.model.relm.stream(), 1000 as u32, ||  Msg::SetHydrating(false));
+                    let next_note = match self.model.notebook.list() {
+                        Ok(names) if !names.is_empty() => Ok(names[0].to_string()),
+                        _ => self.model.notebook.create(),
+                    };
+                    match next_note {
+                        // nothing is left to save for the deleted note
+                        Ok(name) => self.load_note(name),
+                        Err(err) => show_error_dialog(err.to_string().as_str()),
+                    }
+                }
+            }
+            Msg::DuplicateNote => {
+                // the copy should include what hasn't been saved yet
+                if !self.leave_note() {
+                    return;
+                }
+                match self
+                    .model
+                    .notebook
+                    .duplicate(self.model.current_note.as_str())
+                {
+                    Ok(name) => self.switch_note(name),
+                    Err(err) => show_error_dialog(err.to_string().as_str()),
+                }
+            }
+            Msg::ShowHistory => self.show_history(),
+            Msg::ExportNote => self.export_note(),
+            Msg::ImportNote => self.import_note(),
+            Msg::EditPalette => self.edit_palette(),
+            Msg::EditPassphrase => self.edit_passphrase(),
+            Msg::ShareNote => self.share_note(),
+            Msg::Session(id, event) => self.session_event(id, event),
+            Msg::CursorMoved => self.send_cursor(),
+            Msg::NoteFileChanged => {
+                if !self.model.is_locked && self.is_changed_on_disk() {
+                    self.show_change_bar(true);
+                }
+            }
+            Msg::ResolveExternalChange(choice) => self.resolve_external_change(choice),
+            Msg::Quit => {
+                // the window stays open while the note has unsaved changes
+                // that aren't to be thrown away
+                if self.leave_note() {
+                    gtk::main_quit()
+                }
             }
-            Msg::Quit => gtk::main_quit(),
         }
     }
 }
@@ -344,9 +2678,30 @@ impl Widget for Win {
         // Create the view using the normal GTK+ method calls.
 
         let hbox = Box::new(Horizontal, 0);
+        let sidebar = Box::new(Vertical, 5);
+        sidebar.set_size_request(200, -1);
         let button_box = gtk::Box::new(Vertical, 10);
         button_box.set_size_request(220, 220);
 
+        let notes_list = ListBox::new();
+        notes_list.set_selection_mode(gtk::SelectionMode::Single);
+        let notes_scroll = ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+        notes_scroll.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Automatic);
+        notes_scroll.add(&notes_list);
+
+        let note_actions = gtk::Box::new(Horizontal, 0);
+        let new_note_button = Button::with_label("New");
+        let rename_note_button = Button::with_label("Rename");
+        let duplicate_note_button = Button::with_label("Duplicate");
+        let delete_note_button = Button::with_label("Delete");
+        note_actions.pack_start(&new_note_button, true, true, 0);
+        note_actions.pack_start(&rename_note_button, true, true, 0);
+        note_actions.pack_start(&duplicate_note_button, true, true, 0);
+        note_actions.pack_start(&delete_note_button, true, true, 0);
+
+        sidebar.pack_start(&notes_scroll, true, true, 0);
+        sidebar.pack_end(&note_actions, false, false, 5);
+
         let tv = TextView::new();
         let buffer = match tv.buffer() {
             Some(buffer) => buffer,
@@ -356,62 +2711,178 @@ impl Widget for Win {
             }
         };
 
-        let btn1 = get_button_with_label(COLORS[0]);
-        let btn2 = get_button_with_label(COLORS[1]);
-        let btn3 = get_button_with_label(COLORS[2]);
+        let color_box = gtk::Box::new(Vertical, 10);
+        fill_color_buttons(relm, &color_box, &model.palette);
+        // the highlighter marks the selected text, whatever its color
+        let highlight_box = gtk::Box::new(Horizontal, 5);
+        highlight_box.set_homogeneous(true);
+        highlight_box.set_margin_start(10);
+        highlight_box.set_margin_end(10);
+        let highlight_buttons = HIGHLIGHTS
+            .iter()
+            .map(|color| {
+                let button = Button::new();
+                button.set_widget_name(color);
+                button.set_tooltip_text(Some(color));
+                button.set_size_request(-1, 30);
+                paint_button(&button, color);
+                highlight_box.add(&button);
+                button
+            })
+            .collect::<Vec<Button>>();
+        let clear_highlight_button = get_button_with_label("No Highlight");
+        let bold_button = get_button_with_label("Bold");
+        let italic_button = get_button_with_label("Italic");
         let save_button = get_button_with_label("Save Note");
+        let export_button = get_button_with_label("Export…");
+        let import_button = get_button_with_label("Import…");
+        let history_button = get_button_with_label("History…");
+        let share_button = get_button_with_label("Share…");
+        let shortcuts_button = get_button_with_label("Shortcuts");
+        let passphrase_button = get_button_with_label("Passphrase…");
+        let palette_button = get_button_with_label("Palette…");
 
         tv.set_left_margin(20);
         tv.set_right_margin(20);
         tv.set_top_margin(15);
         tv.set_bottom_margin(15);
 
-        button_box.add(&btn1);
-        button_box.add(&btn2);
-        button_box.add(&btn3);
+        button_box.add(&color_box);
+        button_box.add(&palette_button);
+        button_box.add(&highlight_box);
+        button_box.add(&clear_highlight_button);
+        button_box.add(&bold_button);
+        button_box.add(&italic_button);
+        button_box.pack_end(&shortcuts_button, false, false, 0);
+        button_box.pack_end(&passphrase_button, false, false, 0);
+        button_box.pack_end(&share_button, false, false, 0);
+        button_box.pack_end(&history_button, false, false, 0);
+        button_box.pack_end(&import_button, false, false, 0);
+        button_box.pack_end(&export_button, false, false, 0);
         button_box.pack_end(&save_button, false, false, 10);
+        hbox.pack_start(&sidebar, false, false, 0);
         hbox.pack_start(&button_box, false, false, 0);
-        hbox.pack_start(&tv, true, true, 0);
+        let search = search_bar(relm);
+        let change_bar = change_bar(relm);
+        let editor_box = gtk::Box::new(Vertical, 0);
+        editor_box.pack_start(&change_bar, false, false, 0);
+        editor_box.pack_start(&search.bar, false, false, 0);
+        editor_box.pack_start(&tv, true, true, 0);
+        hbox.pack_start(&editor_box, true, true, 0);
 
         let window = Window::new(WindowType::Toplevel);
         window.set_title("Pretty Notes");
-        window.set_size_request(600, 500);
+        window.set_size_request(800, 500);
 
         window.add(&hbox);
         window.show_all();
 
-        // TODO: move this into a sepaate function
-        let mut color_tags = COLORS
-            .to_vec()
-            .iter()
-            .enumerate()
-            .map(|(idx, color)| {
-                gtk::builders::TextTagBuilder::new()
-                    .name(format!("color_tag_{}", idx + 1).as_str())
-                    .size_points(14.0)
-                    .foreground(*color)
-                    // .font("Mononoki Nerd Font Mono")
-                    .font("JetBrains Mono")
-                    .build()
-            })
-            .collect::<Vec<TextTag>>();
-
+        let mut format_tags = Vec::<TextTag>::new();
         let italic_tag = gtk::builders::TextTagBuilder::new()
-            .name("italic")
+            .name(tags::ITALIC_TAG)
             .style(gtk::pango::Style::Italic)
             .build();
         let bold_tag = gtk::builders::TextTagBuilder::new()
-            .name("bold")
+            .name(tags::BOLD_TAG)
             .weight(600)
             .build();
-        color_tags.push(italic_tag);
-        color_tags.push(bold_tag);
+        let strikethrough_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::STRIKETHROUGH_TAG)
+            .strikethrough(true)
+            .build();
+        let code_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::CODE_TAG)
+            .family("monospace")
+            .background("#EEEEEE")
+            .build();
+        let link_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::LINK_TAG)
+            .foreground("#3584E4")
+            .underline(gtk::pango::Underline::Single)
+            .build();
+        let link_url_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::LINK_URL_TAG)
+            .invisible(true)
+            .build();
+        let heading_1_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::HEADING_1_TAG)
+            .scale(1.8)
+            .weight(700)
+            .pixels_above_lines(12)
+            .build();
+        let heading_2_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::HEADING_2_TAG)
+            .scale(1.4)
+            .weight(700)
+            .pixels_above_lines(8)
+            .build();
+        let list_item_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::LIST_ITEM_TAG)
+            .left_margin(40)
+            .indent(-16)
+            .build();
+        let list_marker_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::LIST_MARKER_TAG)
+            .weight(700)
+            .build();
+        let blockquote_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::BLOCKQUOTE_TAG)
+            .left_margin(40)
+            .foreground("#888888")
+            .style(gtk::pango::Style::Italic)
+            .build();
+        let block_marker_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::BLOCK_MARKER_TAG)
+            .invisible(true)
+            .build();
+        let rule_tag = gtk::builders::TextTagBuilder::new()
+            .name(tags::RULE_TAG)
+            .foreground("#BBBBBB")
+            .strikethrough(true)
+            .justification(gtk::Justification::Center)
+            .build();
+        format_tags.push(italic_tag);
+        format_tags.push(bold_tag);
+        format_tags.push(strikethrough_tag);
+        format_tags.push(code_tag);
+        format_tags.push(link_tag);
+        format_tags.push(link_url_tag);
+        format_tags.push(heading_1_tag);
+        format_tags.push(heading_2_tag);
+        format_tags.push(list_item_tag);
+        format_tags.push(list_marker_tag);
+        format_tags.push(blockquote_tag);
+        format_tags.push(block_marker_tag);
+        format_tags.push(rule_tag);
+
+        format_tags.push(
+            gtk::builders::TextTagBuilder::new()
+                .name(tags::PEER_CURSOR_TAG)
+                .background("#99C1F1")
+                .build(),
+        );
+        // last, so the current match shows on top of any other background
+        format_tags.push(
+            gtk::builders::TextTagBuilder::new()
+                .name(tags::SEARCH_MATCH_TAG)
+                .background("#F6D32D")
+                .build(),
+        );
 
         let tag_table = buffer.tag_table().unwrap();
 
-        for tag in &color_tags {
+        for tag in &format_tags {
             tag_table.add(tag);
         }
+        register_color_tags(&buffer, &model.palette);
+        for (tag_name, color) in highlight_tag_colors() {
+            tag_table.add(
+                &gtk::builders::TextTagBuilder::new()
+                    .name(tag_name.as_str())
+                    .background(color.as_str())
+                    .build(),
+            );
+        }
 
         relm.stream().clone().emit(Msg::Hydrate);
 
@@ -419,43 +2890,124 @@ impl Widget for Win {
             relm,
             buffer,
             connect_insert_text(_, iter, content),
-            Msg::InsertText(InsertTextEventData::new(iter.offset(), content))
+            Msg::InsertText(buffer_ops::insert_event_data(iter, content))
         );
 
         connect!(
             relm,
             buffer,
-            connect_delete_range(_, s_itr, e_itr),
-            Msg::DeleteText(DeleteTextEventData::new(s_itr.offset(), e_itr.offset()))
+            connect_delete_range(buf, s_itr, e_itr),
+            Msg::DeleteText(buffer_ops::delete_event_data(buf, s_itr, e_itr))
+        );
+        connect!(
+            relm,
+            buffer,
+            connect_cursor_position_notify(_),
+            Msg::CursorMoved
         );
 
+        // shortcuts go through the window's actions, ahead of the text view
+        let actions = window_actions(relm);
+        window.insert_action_group("win", Some(&actions));
+        let shortcut_list = shortcuts::shortcuts(shortcuts::MAX_COLOR_SHORTCUTS);
+        window.connect_key_press_event(move |window, key| {
+            // text entries keep their own editing keys, Ctrl+Z in the find
+            // entry isn't meant for the note
+            let entry_has_focus = window
+                .focused_widget()
+                .map_or(false, |widget| widget.is::<gtk::Entry>());
+            if entry_has_focus {
+                return Inhibit(false);
+            }
+
+            match shortcuts::matching_shortcut(&shortcut_list, key) {
+                Some(shortcut) => {
+                    let target = shortcut.target.map(|target| target.to_variant());
+                    actions.activate_action(shortcut.action, target.as_ref());
+                    Inhibit(true)
+                }
+                None => Inhibit(false),
+            }
+        });
+
+        connect!(relm, palette_button, connect_clicked(_), Msg::EditPalette);
+        for (idx, button) in highlight_buttons.iter().enumerate() {
+            let tag_name = format!("highlight_tag_{}", idx + 1);
+            connect!(
+                relm,
+                button,
+                connect_clicked(_),
+                Msg::Highlight(Some(tag_name.clone()))
+            );
+        }
         connect!(
             relm,
-            btn1,
+            clear_highlight_button,
             connect_clicked(_),
-            Msg::SelectColor(String::from("color_tag_1"))
+            Msg::Highlight(None)
         );
-
         connect!(
             relm,
-            btn2,
+            bold_button,
             connect_clicked(_),
-            Msg::SelectColor(String::from("color_tag_2"))
+            Msg::ToggleTag(String::from(tags::BOLD_TAG))
         );
-
         connect!(
             relm,
-            btn3,
+            italic_button,
             connect_clicked(_),
-            Msg::SelectColor(String::from("color_tag_3"))
+            Msg::ToggleTag(String::from(tags::ITALIC_TAG))
         );
         connect!(relm, save_button, connect_clicked(_), Msg::SaveNote);
+        connect!(relm, export_button, connect_clicked(_), Msg::ExportNote);
+        connect!(relm, import_button, connect_clicked(_), Msg::ImportNote);
+        connect!(relm, history_button, connect_clicked(_), Msg::ShowHistory);
+        connect!(relm, share_button, connect_clicked(_), Msg::ShareNote);
+        connect!(
+            relm,
+            shortcuts_button,
+            connect_clicked(_),
+            Msg::ShowShortcuts
+        );
+        connect!(
+            relm,
+            passphrase_button,
+            connect_clicked(_),
+            Msg::EditPassphrase
+        );
+
+        connect!(
+            relm,
+            notes_list,
+            connect_row_selected(_, row),
+            row.map(|row| Msg::OpenNote(row.widget_name().to_string()))
+        );
+        connect!(relm, new_note_button, connect_clicked(_), Msg::NewNote);
+        connect!(
+            relm,
+            rename_note_button,
+            connect_clicked(_),
+            Msg::RenameNote
+        );
+        connect!(
+            relm,
+            duplicate_note_button,
+            connect_clicked(_),
+            Msg::DuplicateNote
+        );
+        connect!(
+            relm,
+            delete_note_button,
+            connect_clicked(_),
+            Msg::DeleteNote
+        );
 
         connect!(
             relm,
             window,
             connect_delete_event(_, _),
-            return (Some(Msg::Quit), Inhibit(false))
+            // closing is up to `Msg::Quit`
+            return (Some(Msg::Quit), Inhibit(true))
         );
 
         Win {
@@ -463,6 +3015,11 @@ impl Widget for Win {
             widgets: Widgets {
                 window,
                 buffer,
+                text_view: tv,
+                search,
+                change_bar,
+                color_box,
+                notes_list,
                 save_button,
             },
         }
@@ -470,5 +3027,11 @@ impl Widget for Win {
 }
 
 fn main() {
+    // any arguments mean the command line interface, without opening a window
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     Win::run(()).expect("Win::run failed");
 }
@@ -0,0 +1,650 @@
+use crate::document::Document;
+use crate::export::TagStyle;
+use crate::text_ops::{Ops, TagRun};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Every note file starts with these bytes followed by the format version as
+/// a little endian u32, the bincode encoded payload comes after that.
+const MAGIC: &[u8; 4] = b"PNTS";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+/// Files written before the header existed are treated as this version.
+const HEADERLESS_VERSION: u32 = 1;
+pub const FORMAT_VERSION: u32 = 6;
+
+/// Saving folds the op log into the snapshot once it holds more ops than this.
+pub const COMPACTION_THRESHOLD: usize = 1000;
+
+/// The buffer contents at the time the op log was last compacted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub text: String,
+    pub tags: Vec<TagRun>,
+    pub current_tag: String,
+}
+
+/// The time of ops that were logged before times were kept.
+pub const UNKNOWN_TIME: u64 = 0;
+
+/**
+ * A saved note, the ops are replayed on top of the snapshot. `op_times` has
+ * the time every op was applied at, in milliseconds since the Unix epoch.
+ * `tags` holds the look of the tags the note was written with, so it can
+ * still be drawn when the app doesn't define them anymore.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Note {
+    pub snapshot: Option<Snapshot>,
+    pub ops: Vec<Ops>,
+    pub op_times: Vec<u64>,
+    pub tags: Vec<(String, TagStyle)>,
+}
+
+impl Note {
+    /// A note whose ops were applied at an unknown time.
+    pub fn new(snapshot: Option<Snapshot>, ops: Vec<Ops>, tags: Vec<(String, TagStyle)>) -> Self {
+        return Note {
+            snapshot,
+            op_times: vec![UNKNOWN_TIME; ops.len()],
+            ops,
+            tags,
+        };
+    }
+
+    /**
+     * Replaces the snapshot with `document` and drops the op log once it is
+     * longer than `COMPACTION_THRESHOLD`. `document` has to be what the
+     * snapshot and ops lead to. Returns whether the note was compacted.
+     */
+    pub fn compact(&mut self, document: &Document, current_tag: &str) -> bool {
+        if self.ops.len() <= COMPACTION_THRESHOLD {
+            return false;
+        }
+        self.snapshot = Some(document.snapshot(current_tag));
+        self.ops.clear();
+        self.op_times.clear();
+        return true;
+    }
+}
+
+pub enum NoteFileError {
+    UnsupportedVersion(u32),
+    Corrupt(bincode::Error),
+}
+
+impl fmt::Display for NoteFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoteFileError::UnsupportedVersion(version) => write!(
+                f,
+                "The note was saved by a newer version of Pretty Notes (format version {}, \
+                 this version reads up to {})",
+                version, FORMAT_VERSION
+            ),
+            NoteFileError::Corrupt(err) => write!(f, "The note file is damaged: {}", err),
+        }
+    }
+}
+
+impl From<bincode::Error> for NoteFileError {
+    fn from(err: bincode::Error) -> Self {
+        NoteFileError::Corrupt(err)
+    }
+}
+
+pub fn encode(note: &Note) -> Result<Vec<u8>, NoteFileError> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend(bincode::serialize(note)?);
+    return Ok(bytes);
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Note, NoteFileError> {
+    // a note that was created but never saved
+    if bytes.is_empty() {
+        return Ok(Note::new(None, vec![], vec![]));
+    }
+
+    if bytes.len() >= HEADER_LEN && bytes.starts_with(MAGIC) {
+        let mut version = [0; 4];
+        version.copy_from_slice(&bytes[MAGIC.len()..HEADER_LEN]);
+        return migrate(u32::from_le_bytes(version), &bytes[HEADER_LEN..]);
+    }
+
+    return migrate(HEADERLESS_VERSION, bytes);
+}
+
+/**
+ * Decodes a payload written in `version` and upgrades it to the current
+ * `Note`. Every old version converts into the one after it, so adding a
+ * format version only needs a frozen copy of the types it replaces.
+ */
+fn migrate(version: u32, payload: &[u8]) -> Result<Note, NoteFileError> {
+    match version {
+        1 => {
+            let ops = bincode::deserialize::<Vec<v1::Ops>>(payload)?
+                .into_iter()
+                .map(v3::Ops::from)
+                .collect();
+            Ok(v3::upgrade(v3::Note {
+                snapshot: None,
+                ops,
+            }))
+        }
+        // a bare op log without a snapshot
+        2 => {
+            let ops = bincode::deserialize::<Vec<v3::Ops>>(payload)?;
+            Ok(v3::upgrade(v3::Note {
+                snapshot: None,
+                ops,
+            }))
+        }
+        3 => Ok(v3::upgrade(bincode::deserialize::<v3::Note>(payload)?)),
+        4 => Ok(v4::upgrade(bincode::deserialize::<v4::Note>(payload)?)),
+        5 => Ok(v5::upgrade(bincode::deserialize::<v5::Note>(payload)?)),
+        FORMAT_VERSION => Ok(bincode::deserialize::<Note>(payload)?),
+        _ => Err(NoteFileError::UnsupportedVersion(version)),
+    }
+}
+
+/// The headerless format, from before `Ops::RemoveTag` existed.
+mod v1 {
+    use super::v3;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub enum Ops {
+        Insert(v3::InsertOpsData),
+        Delete((i32, i32)),
+        MoveCursor(i32),
+        SelectColorTag(String),
+        ApplyTag((String, i32, i32)),
+    }
+
+    impl From<Ops> for v3::Ops {
+        fn from(op: Ops) -> Self {
+            match op {
+                Ops::Insert(data) => v3::Ops::Insert(data),
+                Ops::Delete(offsets) => v3::Ops::Delete(offsets),
+                Ops::MoveCursor(position) => v3::Ops::MoveCursor(position),
+                Ops::SelectColorTag(color) => v3::Ops::SelectColorTag(color),
+                Ops::ApplyTag(tag) => v3::Ops::ApplyTag(tag),
+            }
+        }
+    }
+}
+
+/// Inserts without an offset, replayed wherever the cursor happened to be.
+mod v3 {
+    use crate::text_ops::{self, InsertOpsData as CurrentInsertOpsData};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct Snapshot {
+        pub text: String,
+        pub tags: Vec<(String, i32, i32)>,
+        pub current_tag: String,
+    }
+
+    impl From<Snapshot> for super::Snapshot {
+        fn from(snapshot: Snapshot) -> Self {
+            super::Snapshot {
+                text: snapshot.text,
+                tags: snapshot.tags,
+                current_tag: snapshot.current_tag,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct InsertOpsData {
+        pub content: String,
+        pub tag: String,
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub enum Ops {
+        Insert(InsertOpsData),
+        Delete((i32, i32)),
+        MoveCursor(i32),
+        SelectColorTag(String),
+        ApplyTag((String, i32, i32)),
+        RemoveTag((String, i32, i32)),
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct Note {
+        pub snapshot: Option<Snapshot>,
+        pub ops: Vec<Ops>,
+    }
+
+    /**
+     * Gives every insert the offset it used to land at, by tracking the
+     * cursor the same way the buffer did when these notes were replayed.
+     */
+    pub fn upgrade(note: Note) -> super::Note {
+        let mut cursor = note
+            .snapshot
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.text.chars().count() as i32);
+        let mut len = cursor;
+
+        let ops = note
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                Ops::Insert(data) => {
+                    let inserted_chars = data.content.chars().count() as i32;
+                    let offset = cursor;
+                    cursor += inserted_chars;
+                    len += inserted_chars;
+                    text_ops::Ops::Insert(CurrentInsertOpsData::new(offset, data.content, data.tag))
+                }
+                Ops::Delete((start_offset, end_offset)) => {
+                    let start_offset = start_offset.max(0).min(len);
+                    let end_offset = end_offset.max(start_offset).min(len);
+                    if cursor > start_offset {
+                        cursor -= cursor.min(end_offset) - start_offset;
+                    }
+                    len -= end_offset - start_offset;
+                    text_ops::Ops::Delete((start_offset, end_offset))
+                }
+                Ops::MoveCursor(position) => {
+                    cursor = position.max(0).min(len);
+                    text_ops::Ops::MoveCursor(position)
+                }
+                Ops::SelectColorTag(color) => text_ops::Ops::SelectColorTag(color),
+                Ops::ApplyTag(tag) => text_ops::Ops::ApplyTag(tag),
+                Ops::RemoveTag(tag) => text_ops::Ops::RemoveTag(tag),
+            })
+            .collect();
+
+        return super::Note::new(note.snapshot.map(super::Snapshot::from), ops, vec![]);
+    }
+}
+
+/// Notes without a tag table, drawn with whatever tags the app defines.
+mod v4 {
+    use super::v3;
+    use crate::text_ops;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct InsertOpsData {
+        pub offset: i32,
+        pub content: String,
+        pub tag: String,
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub enum Ops {
+        Insert(InsertOpsData),
+        Delete((i32, i32)),
+        MoveCursor(i32),
+        SelectColorTag(String),
+        ApplyTag((String, i32, i32)),
+        RemoveTag((String, i32, i32)),
+    }
+
+    impl From<Ops> for text_ops::Ops {
+        fn from(op: Ops) -> Self {
+            match op {
+                Ops::Insert(data) => text_ops::Ops::Insert(text_ops::InsertOpsData::new(
+                    data.offset,
+                    data.content,
+                    data.tag,
+                )),
+                Ops::Delete(offsets) => text_ops::Ops::Delete(offsets),
+                Ops::MoveCursor(position) => text_ops::Ops::MoveCursor(position),
+                Ops::SelectColorTag(color) => text_ops::Ops::SelectColorTag(color),
+                Ops::ApplyTag(tag) => text_ops::Ops::ApplyTag(tag),
+                Ops::RemoveTag(tag) => text_ops::Ops::RemoveTag(tag),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct Note {
+        pub snapshot: Option<v3::Snapshot>,
+        pub ops: Vec<Ops>,
+    }
+
+    pub fn upgrade(note: Note) -> super::Note {
+        return super::Note::new(
+            note.snapshot.map(super::Snapshot::from),
+            note.ops.into_iter().map(text_ops::Ops::from).collect(),
+            vec![],
+        );
+    }
+}
+
+/// Notes whose op log doesn't say when each op was applied.
+mod v5 {
+    use super::{v3, v4};
+    use crate::export;
+    use crate::text_ops;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct TagStyle {
+        pub foreground: Option<String>,
+        pub background: Option<String>,
+        pub font_family: Option<String>,
+        pub size_points: Option<f64>,
+        pub italic: bool,
+        pub weight: Option<i32>,
+        pub strikethrough: bool,
+        pub underline: bool,
+        pub invisible: bool,
+        pub scale: Option<f64>,
+        pub left_margin: Option<i32>,
+    }
+
+    impl From<TagStyle> for export::TagStyle {
+        fn from(style: TagStyle) -> Self {
+            export::TagStyle {
+                foreground: style.foreground,
+                background: style.background,
+                font_family: style.font_family,
+                size_points: style.size_points,
+                italic: style.italic,
+                weight: style.weight,
+                strikethrough: style.strikethrough,
+                underline: style.underline,
+                invisible: style.invisible,
+                scale: style.scale,
+                left_margin: style.left_margin,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct Note {
+        pub snapshot: Option<v3::Snapshot>,
+        pub ops: Vec<v4::Ops>,
+        pub tags: Vec<(String, TagStyle)>,
+    }
+
+    pub fn upgrade(note: Note) -> super::Note {
+        return super::Note::new(
+            note.snapshot.map(super::Snapshot::from),
+            note.ops.into_iter().map(text_ops::Ops::from).collect(),
+            note.tags
+                .into_iter()
+                .map(|(tag_name, style)| (tag_name, export::TagStyle::from(style)))
+                .collect(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{any_op, insert};
+    use proptest::prelude::*;
+
+    /// A file written in `version`, with `payload` encoded the way it was.
+    fn file(version: u32, payload: &impl Serialize) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend(bincode::serialize(payload).unwrap());
+        return bytes;
+    }
+
+    fn snapshot() -> Snapshot {
+        return Snapshot {
+            text: "hi".to_string(),
+            tags: vec![("bold".to_string(), 0, 2)],
+            current_tag: "color_tag_2".to_string(),
+        };
+    }
+
+    fn frozen_snapshot() -> v3::Snapshot {
+        return v3::Snapshot {
+            text: "hi".to_string(),
+            tags: vec![("bold".to_string(), 0, 2)],
+            current_tag: "color_tag_2".to_string(),
+        };
+    }
+
+    fn typed(content: &str) -> v3::Ops {
+        return v3::Ops::Insert(v3::InsertOpsData {
+            content: content.to_string(),
+            tag: "color_tag_1".to_string(),
+        });
+    }
+
+    fn decoded(bytes: &[u8]) -> Note {
+        return decode(bytes).ok().expect("the note should decode");
+    }
+
+    #[test]
+    fn headerless_files_are_version_1() {
+        let legacy = vec![
+            v1::Ops::Insert(v3::InsertOpsData {
+                content: "ab".to_string(),
+                tag: "color_tag_1".to_string(),
+            }),
+            v1::Ops::MoveCursor(1),
+            v1::Ops::Insert(v3::InsertOpsData {
+                content: "X".to_string(),
+                tag: "color_tag_1".to_string(),
+            }),
+            v1::Ops::Delete((0, 1)),
+            v1::Ops::ApplyTag(("bold".to_string(), 0, 1)),
+        ];
+        let note = decoded(&bincode::serialize(&legacy).unwrap());
+
+        let ops = vec![
+            insert(0, "ab", "color_tag_1"),
+            Ops::MoveCursor(1),
+            insert(1, "X", "color_tag_1"),
+            Ops::Delete((0, 1)),
+            Ops::ApplyTag(("bold".to_string(), 0, 1)),
+        ];
+        assert_eq!(note, Note::new(None, ops, vec![]));
+    }
+
+    #[test]
+    fn version_2_is_a_bare_op_log() {
+        let ops = vec![
+            typed("ab"),
+            v3::Ops::RemoveTag(("bold".to_string(), 0, 1)),
+            typed("c"),
+        ];
+        let note = decoded(&file(2, &ops));
+
+        let ops = vec![
+            insert(0, "ab", "color_tag_1"),
+            Ops::RemoveTag(("bold".to_string(), 0, 1)),
+            insert(2, "c", "color_tag_1"),
+        ];
+        assert_eq!(note, Note::new(None, ops, vec![]));
+    }
+
+    #[test]
+    fn version_3_inserts_land_after_the_snapshot() {
+        let legacy = v3::Note {
+            snapshot: Some(frozen_snapshot()),
+            ops: vec![typed("!"), v3::Ops::MoveCursor(0), typed(">")],
+        };
+        let note = decoded(&file(3, &legacy));
+
+        let ops = vec![
+            insert(2, "!", "color_tag_1"),
+            Ops::MoveCursor(0),
+            insert(0, ">", "color_tag_1"),
+        ];
+        assert_eq!(note, Note::new(Some(snapshot()), ops, vec![]));
+    }
+
+    #[test]
+    fn version_4_has_no_tag_table() {
+        let legacy = v4::Note {
+            snapshot: Some(frozen_snapshot()),
+            ops: vec![
+                v4::Ops::Insert(v4::InsertOpsData {
+                    offset: 0,
+                    content: ">".to_string(),
+                    tag: "color_tag_1".to_string(),
+                }),
+                v4::Ops::SelectColorTag("color_tag_1".to_string()),
+                v4::Ops::Delete((1, 2)),
+            ],
+        };
+        let note = decoded(&file(4, &legacy));
+
+        let ops = vec![
+            insert(0, ">", "color_tag_1"),
+            Ops::SelectColorTag("color_tag_1".to_string()),
+            Ops::Delete((1, 2)),
+        ];
+        assert_eq!(note, Note::new(Some(snapshot()), ops, vec![]));
+    }
+
+    #[test]
+    fn version_5_has_no_op_times() {
+        let legacy = v5::Note {
+            snapshot: None,
+            ops: vec![
+                v4::Ops::Insert(v4::InsertOpsData {
+                    offset: 0,
+                    content: "old".to_string(),
+                    tag: "color_tag_9".to_string(),
+                }),
+                v4::Ops::ApplyTag(("bold".to_string(), 0, 3)),
+            ],
+            tags: vec![(
+                "color_tag_9".to_string(),
+                v5::TagStyle {
+                    foreground: Some("rgb(1,2,3)".to_string()),
+                    background: None,
+                    font_family: Some("JetBrains Mono".to_string()),
+                    size_points: Some(14.0),
+                    italic: true,
+                    weight: Some(600),
+                    strikethrough: false,
+                    underline: true,
+                    invisible: false,
+                    scale: None,
+                    left_margin: Some(12),
+                },
+            )],
+        };
+        let note = decoded(&file(5, &legacy));
+
+        let ops = vec![
+            insert(0, "old", "color_tag_9"),
+            Ops::ApplyTag(("bold".to_string(), 0, 3)),
+        ];
+        let style = TagStyle {
+            foreground: Some("rgb(1,2,3)".to_string()),
+            background: None,
+            font_family: Some("JetBrains Mono".to_string()),
+            size_points: Some(14.0),
+            italic: true,
+            weight: Some(600),
+            strikethrough: false,
+            underline: true,
+            invisible: false,
+            scale: None,
+            left_margin: Some(12),
+        };
+        let tags = vec![("color_tag_9".to_string(), style)];
+        assert_eq!(note, Note::new(None, ops, tags));
+        assert_eq!(note.op_times, vec![UNKNOWN_TIME; 2]);
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let mut note = Note::new(
+            Some(snapshot()),
+            vec![insert(2, "!", "color_tag_1")],
+            vec![],
+        );
+        note.op_times = vec![1_600_000_000_000];
+
+        let bytes = encode(&note).ok().unwrap();
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(decoded(&bytes), note);
+        assert_eq!(decoded(&[]), Note::new(None, vec![], vec![]));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        assert!(matches!(
+            decode(&file(FORMAT_VERSION + 1, &())),
+            Err(NoteFileError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn damaged_files_are_errors() {
+        let bytes = encode(&Note::new(
+            Some(snapshot()),
+            vec![insert(2, "!", "color_tag_1")],
+            vec![],
+        ))
+        .ok()
+        .unwrap();
+        for len in 1..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "{} bytes decoded", len);
+        }
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[3] = b'X';
+        assert!(decode(&bad_magic).is_err());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        /// A note compacted on save and edited further reads back as the
+        /// document all of its edits lead to.
+        #[test]
+        fn compacted_notes_replay_to_the_same_document(
+            ops in prop::collection::vec(
+                any_op(12),
+                COMPACTION_THRESHOLD + 1..COMPACTION_THRESHOLD + 40,
+            ),
+            later_ops in prop::collection::vec(any_op(12), 0..40),
+        ) {
+            let mut document = Document::new();
+            for op in &ops {
+                document.apply(op);
+            }
+            let mut note = Note::new(None, ops[..COMPACTION_THRESHOLD].to_vec(), vec![]);
+            prop_assert!(!note.compact(&document, "color_tag_1"));
+            let mut note = Note::new(None, ops.to_vec(), vec![]);
+            prop_assert!(note.compact(&document, "color_tag_1"));
+            prop_assert!(note.ops.is_empty() && note.op_times.is_empty());
+
+            for op in &later_ops {
+                document.apply(op);
+                note.ops.push(op.clone());
+                note.op_times.push(UNKNOWN_TIME);
+            }
+            let note = decoded(&encode(&note).ok().unwrap());
+
+            let mut hydrated = Document::new();
+            if let Some(snapshot) = &note.snapshot {
+                hydrated.restore_snapshot(snapshot);
+            }
+            for op in &note.ops {
+                hydrated.apply(op);
+            }
+            prop_assert_eq!(hydrated, document);
+        }
+    }
+}
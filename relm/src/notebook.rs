@@ -0,0 +1,324 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const NOTE_EXTENSION: &str = "note";
+const UNTITLED_NOTE_NAME: &str = "Untitled";
+const BACKUP_DIR: &str = "backups";
+/// How many previous saves of every note are kept around.
+const BACKUP_COUNT: usize = 5;
+
+/// Tells the contents of note files apart without keeping them around.
+pub fn fingerprint(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    return hasher.finish();
+}
+
+/// A directory of note files, each note is named after its file stem.
+pub struct Notebook {
+    dir: PathBuf,
+}
+
+impl Notebook {
+    /// Opens the notebook in the per-user data directory, creating it if needed.
+    pub fn open_default() -> io::Result<Self> {
+        let mut dir = gtk::glib::user_data_dir();
+        dir.push("pretty-notes");
+        dir.push("notes");
+        return Notebook::open(dir);
+    }
+
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        return Ok(Notebook { dir });
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        return self.dir.join(format!("{}.{}", name, NOTE_EXTENSION));
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        return self.path(name).exists();
+    }
+
+    /// Names of all notes, sorted alphabetically.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == NOTE_EXTENSION) {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort_by_key(|name| name.to_lowercase());
+        return Ok(names);
+    }
+
+    /// Creates an empty note with a free "Untitled" name and returns the name.
+    pub fn create(&self) -> io::Result<String> {
+        return self.create_named(UNTITLED_NOTE_NAME);
+    }
+
+    /// Creates an empty note named `base_name`, or "<base_name> 2" and so on
+    /// if that is taken, and returns the name.
+    pub fn create_named(&self, base_name: &str) -> io::Result<String> {
+        validate_name(base_name)?;
+        let name = self.unused_name(base_name);
+        fs::write(self.path(&name), b"")?;
+        return Ok(name);
+    }
+
+    pub fn rename(&self, name: &str, new_name: &str) -> io::Result<()> {
+        validate_name(new_name)?;
+        if name != new_name && self.exists(new_name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("A note named \"{}\" already exists", new_name),
+            ));
+        }
+        fs::rename(self.path(name), self.path(new_name))?;
+
+        for generation in 1..=BACKUP_COUNT {
+            let backup = self.backup_path(name, generation);
+            if backup.exists() {
+                fs::rename(backup, self.backup_path(new_name, generation))?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Deletes a note and its backups, a note created under its name later
+    /// starts without any.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(name))?;
+        return self.delete_backups(name);
+    }
+
+    /// Copies a note under a free "<name> copy" name and returns the new name.
+    pub fn duplicate(&self, name: &str) -> io::Result<String> {
+        let copy_name = self.unused_name(format!("{} copy", name).as_str());
+        fs::copy(self.path(name), self.path(&copy_name))?;
+        return Ok(copy_name);
+    }
+
+    /// Reads a note, a note that was never written reads as empty.
+    pub fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        match fs::read(self.path(name)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            result => result,
+        }
+    }
+
+    /**
+     * Writes the note to a temporary file first and renames it over the old
+     * one, so a crash or a full disk never leaves a half written note behind.
+     * The replaced version goes into the backup rotation.
+     */
+    pub fn write(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let path = self.path(name);
+        let tmp_path = self.dir.join(format!(".{}.{}.tmp", name, NOTE_EXTENSION));
+
+        let written = File::create(&tmp_path).and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        });
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+
+        // a note that was only created has nothing worth backing up yet
+        if fs::metadata(&path).map_or(false, |metadata| metadata.len() > 0) {
+            self.rotate_backups(name)?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        return sync_dir(&self.dir);
+    }
+
+    /// Deletes every backup of a note, the note itself stays.
+    pub fn delete_backups(&self, name: &str) -> io::Result<()> {
+        for generation in 1..=BACKUP_COUNT {
+            let backup = self.backup_path(name, generation);
+            if backup.exists() {
+                fs::remove_file(backup)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn backup_path(&self, name: &str, generation: usize) -> PathBuf {
+        return self
+            .dir
+            .join(BACKUP_DIR)
+            .join(format!("{}.{}.{}", name, NOTE_EXTENSION, generation));
+    }
+
+    /// Shifts the backups of a note by one and copies the note in as the newest.
+    fn rotate_backups(&self, name: &str) -> io::Result<()> {
+        fs::create_dir_all(self.dir.join(BACKUP_DIR))?;
+
+        for generation in (1..BACKUP_COUNT).rev() {
+            let backup = self.backup_path(name, generation);
+            if backup.exists() {
+                fs::rename(backup, self.backup_path(name, generation + 1))?;
+            }
+        }
+        fs::copy(self.path(name), self.backup_path(name, 1))?;
+        return Ok(());
+    }
+
+    fn unused_name(&self, base_name: &str) -> String {
+        let mut name = base_name.to_string();
+        let mut counter = 2;
+        while self.exists(&name) {
+            name = format!("{} {}", base_name, counter);
+            counter += 1;
+        }
+        return name;
+    }
+}
+
+/// Flushes the entries of `dir`, a rename into it can be lost in a crash until then.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    return File::open(dir)?.sync_all();
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    return Ok(());
+}
+
+fn validate_name(name: &str) -> io::Result<()> {
+    if name.trim().is_empty() || name.starts_with('.') || name.contains(|c| c == '/' || c == '\\') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"{}\" is not a valid note name", name),
+        ));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn notebook() -> (TempDir, Notebook) {
+        let dir = tempfile::tempdir().unwrap();
+        let notebook = Notebook::open(dir.path().to_path_buf()).unwrap();
+        return (dir, notebook);
+    }
+
+    #[test]
+    fn recreated_notes_start_without_backups() {
+        let (_dir, notebook) = notebook();
+        let name = notebook.create_named("Groceries").unwrap();
+        notebook.write(&name, b"eggs").unwrap();
+        notebook.write(&name, b"eggs, milk").unwrap();
+        notebook.write(&name, b"eggs, milk, tea").unwrap();
+        assert_eq!(
+            fs::read(notebook.backup_path(&name, 1)).unwrap(),
+            b"eggs, milk"
+        );
+        assert_eq!(fs::read(notebook.backup_path(&name, 2)).unwrap(), b"eggs");
+
+        notebook.delete(&name).unwrap();
+        assert!(!notebook.exists(&name));
+        assert!(!notebook.backup_path(&name, 1).exists());
+        assert!(!notebook.backup_path(&name, 2).exists());
+
+        assert_eq!(notebook.create_named("Groceries").unwrap(), name);
+        notebook.write(&name, b"bread").unwrap();
+        assert_eq!(notebook.read(&name).unwrap(), b"bread");
+        assert!(!notebook.backup_path(&name, 1).exists());
+        notebook.write(&name, b"bread, jam").unwrap();
+        assert_eq!(fs::read(notebook.backup_path(&name, 1)).unwrap(), b"bread");
+        assert!(!notebook.backup_path(&name, 2).exists());
+    }
+
+    #[test]
+    fn writes_replace_the_note_in_one_go() {
+        let (dir, notebook) = notebook();
+        let name = notebook.create().unwrap();
+        notebook.write(&name, b"first").unwrap();
+        notebook.write(&name, b"second").unwrap();
+        assert_eq!(notebook.read(&name).unwrap(), b"second");
+
+        let mut entries = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        entries.sort();
+        assert_eq!(entries, vec!["Untitled.note", BACKUP_DIR]);
+    }
+
+    #[test]
+    fn failed_writes_leave_the_note_alone() {
+        let (dir, notebook) = notebook();
+        let name = notebook.create().unwrap();
+        notebook.write(&name, b"older").unwrap();
+        notebook.write(&name, b"kept").unwrap();
+
+        // the temporary file can't be created where a directory is
+        fs::create_dir(dir.path().join(".Untitled.note.tmp")).unwrap();
+        assert!(notebook.write(&name, b"lost").is_err());
+        assert_eq!(notebook.read(&name).unwrap(), b"kept");
+        assert_eq!(fs::read(notebook.backup_path(&name, 1)).unwrap(), b"older");
+        assert!(!notebook.backup_path(&name, 2).exists());
+    }
+
+    #[test]
+    fn backups_keep_the_last_five_saves() {
+        let (_dir, notebook) = notebook();
+        let name = notebook.create().unwrap();
+        for save in 1..=7 {
+            notebook.write(&name, save.to_string().as_bytes()).unwrap();
+        }
+
+        assert_eq!(notebook.read(&name).unwrap(), b"7");
+        for generation in 1..=BACKUP_COUNT {
+            let expected = (7 - generation).to_string();
+            assert_eq!(
+                fs::read(notebook.backup_path(&name, generation)).unwrap(),
+                expected.as_bytes()
+            );
+        }
+        assert!(!notebook.backup_path(&name, BACKUP_COUNT + 1).exists());
+    }
+
+    #[test]
+    fn note_names() {
+        let cases = [
+            ("Groceries", true),
+            ("2024 plans", true),
+            ("v1.2 notes", true),
+            ("", false),
+            ("   ", false),
+            (".hidden", false),
+            ("a/b", false),
+            ("a\\b", false),
+        ];
+        for (name, is_valid) in cases.iter() {
+            assert_eq!(validate_name(name).is_ok(), *is_valid, "{:?}", name);
+        }
+
+        let (_dir, notebook) = notebook();
+        let err = notebook.create_named("../escape").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(notebook.create_named("Groceries").unwrap(), "Groceries");
+        assert_eq!(notebook.create_named("Groceries").unwrap(), "Groceries 2");
+    }
+
+    #[test]
+    fn fingerprints_tell_contents_apart() {
+        assert_eq!(fingerprint(b"note"), fingerprint(b"note"));
+        assert_ne!(fingerprint(b"note"), fingerprint(b"notes"));
+        assert_ne!(fingerprint(b""), fingerprint(b"\0"));
+    }
+}
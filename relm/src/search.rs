@@ -0,0 +1,165 @@
+use regex::{Regex, RegexBuilder};
+
+/// How the search term of the find bar is matched.
+#[derive(Clone, Copy, Default)]
+pub struct SearchOptions {
+    pub case_sensitive: bool,
+    /// the term is a regular expression rather than literal text
+    pub regex: bool,
+    pub whole_word: bool,
+}
+
+/// A match in char offsets, with the text that replaces it.
+#[derive(Debug, PartialEq)]
+pub struct SearchMatch {
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub replacement: String,
+}
+
+fn build_regex(query: &str, options: &SearchOptions) -> Result<Regex, regex::Error> {
+    let mut pattern = if options.regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+    if options.whole_word {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+
+    return RegexBuilder::new(pattern.as_str())
+        .case_insensitive(!options.case_sensitive)
+        .multi_line(true)
+        .build();
+}
+
+/**
+ * Finds every non-empty match of `query` in `text`. In regex mode `$1` and
+ * `${name}` in `replace_with` stand for the groups of each match, otherwise
+ * it is used as it is. A broken regex comes back as its error message.
+ */
+pub fn find_matches(
+    text: &str,
+    query: &str,
+    options: &SearchOptions,
+    replace_with: &str,
+) -> Result<Vec<SearchMatch>, String> {
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+    let regex = build_regex(query, options).map_err(|err| err.to_string())?;
+
+    let mut matches = vec![];
+    // byte offsets from the regex become char offsets for the buffer
+    let mut chars_before = 0;
+    let mut bytes_counted = 0;
+    for captures in regex.captures_iter(text) {
+        let found = captures.get(0).expect("every match has a group 0");
+        if found.start() == found.end() {
+            continue;
+        }
+
+        chars_before += text[bytes_counted..found.start()].chars().count() as i32;
+        let match_chars = found.as_str().chars().count() as i32;
+        bytes_counted = found.end();
+
+        let replacement = if options.regex {
+            let mut expanded = String::new();
+            captures.expand(replace_with, &mut expanded);
+            expanded
+        } else {
+            replace_with.to_string()
+        };
+
+        matches.push(SearchMatch {
+            start_offset: chars_before,
+            end_offset: chars_before + match_chars,
+            replacement,
+        });
+        chars_before += match_chars;
+    }
+
+    return Ok(matches);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offsets(matches: &[SearchMatch]) -> Vec<(i32, i32)> {
+        return matches
+            .iter()
+            .map(|found| (found.start_offset, found.end_offset))
+            .collect();
+    }
+
+    #[test]
+    fn literal_search_ignores_case_by_default() {
+        let options = SearchOptions::default();
+        let matches = find_matches("Note, note, NOTE.", "note", &options, "").unwrap();
+        assert_eq!(offsets(&matches), vec![(0, 4), (6, 10), (12, 16)]);
+
+        let options = SearchOptions {
+            case_sensitive: true,
+            ..SearchOptions::default()
+        };
+        let matches = find_matches("Note, note, NOTE.", "note", &options, "").unwrap();
+        assert_eq!(offsets(&matches), vec![(6, 10)]);
+    }
+
+    #[test]
+    fn literal_search_escapes_regex_syntax() {
+        let options = SearchOptions::default();
+        let matches = find_matches("a.b axb a.b", "a.b", &options, "$1").unwrap();
+        assert_eq!(offsets(&matches), vec![(0, 3), (8, 11)]);
+        assert_eq!(matches[0].replacement, "$1");
+    }
+
+    #[test]
+    fn whole_words_only() {
+        let options = SearchOptions {
+            whole_word: true,
+            ..SearchOptions::default()
+        };
+        let matches = find_matches("cat concat cat's", "cat", &options, "").unwrap();
+        assert_eq!(offsets(&matches), vec![(0, 3), (11, 14)]);
+    }
+
+    #[test]
+    fn regex_replacements_expand_groups() {
+        let options = SearchOptions {
+            regex: true,
+            ..SearchOptions::default()
+        };
+        let matches = find_matches(
+            "2020-07-14 and 2021-01-02",
+            r"(\d+)-(\d+)-(\d+)",
+            &options,
+            "$3.$2.$1",
+        )
+        .unwrap();
+        let replacements = matches
+            .iter()
+            .map(|found| found.replacement.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(replacements, vec!["14.07.2020", "02.01.2021"]);
+    }
+
+    #[test]
+    fn offsets_count_chars() {
+        let options = SearchOptions::default();
+        let matches = find_matches("café 🎉 café", "café", &options, "").unwrap();
+        assert_eq!(offsets(&matches), vec![(0, 4), (7, 11)]);
+    }
+
+    #[test]
+    fn empty_matches_and_broken_regexes() {
+        let options = SearchOptions {
+            regex: true,
+            ..SearchOptions::default()
+        };
+        assert_eq!(find_matches("abc", "x*", &options, "").unwrap(), vec![]);
+        assert!(find_matches("abc", "(", &options, "").is_err());
+        assert_eq!(find_matches("abc", "", &options, "").unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,24 @@
+use gtk::gdk;
+use gtk::prelude::*;
+
+pub use common::shortcuts::{shortcuts, Shortcut, MAX_COLOR_SHORTCUTS};
+
+/// Finds the shortcut `key` was pressed for.
+pub fn matching_shortcut<'a>(
+    shortcuts: &'a [Shortcut],
+    key: &gdk::EventKey,
+) -> Option<&'a Shortcut> {
+    let state = key.state() & gtk::accelerator_get_default_mod_mask();
+    let keyval = *key.keyval().to_lower();
+
+    return shortcuts.iter().find(|shortcut| {
+        let (accel_key, accel_mods) = gtk::accelerator_parse(shortcut.accel.as_str());
+        accel_key == keyval && accel_mods == state
+    });
+}
+
+/// Builds the help window listing `shortcuts` by group.
+pub fn shortcuts_window(shortcuts: &[Shortcut]) -> Option<gtk::ShortcutsWindow> {
+    let ui = common::shortcuts::shortcuts_ui(shortcuts);
+    return gtk::Builder::from_string(ui.as_str()).object("shortcuts");
+}
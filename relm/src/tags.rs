@@ -1,161 +1,707 @@
 use crate::Ops;
+use unicode_segmentation::UnicodeSegmentation;
 
-pub enum TagType {
-    Bold,
-    Italic,
-    BoldItalic,
+pub const ITALIC_TAG: &str = "italic";
+pub const BOLD_TAG: &str = "bold";
+pub const STRIKETHROUGH_TAG: &str = "strikethrough";
+pub const CODE_TAG: &str = "code";
+pub const LINK_TAG: &str = "link";
+/// the `(url)` part of a link, kept in the text but hidden
+pub const LINK_URL_TAG: &str = "link_url";
+
+pub const HEADING_1_TAG: &str = "heading_1";
+pub const HEADING_2_TAG: &str = "heading_2";
+pub const LIST_ITEM_TAG: &str = "list_item";
+pub const BLOCKQUOTE_TAG: &str = "blockquote";
+pub const RULE_TAG: &str = "rule";
+/// the `# ` and `> ` that start a line, kept in the text but hidden
+pub const BLOCK_MARKER_TAG: &str = "block_marker";
+/// the `- ` and `1. ` that start a list item, they stay visible
+pub const LIST_MARKER_TAG: &str = "list_marker";
+
+/// Marks the matches of the find bar.
+pub const SEARCH_MATCH_TAG: &str = "search_match";
+/// Marks the cursor of the peer a note is shared with.
+pub const PEER_CURSOR_TAG: &str = "peer_cursor";
+
+/// Tags that format a whole line, text typed into the line takes them on.
+pub const BLOCK_TAGS: [&str; 4] = [HEADING_1_TAG, HEADING_2_TAG, LIST_ITEM_TAG, BLOCKQUOTE_TAG];
+
+/// Tags that only exist in the view, they are never saved or exported.
+pub fn is_view_tag(tag_name: &str) -> bool {
+    return tag_name == SEARCH_MATCH_TAG || tag_name == PEER_CURSOR_TAG;
+}
+
+/// A pair of delimiter runs that turns the text between them into `tags`.
+struct Delimiter {
+    symbol: &'static str,
+    length: usize,
+    tags: &'static [&'static str],
+    /// `_` only counts at word boundaries, so snake_case stays as it is
+    intraword: bool,
 }
 
-pub struct TextTagState {
-    pub is_active: bool,
-    pub start_offset: i32,
-    pub tag_type: TagType,
-    pub tag_symbol: char,
+const DELIMITERS: [Delimiter; 7] = [
+    Delimiter {
+        symbol: "*",
+        length: 1,
+        tags: &[ITALIC_TAG],
+        intraword: true,
+    },
+    Delimiter {
+        symbol: "*",
+        length: 2,
+        tags: &[BOLD_TAG],
+        intraword: true,
+    },
+    Delimiter {
+        symbol: "*",
+        length: 3,
+        tags: &[BOLD_TAG, ITALIC_TAG],
+        intraword: true,
+    },
+    Delimiter {
+        symbol: "_",
+        length: 1,
+        tags: &[ITALIC_TAG],
+        intraword: false,
+    },
+    Delimiter {
+        symbol: "_",
+        length: 2,
+        tags: &[BOLD_TAG],
+        intraword: false,
+    },
+    Delimiter {
+        symbol: "_",
+        length: 3,
+        tags: &[BOLD_TAG, ITALIC_TAG],
+        intraword: false,
+    },
+    Delimiter {
+        symbol: "~",
+        length: 2,
+        tags: &[STRIKETHROUGH_TAG],
+        intraword: true,
+    },
+];
+
+/**
+ * The text of a line as grapheme clusters. Markers and word boundaries are
+ * found on clusters, so an accent or emoji modifier is never split from its
+ * base, while offsets stay in chars like GTK's.
+ */
+struct Line<'a> {
+    graphemes: Vec<&'a str>,
+    /// the buffer offset of every cluster, plus the offset past the last one
+    offsets: Vec<i32>,
 }
 
-impl TextTagState {
-    pub fn new(tag_type: TagType, tag_symbol: char) -> Self {
-        TextTagState {
-            is_active: false,
-            start_offset: -1,
-            tag_type,
-            tag_symbol,
+impl<'a> Line<'a> {
+    fn new(text: &'a str, line_offset: i32) -> Self {
+        let graphemes = text.graphemes(true).collect::<Vec<&str>>();
+        let mut offsets = vec![line_offset];
+        for grapheme in &graphemes {
+            offsets.push(offsets[offsets.len() - 1] + grapheme.chars().count() as i32);
+        }
+        return Line { graphemes, offsets };
+    }
+
+    fn len(&self) -> usize {
+        return self.graphemes.len();
+    }
+
+    /// Finds the maximal run of `symbol` that ends right before `end`.
+    fn run_start(&self, end: usize, symbol: &str) -> usize {
+        let mut start = end;
+        while start > 0 && self.graphemes[start - 1] == symbol {
+            start -= 1;
         }
+        return start;
+    }
+}
+
+fn is_whitespace(grapheme: &str) -> bool {
+    return grapheme.chars().all(char::is_whitespace);
+}
+
+fn is_alphanumeric(grapheme: &str) -> bool {
+    return grapheme.chars().next().map_or(false, char::is_alphanumeric);
+}
+
+/**
+ * Formats Markdown as it is typed. Called after `inserted`, a single grapheme
+ * cluster, was typed at the end of `line_before`, the text of its line up to
+ * that point, which starts at `line_offset` in the buffer.
+ *
+ * A delimiter run only closes once something other than the delimiter is
+ * typed after it, that's how `*` can still grow into `**`. Links close on
+ * their `)`. Returns the ops that remove the markers and tag the text, or
+ * nothing when `inserted` completes no construct.
+ */
+pub fn format_insert(line_before: &str, line_offset: i32, inserted: &str) -> Vec<Ops> {
+    let block_ops = block_ops(line_before, line_offset, inserted);
+    if !block_ops.is_empty() {
+        return block_ops;
+    }
+
+    // a combining mark or joiner typed after a marker merges into it, the
+    // marker is no marker anymore
+    let typed_text = format!("{}{}", line_before, inserted);
+    if typed_text.graphemes(true).next_back() != Some(inserted) {
+        return vec![];
+    }
+
+    let ops = all_closing_ops(line_before, line_offset, inserted);
+    if ops.is_empty() && inserted == ")" {
+        return link_ops(&Line::new(line_before, line_offset));
     }
+    return ops;
 }
 
-pub struct BoldItalicTagState {
-    pub single_asterisk_active: bool,
-    pub double_asterisk_active: bool,
-    pub single_asterisk_complete: bool,
-    pub start_offset: i32,
-    pub end_offset: i32,
-    pub single_asterisk_tag_name: String,
-    pub double_asterisk_tag_name: String,
-}
-
-impl BoldItalicTagState {
-    pub fn new(single_asterisk_tag_name: String, double_asterisk_tag_name: String) -> Self {
-        return BoldItalicTagState {
-            single_asterisk_active: false,
-            double_asterisk_active: false,
-            single_asterisk_complete: false,
-            start_offset: -1,
-            end_offset: -1,
-            single_asterisk_tag_name,
-            double_asterisk_tag_name,
-        };
-    }
-
-    fn reset_state(&mut self) {
-        self.start_offset = -1;
-        self.single_asterisk_complete = false;
-        self.single_asterisk_active = false;
-        self.double_asterisk_active = false;
-    }
-
-    pub fn update_state(
-        &mut self,
-        offset: i32,
-        string_to_modify: String,
-        last_char: String,
-    ) -> (&Self, Vec<Ops>) {
-        let mut ops_to_perform = vec![];
-
-        if last_char != "*".to_string()
-            && self.single_asterisk_complete
-            && self.double_asterisk_active
-        {
-            // single_asterisk is matched and realized
-
-            ops_to_perform.push(Ops::ApplyTag((
-                self.single_asterisk_tag_name.to_string(),
-                self.start_offset,
-                offset,
-            )));
-            ops_to_perform.push(Ops::Delete((self.start_offset, self.start_offset + 1)));
-            ops_to_perform.push(Ops::Delete((offset - 1, offset)));
-            self.reset_state();
-            return (self, ops_to_perform);
-        } else if last_char == "*".to_string() {
-            return self.update_state_with_asterisk(offset, string_to_modify);
-        } else {
-            return (self, ops_to_perform);
+/**
+ * Closes a delimiter run left at the very end of inserted text. `next` is
+ * the grapheme following the text in the buffer, `None` at the end of a line.
+ * A typed run waits for the next keystroke, inserted text is complete as is.
+ */
+pub fn format_boundary(line_before: &str, line_offset: i32, next: Option<&str>) -> Vec<Ops> {
+    return all_closing_ops(line_before, line_offset, next.unwrap_or("\n"));
+}
+
+/**
+ * Like `closing_ops`, but when a closing run is longer than the emphasis it
+ * closed, what is left of it goes on to close the next one.
+ */
+fn all_closing_ops(line_before: &str, line_offset: i32, next: &str) -> Vec<Ops> {
+    let line = Line::new(line_before, line_offset);
+    let mut ops = closing_ops(&line, next);
+
+    // the closing run is removed first
+    let line_end = line.offsets[line.len()];
+    if let Some(Ops::Delete((_, close_end))) = ops.first() {
+        if *close_end < line_end {
+            let mut rest = line_before.chars().collect::<Vec<char>>();
+            for op in &ops {
+                if let Ops::Delete((start_offset, end_offset)) = op {
+                    rest.drain(
+                        (start_offset - line_offset) as usize..(end_offset - line_offset) as usize,
+                    );
+                }
+            }
+            let rest = rest.into_iter().collect::<String>();
+            ops.extend(all_closing_ops(rest.as_str(), line_offset, next));
         }
     }
+    return ops;
+}
+
+/// Converts the emphasis or code span closed by the run `line` ends with.
+fn closing_ops(line: &Line, next: &str) -> Vec<Ops> {
+    match line.graphemes.last() {
+        Some(&"`") if next != "`" => code_span_ops(line),
+        Some(last) if *last != next => emphasis_ops(line, next),
+        _ => vec![],
+    }
+}
+
+/**
+ * Line start triggers: `# `, `## `, `> `, `- ` and `1. ` once their space is
+ * typed, `---` once the line is ended. The markers stay in the text so the
+ * note still reads as Markdown, only tagged.
+ */
+fn block_ops(line_before: &str, line_offset: i32, inserted: &str) -> Vec<Ops> {
+    let marker_end = line_offset + line_before.chars().count() as i32;
+    let tag = |tag_name: &str, end_offset: i32| {
+        Ops::ApplyTag((tag_name.to_string(), line_offset, end_offset))
+    };
+    let is_numbered = line_before.strip_suffix('.').map_or(false, |number| {
+        !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+    });
+
+    // the marker along with the typed space
+    match (line_before, inserted) {
+        ("#", " ") => vec![
+            tag(BLOCK_MARKER_TAG, marker_end + 1),
+            tag(HEADING_1_TAG, marker_end + 1),
+        ],
+        ("##", " ") => vec![
+            tag(BLOCK_MARKER_TAG, marker_end + 1),
+            tag(HEADING_2_TAG, marker_end + 1),
+        ],
+        (">", " ") => vec![
+            tag(BLOCK_MARKER_TAG, marker_end + 1),
+            tag(BLOCKQUOTE_TAG, marker_end + 1),
+        ],
+        ("-", " ") => vec![
+            tag(LIST_MARKER_TAG, marker_end + 1),
+            tag(LIST_ITEM_TAG, marker_end + 1),
+        ],
+        (_, " ") if is_numbered => vec![
+            tag(LIST_MARKER_TAG, marker_end + 1),
+            tag(LIST_ITEM_TAG, marker_end + 1),
+        ],
+        ("---", "\n") => vec![tag(RULE_TAG, marker_end)],
+        _ => vec![],
+    }
+}
+
+/**
+ * Removes the markers of a matched pair, `open`/`close` are the cluster
+ * ranges of the opening and closing runs within the line.
+ */
+fn conversion_ops(
+    line: &Line,
+    open: (usize, usize),
+    close: (usize, usize),
+    tags: &[&str],
+) -> Vec<Ops> {
+    let open_start = line.offsets[open.0];
+    let open_len = line.offsets[open.1] - open_start;
+    let close_start = line.offsets[close.0];
+    let close_end = line.offsets[close.1];
+
+    // the closing run goes first so the offsets of the opening one stay valid
+    let mut ops = vec![
+        Ops::Delete((close_start, close_end)),
+        Ops::Delete((open_start, open_start + open_len)),
+    ];
+    for tag_name in tags {
+        ops.push(Ops::ApplyTag((
+            tag_name.to_string(),
+            open_start,
+            close_start - open_len,
+        )));
+    }
+    return ops;
+}
 
-    pub fn update_state_with_asterisk(
-        &mut self,
-        offset: i32,
-        string_to_modify: String,
-    ) -> (&Self, Vec<Ops>) {
-        let mut tag_to_apply = None;
-        if self.single_asterisk_active == false && self.double_asterisk_active == false {
-            self.single_asterisk_active = true;
-            self.start_offset = offset;
-        } else if self.single_asterisk_active == true && self.double_asterisk_active == false {
-            if string_to_modify.len() == 1 {
-                self.double_asterisk_active = true
-            } else if string_to_modify[..1] == " ".to_string()
-                || string_to_modify[(string_to_modify.len() - 2)..] == " ".to_string()
-            {
-                self.single_asterisk_active = true;
-                self.start_offset = offset;
-            } else {
-                tag_to_apply = Some(self.single_asterisk_tag_name.to_string());
+/**
+ * Converts the emphasis the delimiter run `line` ends with closes. A run
+ * longer than its opener closes it with the end that touches the text, so
+ * `**big *world***` closes the italic first and leaves `**` for the bold.
+ */
+fn emphasis_ops(line: &Line, inserted: &str) -> Vec<Ops> {
+    let close_end = line.len();
+    let symbol = line.graphemes[close_end - 1];
+    let close_start = line.run_start(close_end, symbol);
+    let length = close_end - close_start;
+
+    // a closing run hugs the text before it
+    if close_start == 0 || is_whitespace(line.graphemes[close_start - 1]) {
+        return vec![];
+    }
+
+    // the nearest opener wins, the longer delimiter when they start together
+    let mut matched: Option<((usize, usize), &Delimiter)> = None;
+    for delimiter in DELIMITERS
+        .iter()
+        .filter(|delimiter| delimiter.symbol == symbol && delimiter.length <= length)
+    {
+        if !delimiter.intraword && is_alphanumeric(inserted) {
+            continue;
+        }
+        if let Some(open) = find_opener(line, delimiter, close_start) {
+            let is_nearer = matched.map_or(true, |(matched_open, matched_delimiter)| {
+                open.0 > matched_open.0
+                    || (open.0 == matched_open.0 && delimiter.length > matched_delimiter.length)
+            });
+            if is_nearer {
+                matched = Some((open, delimiter));
             }
-        } else {
-            if self.single_asterisk_complete == false {
-                if string_to_modify == "*".to_string() || string_to_modify.len() == 0 {
-                    self.double_asterisk_active = true;
-                    self.single_asterisk_active = true;
-                    self.start_offset = self.start_offset + 1;
-                } else if string_to_modify[..1] == " ".to_string()
-                    || string_to_modify[(string_to_modify.len() - 1)..] == " ".to_string()
-                {
-                    self.single_asterisk_active = true;
-                    self.start_offset = offset;
-                    self.double_asterisk_active = false;
-                } else {
-                    self.single_asterisk_complete = true;
-                }
-            } else {
-                // do not need to check for spaces or inali
-                tag_to_apply = Some(self.double_asterisk_tag_name.to_string());
+        }
+    }
+
+    return match matched {
+        Some((open, delimiter)) => conversion_ops(
+            line,
+            open,
+            (close_start, close_start + delimiter.length),
+            delimiter.tags,
+        ),
+        None => vec![],
+    };
+}
+
+/// Finds the run opening `delimiter` that a run starting at `close_start` closes.
+fn find_opener(line: &Line, delimiter: &Delimiter, close_start: usize) -> Option<(usize, usize)> {
+    let symbol = delimiter.symbol;
+    let mut end = close_start;
+    while end > 0 {
+        if line.graphemes[end - 1] != symbol {
+            end -= 1;
+            continue;
+        }
+
+        let start = line.run_start(end, symbol);
+        let is_opener = end - start == delimiter.length
+            && end < close_start
+            && !is_whitespace(line.graphemes[end])
+            && (delimiter.intraword || start == 0 || !is_alphanumeric(line.graphemes[start - 1]));
+        // markers inside an unfinished code span are literal
+        let in_code_span = line.graphemes[..start]
+            .iter()
+            .filter(|grapheme| **grapheme == "`")
+            .count()
+            % 2
+            == 1;
+
+        if is_opener && !in_code_span {
+            return Some((start, end));
+        }
+        end = start;
+    }
+
+    return None;
+}
+
+/// Code spans open and close with backtick runs of the same length.
+fn code_span_ops(line: &Line) -> Vec<Ops> {
+    let close_end = line.len();
+    let close_start = line.run_start(close_end, "`");
+    let length = close_end - close_start;
+
+    let mut end = close_start;
+    while end > 0 {
+        if line.graphemes[end - 1] != "`" {
+            end -= 1;
+            continue;
+        }
+
+        let start = line.run_start(end, "`");
+        if end - start == length {
+            let is_blank = line.graphemes[end..close_start]
+                .iter()
+                .all(|grapheme| is_whitespace(grapheme));
+            if is_blank {
+                return vec![];
             }
+            return conversion_ops(line, (start, end), (close_start, close_end), &[CODE_TAG]);
         }
+        end = start;
+    }
 
-        let mut ops_to_perform = vec![];
-        match tag_to_apply {
-            Some(tag_name) => {
-                ops_to_perform.push(Ops::Delete((
-                    self.start_offset,
-                    self.start_offset
-                        + if self.single_asterisk_tag_name == tag_name.to_string() {
-                            1
-                        } else {
-                            2
-                        },
-                )));
-                ops_to_perform.push(Ops::Delete((
-                    offset
-                        - if self.single_asterisk_tag_name == tag_name.to_string() {
-                            1
-                        } else {
-                            3
-                        },
-                    offset,
-                )));
-                ops_to_perform.push(Ops::ApplyTag((
-                    tag_name.to_string(),
-                    self.start_offset,
-                    offset,
-                )));
-                self.reset_state();
+    return vec![];
+}
+
+/**
+ * `[text](url)` keeps only `text` visible, tagged as a link. The `(url)`
+ * stays in the buffer under the hidden url tag so it is saved and exported
+ * along with the note.
+ */
+fn link_ops(line: &Line) -> Vec<Ops> {
+    // the line ends right before the `)` that was just typed
+    let url_end = line.len();
+    let paren = match line.graphemes.iter().rposition(|grapheme| *grapheme == "(") {
+        Some(paren) => paren,
+        None => return vec![],
+    };
+    let url = &line.graphemes[paren + 1..url_end];
+    if url.is_empty()
+        || url
+            .iter()
+            .any(|grapheme| is_whitespace(grapheme) || *grapheme == ")")
+    {
+        return vec![];
+    }
+
+    if paren == 0 || line.graphemes[paren - 1] != "]" {
+        return vec![];
+    }
+    let close_bracket = paren - 1;
+    let open_bracket = match line.graphemes[..close_bracket]
+        .iter()
+        .rposition(|grapheme| *grapheme == "[" || *grapheme == "]")
+    {
+        Some(idx) if line.graphemes[idx] == "[" => idx,
+        _ => return vec![],
+    };
+    if open_bracket + 1 == close_bracket {
+        return vec![];
+    }
+
+    let open_bracket_offset = line.offsets[open_bracket];
+    let close_bracket_offset = line.offsets[close_bracket];
+    // offsets once both brackets are gone, the `)` sits at `url_end`
+    let text_end = close_bracket_offset - 1;
+    let url_tag_end = line.offsets[url_end] - 1;
+
+    return vec![
+        Ops::Delete((close_bracket_offset, close_bracket_offset + 1)),
+        Ops::Delete((open_bracket_offset, open_bracket_offset + 1)),
+        Ops::ApplyTag((LINK_TAG.to_string(), open_bracket_offset, text_end)),
+        Ops::ApplyTag((LINK_URL_TAG.to_string(), text_end, url_tag_end)),
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delete(start_offset: i32, end_offset: i32) -> Ops {
+        Ops::Delete((start_offset, end_offset))
+    }
+
+    fn tag(tag_name: &str, start_offset: i32, end_offset: i32) -> Ops {
+        Ops::ApplyTag((tag_name.to_string(), start_offset, end_offset))
+    }
+
+    /**
+     * Types `input` one char at a time at the end of the text, applying the
+     * deletions of every conversion, and returns all ops the formatter made.
+     */
+    fn type_text(input: &str) -> Vec<Ops> {
+        let mut text: Vec<char> = vec![];
+        let mut ops = vec![];
+
+        for c in input.chars() {
+            let line_start = text
+                .iter()
+                .rposition(|c| *c == '\n')
+                .map_or(0, |idx| idx + 1);
+            let line_before = text[line_start..].iter().collect::<String>();
+            text.push(c);
+
+            let inserted = c.to_string();
+            for op in format_insert(line_before.as_str(), line_start as i32, inserted.as_str()) {
+                if let Ops::Delete((start_offset, end_offset)) = op {
+                    text.drain(start_offset as usize..end_offset as usize);
+                }
+                ops.push(op);
             }
-            None => {}
         }
 
-        return (self, ops_to_perform);
+        return ops;
+    }
+
+    #[test]
+    fn typed_sequences() {
+        let cases: Vec<(&str, Vec<Ops>)> = vec![
+            (
+                "*italic* ",
+                vec![delete(7, 8), delete(0, 1), tag(ITALIC_TAG, 0, 6)],
+            ),
+            (
+                "**bold** ",
+                vec![delete(6, 8), delete(0, 2), tag(BOLD_TAG, 0, 4)],
+            ),
+            // a longer closing run closes the inner emphasis first
+            (
+                "**big *world*** ",
+                vec![
+                    delete(12, 13),
+                    delete(6, 7),
+                    tag(ITALIC_TAG, 6, 11),
+                    delete(11, 13),
+                    delete(0, 2),
+                    tag(BOLD_TAG, 0, 9),
+                ],
+            ),
+            (
+                "*italic** ",
+                vec![delete(7, 8), delete(0, 1), tag(ITALIC_TAG, 0, 6)],
+            ),
+            ("**bold* ", vec![]),
+            (
+                "***both*** ",
+                vec![
+                    delete(7, 10),
+                    delete(0, 3),
+                    tag(BOLD_TAG, 0, 4),
+                    tag(ITALIC_TAG, 0, 4),
+                ],
+            ),
+            (
+                "_it_ ",
+                vec![delete(3, 4), delete(0, 1), tag(ITALIC_TAG, 0, 2)],
+            ),
+            (
+                "__b__ ",
+                vec![delete(3, 5), delete(0, 2), tag(BOLD_TAG, 0, 1)],
+            ),
+            (
+                "~~gone~~ ",
+                vec![delete(6, 8), delete(0, 2), tag(STRIKETHROUGH_TAG, 0, 4)],
+            ),
+            (
+                "`code` ",
+                vec![delete(5, 6), delete(0, 1), tag(CODE_TAG, 0, 4)],
+            ),
+            (
+                "`a*b*` ",
+                vec![delete(5, 6), delete(0, 1), tag(CODE_TAG, 0, 4)],
+            ),
+            (
+                "[site](x.io)",
+                vec![
+                    delete(5, 6),
+                    delete(0, 1),
+                    tag(LINK_TAG, 0, 4),
+                    tag(LINK_URL_TAG, 4, 10),
+                ],
+            ),
+            (
+                "**bold *it* more** ",
+                vec![
+                    delete(10, 11),
+                    delete(7, 8),
+                    tag(ITALIC_TAG, 7, 9),
+                    delete(14, 16),
+                    delete(0, 2),
+                    tag(BOLD_TAG, 0, 12),
+                ],
+            ),
+            (
+                "see (*x*)",
+                vec![delete(7, 8), delete(5, 6), tag(ITALIC_TAG, 5, 6)],
+            ),
+            (
+                "first\n*x* ",
+                vec![delete(8, 9), delete(6, 7), tag(ITALIC_TAG, 6, 7)],
+            ),
+            (
+                "*héllo* ",
+                vec![delete(6, 7), delete(0, 1), tag(ITALIC_TAG, 0, 5)],
+            ),
+            // markers next to spaces are plain text
+            ("a * b * c ", vec![]),
+            ("* spaced * ", vec![]),
+            ("** spaced** ", vec![]),
+            // underscores inside words are plain text
+            ("snake_case_name ", vec![]),
+            // mismatched runs don't pair up
+            ("**bold* ", vec![]),
+            ("~single~ ", vec![]),
+            ("`` ", vec![]),
+            ("[](x) [a]() [a] (x)", vec![]),
+            // line starts
+            (
+                "# Title",
+                vec![tag(BLOCK_MARKER_TAG, 0, 2), tag(HEADING_1_TAG, 0, 2)],
+            ),
+            (
+                "## Sub",
+                vec![tag(BLOCK_MARKER_TAG, 0, 3), tag(HEADING_2_TAG, 0, 3)],
+            ),
+            (
+                "> quote",
+                vec![tag(BLOCK_MARKER_TAG, 0, 2), tag(BLOCKQUOTE_TAG, 0, 2)],
+            ),
+            (
+                "- item",
+                vec![tag(LIST_MARKER_TAG, 0, 2), tag(LIST_ITEM_TAG, 0, 2)],
+            ),
+            (
+                "text\n12. item",
+                vec![tag(LIST_MARKER_TAG, 5, 9), tag(LIST_ITEM_TAG, 5, 9)],
+            ),
+            ("a\n---\n", vec![tag(RULE_TAG, 2, 5)]),
+            (
+                "# *big* ",
+                vec![
+                    tag(BLOCK_MARKER_TAG, 0, 2),
+                    tag(HEADING_1_TAG, 0, 2),
+                    delete(6, 7),
+                    delete(2, 3),
+                    tag(ITALIC_TAG, 2, 5),
+                ],
+            ),
+            // only at the start of a line
+            ("a # b - c > d 1. e ", vec![]),
+            ("#no space ", vec![]),
+            ("--- ", vec![]),
+            (". x", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(type_text(input), expected, "typing {:?}", input);
+        }
+    }
+
+    #[test]
+    fn inserted_text_closes_at_its_end() {
+        assert_eq!(
+            format_boundary("a **bold**", 0, None),
+            vec![delete(8, 10), delete(2, 4), tag(BOLD_TAG, 2, 6)]
+        );
+        assert_eq!(
+            format_boundary("`x`", 4, Some(" ")),
+            vec![delete(6, 7), delete(4, 5), tag(CODE_TAG, 4, 5)]
+        );
+        // the run may still continue into the text that follows
+        assert_eq!(format_boundary("*x*", 0, Some("*")), vec![]);
+        assert_eq!(format_boundary("_x_", 0, Some("y")), vec![]);
+        assert_eq!(format_boundary("# ", 0, None), vec![]);
+    }
+
+    // offsets are in chars, the way GTK counts them
+    #[test]
+    fn non_ascii_notes() {
+        let cases: Vec<(&str, Vec<Ops>)> = vec![
+            (
+                "*日本語* ",
+                vec![delete(4, 5), delete(0, 1), tag(ITALIC_TAG, 0, 3)],
+            ),
+            (
+                "**café** ",
+                vec![delete(6, 8), delete(0, 2), tag(BOLD_TAG, 0, 4)],
+            ),
+            (
+                "*مرحبا* ",
+                vec![delete(6, 7), delete(0, 1), tag(ITALIC_TAG, 0, 5)],
+            ),
+            (
+                "_наш_ ",
+                vec![delete(4, 5), delete(0, 1), tag(ITALIC_TAG, 0, 3)],
+            ),
+            // "e" followed by a combining acute accent
+            (
+                "*cafe\u{301}* ",
+                vec![delete(6, 7), delete(0, 1), tag(ITALIC_TAG, 0, 5)],
+            ),
+            (
+                "🙂 *a* ",
+                vec![delete(4, 5), delete(2, 3), tag(ITALIC_TAG, 2, 3)],
+            ),
+            // thumbs up with a skin tone modifier, two chars
+            (
+                "**👍🏽 ok** ",
+                vec![delete(7, 9), delete(0, 2), tag(BOLD_TAG, 0, 5)],
+            ),
+            // a family emoji, five chars joined by zero width joiners
+            (
+                "*👨\u{200d}👩\u{200d}👧*x",
+                vec![delete(6, 7), delete(0, 1), tag(ITALIC_TAG, 0, 5)],
+            ),
+            (
+                "Ünïcödé\n~~straße~~ ",
+                vec![delete(16, 18), delete(8, 10), tag(STRIKETHROUGH_TAG, 8, 14)],
+            ),
+            (
+                "`ℤ → ℝ` ",
+                vec![delete(6, 7), delete(0, 1), tag(CODE_TAG, 0, 5)],
+            ),
+            (
+                "[東京](https://例え.jp)",
+                vec![
+                    delete(3, 4),
+                    delete(0, 1),
+                    tag(LINK_TAG, 0, 2),
+                    tag(LINK_URL_TAG, 2, 17),
+                ],
+            ),
+            (
+                "# Überschrift",
+                vec![tag(BLOCK_MARKER_TAG, 0, 2), tag(HEADING_1_TAG, 0, 2)],
+            ),
+            // an accent typed onto the closing marker makes it a letter
+            ("*x*\u{301} ", vec![]),
+            // the underscore follows a letter, accent and all
+            ("cafe\u{301}_x_ ", vec![]),
+            ("ça_va_ ", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(type_text(input), expected, "typing {:?}", input);
+        }
     }
 }
@@ -1,26 +1,35 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct InsertOpsData {
+    pub offset: i32,
     pub content: String,
     pub tag: String,
 }
 
 impl InsertOpsData {
-    pub fn new(content: String, tag: String) -> Self {
-        return InsertOpsData { content, tag };
+    pub fn new(offset: i32, content: String, tag: String) -> Self {
+        return InsertOpsData {
+            offset,
+            content,
+            tag,
+        };
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Ops {
     Insert(InsertOpsData),
     Delete((i32, i32)),
     MoveCursor(i32),
     SelectColorTag(String),
     ApplyTag((String, i32, i32)),
+    RemoveTag((String, i32, i32)),
 }
 
+/// A tag name together with the start and end offsets it covers.
+pub type TagRun = (String, i32, i32);
+
 pub struct InsertTextEventData {
     pub offset: i32,
     pub content: String,
@@ -39,13 +48,21 @@ impl InsertTextEventData {
 pub struct DeleteTextEventData {
     pub start_offset: i32,
     pub end_offset: i32,
+    /**
+     * the deleted text and its tags, captured before gtk removes them so the
+     * deletion can be undone
+     */
+    pub content: String,
+    pub tags: Vec<TagRun>,
 }
 
 impl DeleteTextEventData {
-    pub fn new(start_offset: i32, end_offset: i32) -> Self {
+    pub fn new(start_offset: i32, end_offset: i32, content: String, tags: Vec<TagRun>) -> Self {
         return DeleteTextEventData {
             start_offset,
             end_offset,
+            content,
+            tags,
         };
     }
 }
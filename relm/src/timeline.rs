@@ -0,0 +1,186 @@
+use crate::document::Document;
+use crate::note_file::Snapshot;
+use crate::text_ops::{InsertOpsData, Ops};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch, the time every op of the log gets.
+pub fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+}
+
+/**
+ * Replays the first `op_count` ops on top of `snapshot`, the way opening the
+ * note does. Comes with the color typed text would have gotten at that point,
+ * if the note had picked one.
+ */
+pub fn version_at(
+    snapshot: Option<&Snapshot>,
+    ops: &[Ops],
+    op_count: usize,
+) -> (Document, Option<String>) {
+    let mut document = Document::new();
+    let mut current_tag = None;
+
+    if let Some(snapshot) = snapshot {
+        document.restore_snapshot(snapshot);
+        current_tag = Some(snapshot.current_tag.to_string());
+    }
+    for op in ops.iter().take(op_count) {
+        if let Ops::SelectColorTag(tag_name) = op {
+            current_tag = Some(tag_name.to_string());
+        }
+        document.apply(op);
+    }
+
+    return (document, current_tag);
+}
+
+/**
+ * The ops that turn `current` into `target`, so going back to an old version
+ * is an edit like any other. Only the text between the common start and end
+ * is replaced, and only the tags that differ afterwards are redone.
+ */
+pub fn restore_ops(current: &Document, target: &Document) -> Vec<Ops> {
+    let current_chars = current.text().chars().collect::<Vec<char>>();
+    let target_chars = target.text().chars().collect::<Vec<char>>();
+
+    let prefix = current_chars
+        .iter()
+        .zip(&target_chars)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = current_chars[prefix..]
+        .iter()
+        .rev()
+        .zip(target_chars[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut ops = vec![];
+    if prefix + suffix < current_chars.len() {
+        ops.push(Ops::Delete((
+            prefix as i32,
+            (current_chars.len() - suffix) as i32,
+        )));
+    }
+    if prefix + suffix < target_chars.len() {
+        let content = target_chars[prefix..target_chars.len() - suffix]
+            .iter()
+            .collect::<String>();
+        ops.push(Ops::Insert(InsertOpsData::new(
+            prefix as i32,
+            content,
+            String::new(),
+        )));
+    }
+
+    let mut edited = current.clone();
+    for op in &ops {
+        edited.apply(op);
+    }
+    let len = target.char_count();
+    let edited_runs = edited.tag_runs(0, len);
+    let target_runs = target.tag_runs(0, len);
+
+    let mut tag_names = edited_runs
+        .iter()
+        .chain(&target_runs)
+        .map(|(name, _, _)| name.to_string())
+        .collect::<Vec<String>>();
+    tag_names.sort();
+    tag_names.dedup();
+
+    for tag_name in tag_names {
+        let runs_of = |runs: &[(String, i32, i32)]| {
+            runs.iter()
+                .filter(|(name, _, _)| *name == tag_name)
+                .map(|(_, s, e)| (*s, *e))
+                .collect::<Vec<(i32, i32)>>()
+        };
+        let target_ranges = runs_of(&target_runs);
+        if runs_of(&edited_runs) == target_ranges {
+            continue;
+        }
+
+        ops.push(Ops::RemoveTag((tag_name.to_string(), 0, len)));
+        for (start_offset, end_offset) in target_ranges {
+            ops.push(Ops::ApplyTag((
+                tag_name.to_string(),
+                start_offset,
+                end_offset,
+            )));
+        }
+    }
+
+    return ops;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{any_op, insert};
+    use proptest::prelude::*;
+
+    fn ops() -> Vec<Ops> {
+        return vec![
+            insert(0, "hello", "color_tag_1"),
+            Ops::SelectColorTag("color_tag_2".to_string()),
+            insert(5, " world", "color_tag_2"),
+            Ops::ApplyTag(("bold".to_string(), 0, 5)),
+            Ops::Delete((0, 6)),
+        ];
+    }
+
+    #[test]
+    fn versions_replay_a_prefix_of_the_log() {
+        let (empty, current_tag) = version_at(None, &ops(), 0);
+        assert_eq!(empty.text(), "");
+        assert_eq!(current_tag, None);
+
+        let (document, current_tag) = version_at(None, &ops(), 3);
+        assert_eq!(document.text(), "hello world");
+        assert_eq!(current_tag, Some("color_tag_2".to_string()));
+
+        let (latest, _) = version_at(None, &ops(), usize::MAX);
+        assert_eq!(latest.text(), "world");
+    }
+
+    #[test]
+    fn versions_start_from_the_snapshot() {
+        let (compacted, _) = version_at(None, &ops(), 2);
+        let snapshot = compacted.snapshot("color_tag_2");
+
+        let (document, current_tag) = version_at(Some(&snapshot), &ops()[2..], 1);
+        assert_eq!(document.text(), "hello world");
+        assert_eq!(current_tag, Some("color_tag_2".to_string()));
+    }
+
+    #[test]
+    fn restoring_only_touches_what_changed() {
+        let (current, _) = version_at(None, &ops(), 4);
+        let (target, _) = version_at(None, &ops(), 3);
+
+        let ops = restore_ops(&current, &target);
+        assert_eq!(ops, vec![Ops::RemoveTag(("bold".to_string(), 0, 11))]);
+        assert_eq!(restore_ops(&current, &current), vec![]);
+    }
+
+    proptest! {
+        #[test]
+        fn restoring_reaches_any_version(
+            log in prop::collection::vec(any_op(12), 0..12),
+            split in 0..12usize,
+        ) {
+            let (current, _) = version_at(None, &log, usize::MAX);
+            let (target, _) = version_at(None, &log, split);
+
+            let mut restored = current.clone();
+            for op in restore_ops(&current, &target) {
+                restored.apply(&op);
+            }
+            prop_assert_eq!(restored, target);
+        }
+    }
+}